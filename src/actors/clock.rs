@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::Result;
+use chrono::{TimeDelta, Utc};
+use iroh::NodeId;
+use ractor::{Actor, ActorProcessingErr, ActorRef, time::send_interval};
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::{
+    actors::gossip::{GossipEvent, GossipMessage, gossip_receiver},
+    db::{AuditEvent, Peer},
+    network::clock::{CLOCK_SKEW_THRESHOLD, ClockSkewEstimator, SkewChange},
+};
+
+/// How often estimated offsets are written and round trip times are reloaded
+const SYNC_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Offsets that moved less than this since they were last written are not written again
+const MIN_OFFSET_CHANGE: TimeDelta = TimeDelta::milliseconds(250);
+
+/// Estimates how far each peer's clock is from ours, and flags the ones that are too far off
+pub struct ClockActor;
+
+#[derive(Debug)]
+pub enum ClockMessage {
+    Gossip(GossipEvent),
+    /// Write the current estimates and pick up new round trip times
+    Sync,
+}
+
+impl From<GossipEvent> for ClockMessage {
+    fn from(event: GossipEvent) -> Self {
+        ClockMessage::Gossip(event)
+    }
+}
+
+#[derive(Debug)]
+pub struct ClockState {
+    estimator: ClockSkewEstimator,
+    /// Last round trip time the prober measured to each peer
+    rtts: HashMap<NodeId, Duration>,
+    /// Offset last written for each peer
+    written: HashMap<NodeId, TimeDelta>,
+}
+
+impl Actor for ClockActor {
+    type Msg = ClockMessage;
+    type State = ClockState;
+    type Arguments = ();
+
+    async fn pre_start(
+        &self,
+        myself: ActorRef<Self::Msg>,
+        _args: Self::Arguments,
+    ) -> Result<Self::State, ActorProcessingErr> {
+        info!("Starting Clock Actor");
+
+        let mut state = ClockState {
+            estimator: ClockSkewEstimator::default(),
+            rtts: HashMap::new(),
+            written: HashMap::new(),
+        };
+        state.load_rtts().await?;
+
+        gossip_receiver::subscribe("clock", myself.clone())?;
+        send_interval(SYNC_INTERVAL, myself.get_cell(), || ClockMessage::Sync);
+
+        Ok(state)
+    }
+
+    async fn handle(
+        &self,
+        _myself: ActorRef<Self::Msg>,
+        message: Self::Msg,
+        state: &mut Self::State,
+    ) -> Result<(), ActorProcessingErr> {
+        match message {
+            ClockMessage::Gossip(GossipEvent::Message(
+                sender_node_id,
+                GossipMessage::Heartbeat { sent_at, .. }
+                | GossipMessage::DigestHeartbeat { sent_at, .. },
+            )) => {
+                let rtt = state.rtts.get(&sender_node_id).copied();
+                if let Some(change) =
+                    state
+                        .estimator
+                        .heartbeat(sender_node_id, sent_at, Utc::now(), rtt)
+                {
+                    state.record(change).await?;
+                }
+            }
+            ClockMessage::Gossip(_) => {}
+            ClockMessage::Sync => {
+                state.write_offsets().await?;
+                state.load_rtts().await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn post_stop(
+        &self,
+        _myself: ActorRef<Self::Msg>,
+        state: &mut Self::State,
+    ) -> Result<(), ActorProcessingErr> {
+        gossip_receiver::unsubscribe("clock")?;
+        state.write_offsets().await?;
+
+        Ok(())
+    }
+}
+
+impl ClockState {
+    async fn load_rtts(&mut self) -> Result<()> {
+        self.rtts = Peer::list()
+            .await?
+            .iter()
+            .filter_map(|peer| Some((peer.node_id, peer.last_rtt()?)))
+            .collect();
+
+        Ok(())
+    }
+
+    /// Write every offset that moved noticeably since it was last written
+    async fn write_offsets(&mut self) -> Result<()> {
+        for node_id in self.estimator.node_ids() {
+            self.write_offset(node_id).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn write_offset(&mut self, node_id: NodeId) -> Result<()> {
+        let Some(offset) = self.estimator.offset(node_id) else {
+            return Ok(());
+        };
+        if self
+            .written
+            .get(&node_id)
+            .is_some_and(|written| (offset - *written).abs() < MIN_OFFSET_CHANGE)
+        {
+            return Ok(());
+        }
+
+        if Peer::set_clock_offset(node_id, offset).await?.is_none() {
+            // The peer was removed, such as by a revocation
+            self.estimator.forget(node_id);
+            self.written.remove(&node_id);
+            return Ok(());
+        }
+        self.written.insert(node_id, offset);
+
+        Ok(())
+    }
+
+    /// Persist and audit a peer's clock going out of or back into sync
+    async fn record(&mut self, change: SkewChange) -> Result<()> {
+        let SkewChange {
+            node_id,
+            offset,
+            skewed,
+        } = change;
+
+        self.written.remove(&node_id);
+        self.write_offset(node_id).await?;
+
+        let offset_ms = offset.num_milliseconds();
+        if skewed {
+            warn!(%node_id, offset_ms, "Peer clock is skewed");
+            AuditEvent::log(
+                "CLOCK_SKEW_DETECTED".to_string(),
+                format!(
+                    "Peer clock is off by more than {} seconds",
+                    CLOCK_SKEW_THRESHOLD.num_seconds()
+                ),
+                json!({
+                    "node_id": node_id.to_string(),
+                    "offset_ms": offset_ms,
+                }),
+            )
+            .await?;
+        } else {
+            info!(%node_id, offset_ms, "Peer clock is back in sync");
+            AuditEvent::log(
+                "CLOCK_SKEW_RESOLVED".to_string(),
+                "Peer clock is back in sync".to_string(),
+                json!({
+                    "node_id": node_id.to_string(),
+                    "offset_ms": offset_ms,
+                }),
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,148 @@
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+/// Version of the gossip protocol spoken by this build
+///
+/// Bump this whenever the meaning of an existing message changes. Purely additive changes should
+/// add a [`Capabilities`] flag instead, so older nodes can keep talking to us.
+///
+/// Version 2 stopped answering `Ping` messages, pings have a protocol of their own.
+pub const PROTOCOL_VERSION: u16 = 2;
+
+/// Set of optional protocol features a node understands
+///
+/// Nodes advertise their set in `Introduction` and `Heartbeat` messages. A message that needs a
+/// capability is only broadcast once every peer seen recently has advertised it, which lets a
+/// network be upgraded one node at a time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    /// Understands `Admission` records
+    pub const ADMISSION: Capabilities = Capabilities(1 << 0);
+    /// Understands `InviteConsumed` records
+    pub const INVITES: Capabilities = Capabilities(1 << 1);
+    /// Understands `Policy` documents and `Labels` records
+    pub const POLICY: Capabilities = Capabilities(1 << 2);
+    /// Understands `Proposal` and `Approval` records
+    pub const APPROVALS: Capabilities = Capabilities(1 << 3);
+    /// Understands `Revocation` records
+    pub const REVOCATION: Capabilities = Capabilities(1 << 4);
+    /// Understands `KeyHandover` records
+    pub const HANDOVER: Capabilities = Capabilities(1 << 5);
+    /// Understands `Metadata` records
+    pub const METADATA: Capabilities = Capabilities(1 << 6);
+    /// Answered `Ping` messages with a `Pong` before protocol version 2, no longer advertised
+    pub const PING: Capabilities = Capabilities(1 << 7);
+    /// Understands `Leaving` announcements
+    pub const LEAVE: Capabilities = Capabilities(1 << 8);
+    /// Understands `DigestHeartbeat` messages
+    pub const DIGEST: Capabilities = Capabilities(1 << 9);
+    /// Answers anti-entropy requests over the sync protocol
+    pub const SYNC: Capabilities = Capabilities(1 << 10);
+    /// Answers pings over the ping protocol
+    pub const DIRECT_PING: Capabilities = Capabilities(1 << 11);
+
+    /// Name of every known capability flag, used for display
+    const NAMES: &'static [(Capabilities, &'static str)] = &[
+        (Self::ADMISSION, "admission"),
+        (Self::INVITES, "invites"),
+        (Self::POLICY, "policy"),
+        (Self::APPROVALS, "approvals"),
+        (Self::REVOCATION, "revocation"),
+        (Self::HANDOVER, "handover"),
+        (Self::METADATA, "metadata"),
+        (Self::PING, "ping"),
+        (Self::LEAVE, "leave"),
+        (Self::DIGEST, "digest"),
+        (Self::SYNC, "sync"),
+        (Self::DIRECT_PING, "direct-ping"),
+    ];
+
+    /// The capabilities supported by this build
+    pub const fn ours() -> Capabilities {
+        Capabilities::ADMISSION
+            .union(Capabilities::INVITES)
+            .union(Capabilities::POLICY)
+            .union(Capabilities::APPROVALS)
+            .union(Capabilities::REVOCATION)
+            .union(Capabilities::HANDOVER)
+            .union(Capabilities::METADATA)
+            .union(Capabilities::LEAVE)
+            .union(Capabilities::DIGEST)
+            .union(Capabilities::SYNC)
+            .union(Capabilities::DIRECT_PING)
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub const fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn union(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+
+    /// Names of the known flags in this set, unknown flags are skipped
+    pub fn names(self) -> Vec<&'static str> {
+        Self::NAMES
+            .iter()
+            .filter(|(flag, _)| self.contains(*flag))
+            .map(|(_, name)| *name)
+            .collect()
+    }
+}
+
+impl Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names = self.names();
+        if names.is_empty() {
+            write!(f, "none ({:#x})", self.0)
+        } else {
+            write!(f, "{} ({:#x})", names.join(", "), self.0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contains() {
+        let a = Capabilities(0b01);
+        let b = Capabilities(0b10);
+        let both = a.union(b);
+
+        assert!(both.contains(a));
+        assert!(both.contains(b));
+        assert!(!a.contains(b));
+        assert!(a.contains(Capabilities::NONE));
+        assert!(Capabilities::NONE.is_empty());
+    }
+
+    #[test]
+    fn test_unknown_bits_are_preserved() {
+        let future = Capabilities(1 << 31);
+        assert_eq!(future.to_string(), "none (0x80000000)");
+        assert_eq!(
+            Capabilities(future.0 | Capabilities::ADMISSION.0).to_string(),
+            "admission (0x80000001)"
+        );
+        assert!(future.names().is_empty());
+        assert!(!Capabilities::ours().contains(future));
+    }
+
+    #[test]
+    fn test_serializes_as_integer() {
+        let caps = Capabilities(5);
+        assert_eq!(serde_json::to_string(&caps).unwrap(), "5");
+        assert_eq!(serde_json::from_str::<Capabilities>("5").unwrap(), caps);
+    }
+}
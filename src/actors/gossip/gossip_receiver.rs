@@ -1,26 +1,125 @@
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
 
 use anyhow::{Result, anyhow};
 use futures::TryStreamExt;
+use iroh::NodeId;
 use iroh_gossip::api::GossipReceiver;
-use ractor::{Actor, ActorCell};
+use ractor::{Actor, ActorRef, time::send_interval};
 use serde_json::json;
 use tokio::{sync::watch, task::JoinHandle};
 use tracing::{debug, error, trace, warn};
 
 use crate::{
-    actors::gossip::{GossipEvent, GossipMessage, signing::SignedMessage},
-    db::{AuditEvent, Peer},
+    actors::gossip::{
+        GossipEvent, GossipMessage,
+        signing::{SignedMessage, SigningError},
+    },
+    db::{AuditEvent, Peer, ReplicaRecord, SenderStatus},
 };
 
+/// How often the `last_seen` times noted by the receive loop are written to the database
+const LAST_SEEN_FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Number of correctly signed messages we could not decode, usually sent by newer nodes
+static UNKNOWN_MESSAGES: AtomicU64 = AtomicU64::new(0);
+
+/// Number of gossip neighbors we are currently connected to
+static NEIGHBORS: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of gossip neighbors we are currently connected to
+pub fn neighbor_count() -> usize {
+    NEIGHBORS.load(Ordering::Relaxed)
+}
+
 pub struct GossipReceiverActor;
 
-type Subscribers = HashMap<String, ActorCell>;
+type Subscribers = HashMap<String, Subscriber>;
+
+/// An actor that wants to hear about gossip events
+///
+/// The actor's message type only has to be convertible from [`GossipEvent`], so it can have
+/// messages of its own such as timers.
+#[derive(Clone)]
+pub struct Subscriber {
+    name: String,
+    send: Arc<dyn Fn(GossipEvent) -> Result<()> + Send + Sync>,
+}
+
+impl Subscriber {
+    pub fn new<M>(name: impl Into<String>, actor: ActorRef<M>) -> Self
+    where
+        M: From<GossipEvent> + ractor::Message,
+    {
+        Self {
+            name: name.into(),
+            send: Arc::new(move |event| {
+                actor
+                    .send_message(M::from(event))
+                    .map_err(|err| anyhow!("{err}"))
+            }),
+        }
+    }
+
+    fn send(&self, event: GossipEvent) -> Result<()> {
+        (self.send)(event)
+    }
+}
+
+impl std::fmt::Debug for Subscriber {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Subscriber").field(&self.name).finish()
+    }
+}
 
 #[derive(Debug)]
 pub enum GossipReceiverMessage {
-    Subscribe(String),
+    Subscribe(Subscriber),
     Unsubscribe(String),
+    /// Write the `last_seen` times noted since the last flush
+    FlushLastSeen,
+    /// The task reading from the gossip network ended, which fails the actor
+    ReceiveLoopStopped(String),
+    /// A signed message fetched from `from` by anti-entropy, checked and delivered as if it had
+    /// arrived over gossip
+    Replay {
+        from: NodeId,
+        envelope: Vec<u8>,
+    },
+}
+
+/// Start sending gossip events to `actor`
+pub fn subscribe<M>(name: &str, actor: ActorRef<M>) -> Result<()>
+where
+    M: From<GossipEvent> + ractor::Message,
+{
+    let receiver = ractor::registry::where_is("gossip_receiver".to_string())
+        .ok_or_else(|| anyhow!("Could not find gossip_receiver actor"))?;
+    receiver.send_message(GossipReceiverMessage::Subscribe(Subscriber::new(
+        name, actor,
+    )))?;
+    Ok(())
+}
+
+/// Deliver a signed message fetched from `from` as if it had arrived over gossip
+pub fn replay(from: NodeId, envelope: Vec<u8>) -> Result<()> {
+    let receiver = ractor::registry::where_is("gossip_receiver".to_string())
+        .ok_or_else(|| anyhow!("Could not find gossip_receiver actor"))?;
+    receiver.send_message(GossipReceiverMessage::Replay { from, envelope })?;
+    Ok(())
+}
+
+/// Stop sending gossip events to the actor subscribed as `name`
+///
+/// Does nothing if the receiver is already gone, such as while the network is being restarted.
+pub fn unsubscribe(name: &str) -> Result<()> {
+    let Some(receiver) = ractor::registry::where_is("gossip_receiver".to_string()) else {
+        return Ok(());
+    };
+    receiver.send_message(GossipReceiverMessage::Unsubscribe(name.to_string()))?;
+    Ok(())
 }
 
 #[derive(Debug)]
@@ -37,15 +136,28 @@ impl Actor for GossipReceiverActor {
 
     async fn pre_start(
         &self,
-        _myself: ractor::ActorRef<Self::Msg>,
+        myself: ractor::ActorRef<Self::Msg>,
         (mut receiver,): Self::Arguments,
     ) -> Result<Self::State, ractor::ActorProcessingErr> {
         debug!("Starting GossipSender Actor");
 
         let subscribers: Subscribers = HashMap::new();
         let (subscribers_tx, mut subscribers_rx) = watch::channel(subscribers.clone());
-        let handle =
-            tokio::spawn(async move { run_reciever(&mut receiver, &mut subscribers_rx).await });
+        let actor = myself.clone();
+        let handle = tokio::spawn(async move {
+            let result = run_reciever(&mut receiver, &mut subscribers_rx).await;
+            NEIGHBORS.store(0, Ordering::Relaxed);
+            let reason = match &result {
+                Ok(()) => "gossip event stream closed".to_string(),
+                Err(err) => format!("{err:#}"),
+            };
+            // Aborting the task on shutdown skips this, so only real failures are reported
+            let _ = actor.send_message(GossipReceiverMessage::ReceiveLoopStopped(reason));
+            result
+        });
+        send_interval(LAST_SEEN_FLUSH_INTERVAL, myself.get_cell(), || {
+            GossipReceiverMessage::FlushLastSeen
+        });
 
         Ok(Self::State {
             subscribers,
@@ -61,13 +173,12 @@ impl Actor for GossipReceiverActor {
         state: &mut Self::State,
     ) -> Result<(), ractor::ActorProcessingErr> {
         match message {
-            GossipReceiverMessage::Subscribe(name) => {
-                trace!(?name, "Subscribing to GossipReceiver");
-
-                let actor = ractor::registry::where_is(name.clone())
-                    .ok_or_else(|| anyhow!("Could not find actor"))?;
+            GossipReceiverMessage::Subscribe(subscriber) => {
+                trace!(?subscriber, "Subscribing to GossipReceiver");
 
-                state.subscribers.insert(name, actor);
+                state
+                    .subscribers
+                    .insert(subscriber.name.clone(), subscriber);
                 state.subscribers_tx.send(state.subscribers.clone())?;
             }
             GossipReceiverMessage::Unsubscribe(name) => {
@@ -76,6 +187,16 @@ impl Actor for GossipReceiverActor {
                 state.subscribers.remove(&name);
                 state.subscribers_tx.send(state.subscribers.clone())?;
             }
+            GossipReceiverMessage::FlushLastSeen => {
+                let flushed = Peer::flush_last_seen().await?;
+                trace!(flushed, "Wrote last_seen times");
+            }
+            GossipReceiverMessage::ReceiveLoopStopped(reason) => {
+                return Err(anyhow!("Gossip receive loop stopped: {reason}").into());
+            }
+            GossipReceiverMessage::Replay { from, envelope } => {
+                deliver(&envelope, from, true, &state.subscribers).await;
+            }
         }
 
         Ok(())
@@ -88,6 +209,12 @@ impl Actor for GossipReceiverActor {
     ) -> Result<(), ractor::ActorProcessingErr> {
         // Kill the Iroh task
         state.handle.abort();
+        NEIGHBORS.store(0, Ordering::Relaxed);
+
+        // Keep what we noted since the last flush
+        if let Err(err) = Peer::flush_last_seen().await {
+            error!(?err, "Failed to write last_seen times on shutdown");
+        }
 
         Ok(())
     }
@@ -99,50 +226,32 @@ async fn run_reciever(
 ) -> Result<()> {
     trace!("Receiver task running");
 
+    // Joining consumed the first neighbor events, so start from what the receiver knows
+    NEIGHBORS.store(receiver.neighbors().count(), Ordering::Relaxed);
+
     while let Some(event) = receiver.try_next().await? {
         trace!(?event, "Received event from Gossip");
 
         match event {
             iroh_gossip::api::Event::Received(message) => {
-                match SignedMessage::<GossipMessage>::verify_and_decode(&message.content) {
-                    Ok((sender_public_key, gossip_message)) => {
-                        trace!(
-                            ?sender_public_key,
-                            ?gossip_message,
-                            "Successfully verified and decoded gossip message"
-                        );
-
-                        if let Err(err) = Peer::bump_last_seen(message.delivered_from).await {
-                            error!(?err, from = ?message.delivered_from, "Failed to bump last_seen for peer in database");
-                        }
-
-                        for (_name, subscriber) in subscribers_rx.borrow().clone() {
-                            trace!(?subscriber, "Sending verified message to subscriber");
-                            if let Err(err) = subscriber.send_message(GossipEvent::Message(
-                                sender_public_key,
-                                gossip_message.clone(),
-                            )) {
-                                warn!(?err, "Failed to send message to subscriber");
-                            }
-                        }
-                    }
-                    Err(err) => {
-                        error!(
-                            ?err,
-                            from = ?message.delivered_from,
-                            "Failed to verify signature or decode message - dropping"
-                        );
-                    }
-                }
+                let subscribers = subscribers_rx.borrow().clone();
+                deliver(
+                    &message.content,
+                    message.delivered_from,
+                    false,
+                    &subscribers,
+                )
+                .await;
             }
             iroh_gossip::api::Event::NeighborUp(public_key) => {
                 debug!(?public_key, "Neighbor Connected");
+                NEIGHBORS.store(receiver.neighbors().count(), Ordering::Relaxed);
 
-                Peer::bump_last_seen(public_key).await?;
+                Peer::touch(public_key);
 
                 for (_name, subscriber) in subscribers_rx.borrow().clone() {
                     trace!(?subscriber, "Sending NeighborUp to subscriber");
-                    if let Err(err) = subscriber.send_message(GossipEvent::NeighborUp(public_key)) {
+                    if let Err(err) = subscriber.send(GossipEvent::NeighborUp(public_key)) {
                         warn!(?err, "Failed to send NeighborUp to subscriber");
                     }
                 }
@@ -158,15 +267,13 @@ async fn run_reciever(
             }
             iroh_gossip::api::Event::NeighborDown(public_key) => {
                 debug!(?public_key, "Neighbor Dropped");
+                NEIGHBORS.store(receiver.neighbors().count(), Ordering::Relaxed);
 
-                if let Err(err) = Peer::bump_last_seen(public_key).await {
-                    error!(?err, node_id = ?public_key, "Failed to bump last_seen for NeighborDown in database");
-                }
+                Peer::touch(public_key);
 
                 for (_name, subscriber) in subscribers_rx.borrow().clone() {
                     trace!(?subscriber, "Sending NeighborDown to subscriber");
-                    if let Err(err) = subscriber.send_message(GossipEvent::NeighborDown(public_key))
-                    {
+                    if let Err(err) = subscriber.send(GossipEvent::NeighborDown(public_key)) {
                         warn!(?err, "Failed to send NeighborDown to subscriber");
                     }
                 }
@@ -183,6 +290,12 @@ async fn run_reciever(
             iroh_gossip::api::Event::Lagged => {
                 warn!("Iroh Gossip is lagging and we are missing messages!");
 
+                for (_name, subscriber) in subscribers_rx.borrow().clone() {
+                    if let Err(err) = subscriber.send(GossipEvent::Lagged) {
+                        warn!(?err, "Failed to send Lagged to subscriber");
+                    }
+                }
+
                 AuditEvent::log(
                     "GOSSIP_LAGGED".to_string(),
                     "Gossip network is lagging and messages may be missing".to_string(),
@@ -195,3 +308,123 @@ async fn run_reciever(
 
     Ok(())
 }
+
+/// Check a signed message and hand it to every subscriber
+///
+/// Messages worth keeping are stored as replicas first, and only delivered if we did not already
+/// hold them or something newer in their slot. `replayed` is set for messages fetched by
+/// anti-entropy rather than received over gossip.
+async fn deliver(
+    content: &[u8],
+    delivered_from: NodeId,
+    replayed: bool,
+    subscribers: &Subscribers,
+) {
+    match SignedMessage::<GossipMessage>::verify_and_decode(content) {
+        Ok((sender_public_key, gossip_message)) => {
+            trace!(
+                ?sender_public_key,
+                ?gossip_message,
+                "Successfully verified and decoded gossip message"
+            );
+
+            let status = sender_status(sender_public_key).await;
+            if status.revoked {
+                debug!(?sender_public_key, "Ignoring message from a revoked peer");
+                return;
+            }
+
+            if status.retired {
+                debug!(
+                    ?sender_public_key,
+                    "Ignoring message signed with a key that has been rotated out"
+                );
+                return;
+            }
+
+            if gossip_message.requires_admission() && !status.admitted {
+                debug!(
+                    ?sender_public_key,
+                    "Ignoring message from a peer that has not been admitted"
+                );
+                return;
+            }
+
+            // The signer is alive, not the neighbor that relayed the message. A replayed record
+            // says nothing about when its signer was last heard from.
+            if !replayed {
+                Peer::touch(sender_public_key);
+            }
+
+            if gossip_message.requires_admin() && !status.admin {
+                warn!(
+                    ?sender_public_key,
+                    ?gossip_message,
+                    "Ignoring admin only message from a peer that is not an admin"
+                );
+                return;
+            }
+
+            if let Some(slot) = gossip_message.replica_slot(sender_public_key) {
+                match ReplicaRecord::keep(content, slot).await {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        trace!(
+                            ?slot,
+                            "Ignoring message we already hold or that is outdated"
+                        );
+                        return;
+                    }
+                    Err(err) => {
+                        warn!(
+                            ?err,
+                            "Failed to keep a replica of gossip message, dropping it"
+                        );
+                        return;
+                    }
+                }
+            }
+
+            for subscriber in subscribers.values() {
+                trace!(?subscriber, "Sending verified message to subscriber");
+                if let Err(err) = subscriber.send(GossipEvent::Message(
+                    sender_public_key,
+                    gossip_message.clone(),
+                )) {
+                    warn!(?err, "Failed to send message to subscriber");
+                }
+            }
+        }
+        Err(SigningError::UnknownPayload(sender_public_key, reason)) => {
+            let count = UNKNOWN_MESSAGES.fetch_add(1, Ordering::Relaxed) + 1;
+            debug!(
+                ?sender_public_key,
+                %reason,
+                unknown_messages = count,
+                "Ignoring message we do not understand, the sender may be running a newer version"
+            );
+        }
+        Err(err) => {
+            error!(
+                ?err,
+                from = ?delivered_from,
+                "Failed to verify signature or decode message - dropping"
+            );
+        }
+    }
+}
+
+/// Look up the sender of a message, on error it is treated as revoked so the message is dropped
+async fn sender_status(node_id: NodeId) -> SenderStatus {
+    SenderStatus::of(node_id).await.unwrap_or_else(|err| {
+        error!(
+            ?err,
+            ?node_id,
+            "Failed to look up sender, treating as revoked"
+        );
+        SenderStatus {
+            revoked: true,
+            ..Default::default()
+        }
+    })
+}
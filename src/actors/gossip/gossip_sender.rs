@@ -1,18 +1,26 @@
+use std::time::Duration;
+
 use anyhow::{Context, Result};
 use iroh::NodeId;
 use iroh_gossip::api::GossipSender;
-use ractor::{Actor, registry};
-use tracing::{debug, trace};
+use ractor::{Actor, ActorRef, RpcReplyPort, registry, rpc::CallResult};
+use tracing::{debug, trace, warn};
 
 use crate::actors::gossip::{GossipMessage, signing::SignedMessage};
-use crate::db::Identity;
+use crate::db::{Identity, OutboxMessage, Peer, ReplicaRecord};
 
 pub struct GossipSenderActor;
 
 #[derive(Debug)]
 pub enum GossipSenderMessage {
     Broadcast(GossipMessage),
+    /// Broadcast a message and reply with whether it was sent
+    BroadcastConfirmed(GossipMessage, RpcReplyPort<bool>),
+    /// Broadcast a message, or queue it in the outbox if not every peer supports it yet
+    BroadcastOrQueue(GossipMessage),
     JoinPeers(Vec<NodeId>),
+    /// Broadcast everything queued in the outbox
+    FlushOutbox,
 }
 
 impl From<GossipMessage> for GossipSenderMessage {
@@ -24,6 +32,39 @@ impl From<GossipMessage> for GossipSenderMessage {
 #[derive(Debug)]
 pub struct GossipSenderState {
     sender: GossipSender,
+    /// Loaded once, our keys only change while the server is stopped
+    identity: Identity,
+}
+
+impl GossipSenderState {
+    /// Sign and broadcast a message, returns false if it was held back because not every peer
+    /// supports it yet
+    async fn broadcast(&self, data: &GossipMessage) -> Result<bool> {
+        let required = data.required_capabilities();
+        if !required.is_empty() {
+            let lacking = Peer::lacking_capabilities(required).await?;
+            if !lacking.is_empty() {
+                debug!(
+                    %required,
+                    lacking = ?lacking.iter().map(|peer| peer.node_id).collect::<Vec<_>>(),
+                    "Not broadcasting message, some peers do not support it yet"
+                );
+                return Ok(false);
+            }
+        }
+
+        trace!(?data, "Broadcasting signed data");
+        let signed_bytes = SignedMessage::sign_and_encode(&self.identity.secret_key, data)?;
+
+        // Gossip does not hand our own messages back to us, so keep our copy here
+        if let Some(slot) = data.replica_slot(self.identity.id()) {
+            ReplicaRecord::keep(&signed_bytes, slot).await?;
+        }
+
+        self.sender.broadcast(signed_bytes.into()).await?;
+
+        Ok(true)
+    }
 }
 
 impl Actor for GossipSenderActor {
@@ -38,7 +79,9 @@ impl Actor for GossipSenderActor {
     ) -> Result<Self::State, ractor::ActorProcessingErr> {
         debug!("Starting GossipSender Actor");
 
-        Ok(Self::State { sender })
+        let identity = Identity::get_or_generate().await?;
+
+        Ok(Self::State { sender, identity })
     }
 
     async fn handle(
@@ -49,15 +92,40 @@ impl Actor for GossipSenderActor {
     ) -> Result<(), ractor::ActorProcessingErr> {
         match message {
             GossipSenderMessage::Broadcast(data) => {
-                trace!(?data, "Broadcasting signed data");
-                let identity = Identity::get_or_generate().await?;
-                let signed_bytes = SignedMessage::sign_and_encode(&identity.secret_key, &data)?;
-                state.sender.broadcast(signed_bytes.into()).await?;
+                state.broadcast(&data).await?;
+            }
+            GossipSenderMessage::BroadcastConfirmed(data, reply) => {
+                let sent = state.broadcast(&data).await?;
+                // The caller may have given up waiting
+                let _ = reply.send(sent);
+            }
+            GossipSenderMessage::BroadcastOrQueue(data) => {
+                if !state.broadcast(&data).await? {
+                    debug!(?data, "Queued message until every peer supports it");
+                    OutboxMessage::queue(&data).await?;
+                }
             }
             GossipSenderMessage::JoinPeers(bootstrap_peer_node_ids) => {
                 trace!(?bootstrap_peer_node_ids, "Manually adding peer(s)");
                 state.sender.join_peers(bootstrap_peer_node_ids).await?;
             }
+            GossipSenderMessage::FlushOutbox => {
+                for queued in OutboxMessage::list().await? {
+                    let data = match queued.decode() {
+                        Ok(data) => data,
+                        Err(err) => {
+                            warn!(?err, id = ?queued.id, "Dropping undecodable outbox message");
+                            queued.delete().await?;
+                            continue;
+                        }
+                    };
+
+                    if state.broadcast(&data).await? {
+                        debug!(?data, "Sent queued outbox message");
+                        queued.delete().await?;
+                    }
+                }
+            }
         }
 
         Ok(())
@@ -73,3 +141,51 @@ pub async fn send(message: GossipMessage) -> Result<()> {
 
     Ok(())
 }
+
+/// Broadcast a message that must not be lost, see [`GossipSenderMessage::BroadcastOrQueue`]
+///
+/// Use this for records other nodes need, messages that are repeated anyway such as heartbeats
+/// are better dropped than queued.
+pub async fn send_or_queue(message: GossipMessage) -> Result<()> {
+    let gossip_sender_ref = registry::where_is("gossip_sender".to_string())
+        .context("Could not get Gossip Sender Actor")?;
+
+    gossip_sender_ref.send_message(GossipSenderMessage::BroadcastOrQueue(message))?;
+
+    Ok(())
+}
+
+/// Broadcast a message and wait until it has been handed to the gossip network
+///
+/// Returns false if it was held back because not every peer supports it, or if it was not sent
+/// within `timeout`.
+pub async fn send_and_wait(message: GossipMessage, timeout: Duration) -> Result<bool> {
+    let gossip_sender_ref = registry::where_is("gossip_sender".to_string())
+        .context("Could not get Gossip Sender Actor")?;
+
+    let result = ActorRef::<GossipSenderMessage>::from(gossip_sender_ref)
+        .call(
+            |reply| GossipSenderMessage::BroadcastConfirmed(message, reply),
+            Some(timeout),
+        )
+        .await?;
+
+    match result {
+        CallResult::Success(sent) => Ok(sent),
+        CallResult::Timeout => {
+            warn!(?timeout, "Timed out waiting for message to be broadcast");
+            Ok(false)
+        }
+        CallResult::SenderError => Ok(false),
+    }
+}
+
+/// Ask the sender to broadcast anything waiting in the outbox
+pub async fn flush_outbox() -> Result<()> {
+    let gossip_sender_ref = registry::where_is("gossip_sender".to_string())
+        .context("Could not get Gossip Sender Actor")?;
+
+    gossip_sender_ref.send_message(GossipSenderMessage::FlushOutbox)?;
+
+    Ok(())
+}
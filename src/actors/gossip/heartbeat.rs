@@ -1,40 +1,106 @@
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
-use ractor::{Actor, ActorRef, time::send_interval};
-use tracing::trace;
+use ractor::{Actor, ActorRef};
+use tracing::{info, trace};
 
 use crate::actors::gossip::{
     GossipMessage,
+    capabilities::Capabilities,
     gossip_sender::{self, GossipSenderMessage},
 };
+use crate::config::HeartbeatConfig;
+use crate::db::Peer;
+use crate::network::digest::StateDigest;
+
+/// How often the digest sent along with heartbeats is recomputed
+const DIGEST_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
 
 pub struct HeartbeatActor;
 
+#[derive(Debug)]
+pub enum HeartbeatMessage {
+    /// Send a heartbeat now and schedule the next one
+    Beat,
+    /// Use a new interval from the next heartbeat on
+    Configure(HeartbeatConfig),
+}
+
+#[derive(Debug)]
+pub struct HeartbeatState {
+    config: HeartbeatConfig,
+    digest: StateDigest,
+    /// Whether every peer understands `DigestHeartbeat`, plain heartbeats are sent until then
+    digest_supported: bool,
+    refreshed_at: Option<Instant>,
+}
+
 impl Actor for HeartbeatActor {
-    type Msg = ();
-    type State = ActorRef<GossipSenderMessage>;
-    type Arguments = (Duration, ActorRef<GossipSenderMessage>);
+    type Msg = HeartbeatMessage;
+    type State = HeartbeatState;
+    type Arguments = (HeartbeatConfig, ActorRef<GossipSenderMessage>);
 
     async fn pre_start(
         &self,
         myself: ractor::ActorRef<Self::Msg>,
-        (duration, gossip_sender_ref): Self::Arguments,
+        (config, _gossip_sender_ref): Self::Arguments,
     ) -> Result<Self::State, ractor::ActorProcessingErr> {
-        send_interval(duration, myself.get_cell(), || ());
-        Ok(gossip_sender_ref)
+        myself.send_after(config.interval, || HeartbeatMessage::Beat);
+
+        Ok(HeartbeatState {
+            config,
+            digest: StateDigest::default(),
+            digest_supported: false,
+            refreshed_at: None,
+        })
     }
 
     async fn handle(
         &self,
-        _myself: ractor::ActorRef<Self::Msg>,
-        _message: Self::Msg,
-        _gossip_sender_ref: &mut Self::State,
+        myself: ractor::ActorRef<Self::Msg>,
+        message: Self::Msg,
+        state: &mut Self::State,
     ) -> Result<(), ractor::ActorProcessingErr> {
-        // Send the heartbeat message
-        let heartbeat = GossipMessage::heartbeat_now();
-        trace!(?heartbeat, "Sending heartbeat");
-        gossip_sender::send(heartbeat.clone()).await?;
+        match message {
+            HeartbeatMessage::Beat => {
+                state.refresh_digest().await?;
+
+                // Send the heartbeat message
+                let heartbeat = if state.digest_supported {
+                    GossipMessage::digest_heartbeat_now(state.digest)
+                } else {
+                    GossipMessage::heartbeat_now()
+                };
+                trace!(?heartbeat, "Sending heartbeat");
+                gossip_sender::send(heartbeat).await?;
+
+                let delay = state.config.delay(state.digest.peer_count as usize);
+                myself.send_after(delay, || HeartbeatMessage::Beat);
+            }
+            HeartbeatMessage::Configure(config) => {
+                info!(interval = ?config.interval, adaptive = config.adaptive, "Changing heartbeat interval");
+                state.config = config;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl HeartbeatState {
+    async fn refresh_digest(&mut self) -> Result<()> {
+        if self
+            .refreshed_at
+            .is_some_and(|refreshed_at| refreshed_at.elapsed() < DIGEST_REFRESH_INTERVAL)
+        {
+            return Ok(());
+        }
+
+        self.digest = StateDigest::collect().await?;
+        self.digest_supported = Peer::lacking_capabilities(Capabilities::DIGEST)
+            .await?
+            .is_empty();
+        self.refreshed_at = Some(Instant::now());
 
         Ok(())
     }
@@ -1,64 +1,346 @@
-use std::time::Duration;
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, anyhow, bail};
+use futures::StreamExt;
 use iroh::{
-    Endpoint, Watcher, discovery::static_provider::StaticProvider, node_info::NodeIdExt,
-    protocol::Router,
+    Endpoint, NodeAddr, NodeId, Watcher, discovery::static_provider::StaticProvider,
+    endpoint::RemoteInfo, node_info::NodeIdExt, protocol::Router,
 };
 use iroh_base::ticket::NodeTicket;
-use iroh_gossip::{net::Gossip, proto::TopicId};
-use ractor::Actor;
+use iroh_gossip::net::Gossip;
+use ractor::{
+    Actor, ActorRef, RpcReplyPort, SupervisionEvent, rpc::CallResult, time::send_interval,
+};
+use serde_json::json;
+use tokio::task::JoinHandle;
 use tracing::{debug, info, trace, warn};
 
 use crate::{
-    actors::gossip::gossip_sender::GossipSenderMessage,
-    db::{Identity, Peer, PeerExt},
-    utils::topic_id,
+    actors::{
+        AppConfig,
+        gossip::{
+            GossipMessage, gossip_receiver,
+            gossip_sender::{self, GossipSenderMessage},
+        },
+    },
+    db::{AuditEvent, Identity, Peer, PeerExt},
+    network::{backoff::Backoff, metadata::NodeMetadata, ping, sync},
 };
 
+/// How often the addresses of every known peer are written back to the database
+const ADDRESS_REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Direct addresses that have not been confirmed for this long are left out of stored tickets
+const STALE_ADDRESS_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// How long shutdown waits for the gossip actors to stop
+const CHILD_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How often we check whether we still have any gossip neighbors
+const CONNECTIVITY_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Delay before the first attempt to rejoin after losing every neighbor, doubled after each
+/// attempt up to `REJOIN_MAX_DELAY`
+const REJOIN_INITIAL_DELAY: Duration = Duration::from_secs(5);
+const REJOIN_MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// Up to this part of each rejoin delay is randomly cut off
+const REJOIN_JITTER: f64 = 0.3;
+
 pub struct IrohActor;
 
 #[derive(Debug)]
-pub enum IrohMessage {}
+pub enum IrohMessage {
+    /// Stop handing out the address of a peer, used once it has been revoked
+    ForgetPeer(NodeId),
+    /// Store the addresses the endpoint has learned for a peer in its ticket
+    RefreshPeerAddresses(NodeId),
+    /// Store the learned addresses of every known peer
+    RefreshAddresses,
+    /// Our own addresses or home relay changed
+    LocalAddressChanged(NodeAddr),
+    /// Rejoin through the known peers if we have lost every gossip neighbor
+    CheckConnectivity,
+    /// Join the gossip topic through new bootstrap peers, such as ones added by a config reload
+    JoinPeers(Vec<NodeAddr>),
+    /// Fetch the replicated records a peer holds that we missed, see [`sync::reconcile`]
+    Reconcile(NodeId),
+    /// Measure the round trip time to a peer within the timeout, see [`ping::ping`]. The answer
+    /// is sent to the reply port if there is one, see [`ping_peer`]
+    Ping(
+        NodeId,
+        Duration,
+        Option<RpcReplyPort<Result<ping::Pinged, String>>>,
+    ),
+}
 
 #[derive(Debug)]
 pub struct IrohState {
     router: Router,
     gossip: Gossip,
+    static_discovery: StaticProvider,
+    /// Watches our own addresses, see [`watch_local_address`]
+    address_watcher: JoinHandle<()>,
+    rejoin_backoff: Backoff,
+    /// When we next try to rejoin, `None` while we have neighbors
+    next_rejoin: Option<Instant>,
+    /// Rejoin attempts since we lost our last neighbor
+    rejoin_attempts: u32,
+    ticket_file: Option<PathBuf>,
+    /// What we publish about ourselves, our introduction names its hostname
+    metadata: NodeMetadata,
+}
+
+impl IrohState {
+    /// Write what the endpoint knows about reaching `node_id` into the peer's ticket
+    ///
+    /// Peers we have no record of are skipped, so revoked peers are not added back.
+    async fn refresh_peer_addresses(&self, node_id: NodeId) -> Result<()> {
+        let Some(info) = self.router.endpoint().remote_info(node_id) else {
+            return Ok(());
+        };
+        let connection = info.conn_type.to_string();
+        let Some(node_addr) = learned_node_addr(&info) else {
+            trace!(%node_id, "No usable addresses learned for peer");
+            return Ok(());
+        };
+
+        if let Some(peer) = Peer::update_addresses(node_addr, connection).await? {
+            trace!(%node_id, node_addr = ?peer.node_addr(), "Refreshed peer addresses");
+            self.static_discovery
+                .add_node_info(peer.node_addr().clone());
+        }
+
+        Ok(())
+    }
+
+    /// Stop the gossip actors, then the network they use
+    async fn shutdown(&mut self, myself: &ractor::ActorRef<IrohMessage>) -> Result<()> {
+        self.address_watcher.abort();
+
+        // Let the sender and receiver finish up while the network is still there
+        myself
+            .stop_children_and_wait(None, Some(CHILD_SHUTDOWN_TIMEOUT))
+            .await;
+
+        self.gossip.shutdown().await?;
+        self.router.shutdown().await?;
+
+        Ok(())
+    }
+
+    /// Rejoin the network if we have no gossip neighbors left, backing off between attempts
+    async fn check_connectivity(&mut self) -> Result<()> {
+        if gossip_receiver::neighbor_count() > 0 {
+            if self.rejoin_attempts > 0 {
+                let attempts = self.rejoin_attempts;
+                info!(attempts, "Rejoined the gossip network");
+                AuditEvent::log(
+                    "GOSSIP_REJOINED".to_string(),
+                    "Reconnected to the gossip network".to_string(),
+                    json!({ "attempts": attempts }),
+                )
+                .await?;
+            }
+            self.rejoin_backoff.reset();
+            self.next_rejoin = None;
+            self.rejoin_attempts = 0;
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        match self.next_rejoin {
+            // Give the network a moment before the first attempt, neighbors often come back
+            // on their own
+            None => {
+                debug!("No gossip neighbors, scheduling a rejoin");
+                self.next_rejoin = Some(now + self.rejoin_backoff.next_delay());
+            }
+            Some(next_rejoin) if now >= next_rejoin => {
+                self.rejoin().await?;
+                self.next_rejoin = Some(now + self.rejoin_backoff.next_delay());
+            }
+            Some(_) => {}
+        }
+
+        Ok(())
+    }
+
+    /// Join the gossip topic again through every known peer
+    async fn rejoin(&mut self) -> Result<()> {
+        let peers = Peer::list().await?;
+        if peers.is_empty() {
+            trace!("No known peers to rejoin through");
+            return Ok(());
+        }
+
+        self.rejoin_attempts += 1;
+        let attempt = self.rejoin_attempts;
+        warn!(
+            attempt,
+            peers = peers.len(),
+            "Lost every gossip neighbor, trying to rejoin"
+        );
+
+        // Their addresses may have dropped out of the endpoint while we were alone
+        for peer in &peers {
+            self.static_discovery
+                .add_node_info(peer.node_addr().clone());
+        }
+
+        let sender = ractor::registry::where_is("gossip_sender".to_string())
+            .context("Could not get Gossip Sender Actor")?;
+        let node_ids = peers.to_node_ids();
+        sender.send_message(GossipSenderMessage::JoinPeers(node_ids.clone()))?;
+
+        AuditEvent::log(
+            "GOSSIP_REJOIN_ATTEMPT".to_string(),
+            "Trying to rejoin the gossip network through known peers".to_string(),
+            json!({
+                "attempt": attempt,
+                "node_ids": node_ids.iter().map(|node_id| node_id.to_string()).collect::<Vec<_>>(),
+            }),
+        )
+        .await?;
+
+        Ok(())
+    }
+}
+
+/// Tell the actor whenever our direct addresses or home relay change
+async fn watch_local_address(endpoint: Endpoint, actor: ractor::ActorRef<IrohMessage>) {
+    let mut updates = endpoint.node_addr().stream_updates_only();
+    while let Some(node_addr) = updates.next().await {
+        let Some(node_addr) = node_addr else {
+            continue;
+        };
+        if let Err(err) = actor.send_message(IrohMessage::LocalAddressChanged(node_addr)) {
+            warn!(
+                ?err,
+                "Failed to report local address change, stopping watcher"
+            );
+            return;
+        }
+    }
+}
+
+/// Ping `node_id` from the running network and wait for the answer, see [`IrohMessage::Ping`]
+pub async fn ping_peer(node_id: NodeId, timeout: Duration) -> Result<ping::Pinged> {
+    let iroh = ractor::registry::where_is("iroh".to_string())
+        .context("Network is not running, try again once the server is up")?;
+
+    // Leave the actor time to report that the ping itself timed out
+    let result = ActorRef::<IrohMessage>::from(iroh)
+        .call(
+            |reply| IrohMessage::Ping(node_id, timeout, Some(reply)),
+            Some(timeout + Duration::from_secs(1)),
+        )
+        .await?;
+
+    match result {
+        CallResult::Success(pinged) => pinged.map_err(|err| anyhow!(err)),
+        CallResult::Timeout => bail!("No answer within {timeout:?}"),
+        CallResult::SenderError => bail!("Network stopped before the peer answered"),
+    }
+}
+
+/// Fetch what `node_id` holds that we missed and note anything that came of it
+async fn reconcile(endpoint: &Endpoint, node_id: NodeId) -> Result<()> {
+    let reconciled = sync::reconcile(endpoint, node_id).await?;
+    if reconciled.fetched == 0 {
+        trace!(%node_id, "Already in sync with peer");
+        return Ok(());
+    }
+
+    info!(%node_id, fetched = reconciled.fetched, kinds = ?reconciled.kinds, "Fetched records we missed from peer");
+    AuditEvent::log(
+        "ANTI_ENTROPY_FETCHED".to_string(),
+        "Fetched records missed over gossip from a peer".to_string(),
+        json!({
+            "node_id": node_id.to_string(),
+            "fetched": reconciled.fetched,
+            "kinds": reconciled.kinds,
+        }),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Write our ticket to the configured ticket file, if any
+async fn write_ticket_file(ticket: &NodeTicket, ticket_file: Option<&PathBuf>) {
+    if let Some(ticket_path) = ticket_file {
+        match crate::utils::write_ticket_to_file(ticket, ticket_path).await {
+            Ok(()) => debug!("Successfully wrote ticket to file '{ticket_path:?}'"),
+            Err(e) => warn!("Failed to write ticket to file '{ticket_path:?}': {e:?}"),
+        }
+    }
+}
+
+/// The relay URL and the recently confirmed direct addresses of a peer
+///
+/// Returns `None` if nothing usable is known, so the stored ticket is left alone.
+pub(crate) fn learned_node_addr(info: &RemoteInfo) -> Option<NodeAddr> {
+    let direct_addresses = info
+        .addrs
+        .iter()
+        .filter(|addr| {
+            addr.last_alive
+                .is_some_and(|last_alive| last_alive <= STALE_ADDRESS_AGE)
+        })
+        .map(|addr| addr.addr)
+        .collect::<BTreeSet<_>>();
+    let relay_url = info.relay_url.as_ref().map(|relay| relay.relay_url.clone());
+
+    if direct_addresses.is_empty() && relay_url.is_none() {
+        return None;
+    }
+
+    Some(NodeAddr {
+        node_id: info.node_id,
+        relay_url,
+        direct_addresses,
+    })
 }
 
 impl Actor for IrohActor {
     type Msg = IrohMessage;
     type State = IrohState;
-    type Arguments = (Vec<Peer>,);
+    type Arguments = (Vec<Peer>, AppConfig);
 
     async fn pre_start(
         &self,
         myself: ractor::ActorRef<Self::Msg>,
-        (bootstrap_peers,): Self::Arguments,
+        (bootstrap_peers, config): Self::Arguments,
     ) -> Result<Self::State, ractor::ActorProcessingErr> {
         debug!("Starting Iroh Actor");
 
-        let topic_id = topic_id!("ROOM_101");
+        let topic_id = config.network.topic;
 
         let identity = Identity::get_or_generate().await?;
 
         let static_discovery = StaticProvider::new();
 
-        let endpoint = Endpoint::builder()
-            .secret_key(identity.clone().secret_key)
-            .discovery_n0()
+        let endpoint = config
+            .network
+            .endpoint_builder(identity.clone().secret_key)
             .add_discovery(static_discovery.clone())
             .bind()
             .await?;
 
         // Wait for the Relay and Addr before creating the ticket
-        let relay = endpoint.home_relay().initialized().await;
+        let relay = if config.network.relay {
+            Some(endpoint.home_relay().initialized().await)
+        } else {
+            None
+        };
         let addr = endpoint.node_addr().initialized().await;
         let ticket = NodeTicket::new(addr.clone());
 
         // Set the global ticket
-        super::NODE_TICKET.set(ticket.clone())?;
+        super::set_node_ticket(ticket.clone());
 
         info!( ticket = ?ticket.to_string(), "Ticket");
         debug!(
@@ -69,20 +351,15 @@ impl Actor for IrohActor {
             "Iroh Endpoint created"
         );
 
-        // Write ticket to file if specified
-        if let crate::args::Commands::Server(server_args) = &crate::args::args().await.command
-            && let Some(ref ticket_path) = server_args.init.ticket_file
-        {
-            match crate::utils::write_ticket_to_file(&ticket, ticket_path).await {
-                Ok(()) => debug!("Successfully wrote ticket to file '{ticket_path:?}'"),
-                Err(e) => warn!("Failed to write ticket to file '{ticket_path:?}': {e:?}"),
-            }
-        }
+        write_ticket_file(&ticket, config.ticket_file.as_ref()).await;
+        let address_watcher = tokio::spawn(watch_local_address(endpoint.clone(), myself.clone()));
 
         let gossip = Gossip::builder().spawn(endpoint.clone());
 
         let router = iroh::protocol::Router::builder(endpoint.clone())
             .accept(iroh_gossip::ALPN, gossip.clone())
+            .accept(sync::ALPN, sync::SyncProtocol)
+            .accept(ping::ALPN, ping::PingProtocol)
             .spawn();
 
         debug!(
@@ -140,23 +417,143 @@ impl Actor for IrohActor {
         Actor::spawn_linked(
             Some("heartbeat".into()),
             super::heartbeat::HeartbeatActor,
-            (Duration::from_secs(1), gossip_sender_ref.clone()),
+            (config.heartbeat, gossip_sender_ref.clone()),
             myself.clone().into(),
         )
         .await
         .context("Failed to start Heartbeat Actor")?;
 
-        Ok(IrohState { router, gossip })
+        // Keep the stored tickets current so we can bootstrap from them after a restart
+        send_interval(ADDRESS_REFRESH_INTERVAL, myself.get_cell(), || {
+            IrohMessage::RefreshAddresses
+        });
+
+        send_interval(CONNECTIVITY_CHECK_INTERVAL, myself.get_cell(), || {
+            IrohMessage::CheckConnectivity
+        });
+
+        Ok(IrohState {
+            router,
+            gossip,
+            static_discovery,
+            address_watcher,
+            rejoin_backoff: Backoff::new(REJOIN_INITIAL_DELAY, REJOIN_MAX_DELAY, REJOIN_JITTER),
+            next_rejoin: None,
+            rejoin_attempts: 0,
+            ticket_file: config.ticket_file,
+            metadata: config.metadata,
+        })
+    }
+
+    async fn handle(
+        &self,
+        myself: ractor::ActorRef<Self::Msg>,
+        message: Self::Msg,
+        state: &mut Self::State,
+    ) -> Result<(), ractor::ActorProcessingErr> {
+        match message {
+            IrohMessage::ForgetPeer(node_id) => {
+                if state.static_discovery.remove_node_info(node_id).is_some() {
+                    debug!(%node_id, "Removed peer from static discovery");
+                }
+            }
+            IrohMessage::RefreshPeerAddresses(node_id) => {
+                state.refresh_peer_addresses(node_id).await?;
+            }
+            IrohMessage::RefreshAddresses => {
+                for peer in Peer::list().await? {
+                    state.refresh_peer_addresses(peer.node_id).await?;
+                }
+            }
+            IrohMessage::LocalAddressChanged(node_addr) => {
+                let ticket = NodeTicket::new(node_addr);
+                if !super::set_node_ticket(ticket.clone()) {
+                    return Ok(());
+                }
+
+                info!(ticket = %ticket, "Our addresses changed, publishing a new ticket");
+                write_ticket_file(&ticket, state.ticket_file.as_ref()).await;
+                let hostname = state.metadata.hostname.clone();
+                gossip_sender::send(GossipMessage::introduction_now(hostname).await?).await?;
+            }
+            IrohMessage::CheckConnectivity => {
+                state.check_connectivity().await?;
+            }
+            IrohMessage::JoinPeers(node_addrs) => {
+                let node_ids = node_addrs.iter().map(|addr| addr.node_id).collect();
+                for node_addr in node_addrs {
+                    state.static_discovery.add_node_info(node_addr);
+                }
+
+                let sender = ractor::registry::where_is("gossip_sender".to_string())
+                    .context("Could not get Gossip Sender Actor")?;
+                sender.send_message(GossipSenderMessage::JoinPeers(node_ids))?;
+            }
+            IrohMessage::Reconcile(node_id) => {
+                // Fetching can take a while, keep handling messages in the meantime
+                let endpoint = state.router.endpoint().clone();
+                tokio::spawn(async move {
+                    if let Err(err) = reconcile(&endpoint, node_id).await {
+                        debug!(?err, %node_id, "Failed to reconcile with peer");
+                    }
+                });
+            }
+            IrohMessage::Ping(node_id, timeout, reply) => {
+                let endpoint = state.router.endpoint().clone();
+                tokio::spawn(async move {
+                    let result = ping::ping(&endpoint, node_id, timeout).await;
+                    match &result {
+                        Ok(pinged) => {
+                            trace!(%node_id, rtt = ?pinged.rtt, "Peer answered ping");
+                            if let Err(err) = Peer::record_rtt(node_id, pinged.rtt).await {
+                                warn!(?err, %node_id, "Failed to record round trip time");
+                            }
+                            // Keep the connection path shown next to the latency up to date
+                            let _ = myself.send_message(IrohMessage::RefreshPeerAddresses(node_id));
+                        }
+                        Err(err) => debug!(?err, %node_id, "Failed to ping peer"),
+                    }
+                    if let Some(reply) = reply {
+                        // The caller may have given up waiting
+                        let _ = reply.send(result.map_err(|err| format!("{err:#}")));
+                    }
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle_supervisor_evt(
+        &self,
+        myself: ractor::ActorRef<Self::Msg>,
+        event: SupervisionEvent,
+        state: &mut Self::State,
+    ) -> Result<(), ractor::ActorProcessingErr> {
+        let (cell, reason) = match event {
+            SupervisionEvent::ActorFailed(cell, err) => (cell, format!("{err:#}")),
+            SupervisionEvent::ActorTerminated(cell, _, reason) => (
+                cell,
+                reason.unwrap_or_else(|| "stopped unexpectedly".to_string()),
+            ),
+            _ => return Ok(()),
+        };
+
+        // The gossip actors only work as a set, so fail the whole subtree and let our
+        // supervisor start it again from scratch
+        let child = cell.get_name().unwrap_or_default();
+        warn!(%child, %reason, "Gossip actor stopped, shutting down the network");
+        state.shutdown(&myself).await?;
+
+        Err(anyhow!("{child} actor stopped: {reason}").into())
     }
 
     async fn post_stop(
         &self,
-        _myself: ractor::ActorRef<Self::Msg>,
+        myself: ractor::ActorRef<Self::Msg>,
         state: &mut Self::State,
     ) -> Result<(), ractor::ActorProcessingErr> {
-        // Shutdown the Iroh gossip and router
-        state.gossip.shutdown().await?;
-        state.router.shutdown().await?;
+        state.shutdown(&myself).await?;
 
         Ok(())
     }
@@ -1,9 +1,18 @@
+use std::sync::RwLock;
+
 use ::iroh::NodeId;
+use anyhow::{Context, Result};
 use chrono::{DateTime, Utc};
 use iroh_base::ticket::NodeTicket;
 use serde::{Deserialize, Serialize};
-use tokio::sync::OnceCell;
 
+use self::capabilities::{Capabilities, PROTOCOL_VERSION};
+use self::signing::Signable;
+use crate::db::{Identity, ReplicaSlot};
+use crate::network::digest::StateDigest;
+use crate::network::metadata::NodeMetadata;
+
+pub mod capabilities;
 pub mod gossip_receiver;
 pub mod gossip_sender;
 pub mod heartbeat;
@@ -15,21 +24,47 @@ pub enum GossipEvent {
     Message(NodeId, GossipMessage),
     NeighborUp(NodeId),
     NeighborDown(NodeId),
+    /// The gossip network dropped messages before we could read them
+    Lagged,
 }
 
-static NODE_TICKET: OnceCell<NodeTicket> = OnceCell::const_new();
+/// Our current ticket, replaced whenever our addresses change
+static NODE_TICKET: RwLock<Option<NodeTicket>> = RwLock::new(None);
 
 /// Get the full ticket of the current node
 pub fn node_ticket() -> Option<NodeTicket> {
-    NODE_TICKET.get().cloned()
+    NODE_TICKET.read().ok()?.clone()
+}
+
+/// Replace the ticket of the current node, returns whether it changed
+pub fn set_node_ticket(ticket: NodeTicket) -> bool {
+    match NODE_TICKET.write() {
+        Ok(mut current) if current.as_ref() != Some(&ticket) => {
+            *current = Some(ticket);
+            true
+        }
+        _ => false,
+    }
 }
 
+/// Everything sent over gossip
+///
+/// Postcard encodes a variant by its position, so new variants must only ever be added at the
+/// end. `test_variant_tags_are_stable` pins the tag of each one.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum GossipMessage {
+    /// Unused, pings have a protocol of their own, see [`crate::network::ping`]
     Ping,
+    /// Unused, see `Ping`
     Pong,
+    /// `protocol_version` and `capabilities` are missing from the JSON sent by legacy nodes, which
+    /// decode as version 0 with no capabilities
     Heartbeat {
         sent_at: chrono::DateTime<chrono::Utc>,
+        #[serde(default)]
+        protocol_version: u16,
+        #[serde(default)]
+        capabilities: Capabilities,
     },
     Introduction {
         node_id: NodeId,
@@ -37,13 +72,260 @@ pub enum GossipMessage {
         time: DateTime<Utc>,
         hostname: Option<String>,
         age_public_key: String,
+        #[serde(default)]
+        protocol_version: u16,
+        #[serde(default)]
+        capabilities: Capabilities,
+    },
+    /// The signer has approved `node_id` to take part in the network
+    Admission {
+        node_id: NodeId,
+        approved_at: DateTime<Utc>,
+    },
+    /// The signer is redeeming an invite token to join the network, only the issuer acts on it
+    InviteRedemption { token: Vec<u8> },
+    /// The invite `token` was redeemed by `node_id` and must not be accepted again
+    InviteConsumed { token: Vec<u8>, node_id: NodeId },
+    /// A signed policy document naming the admins of the network
+    Policy { policy: Vec<u8> },
+    /// Replace the labels assigned to `node_id`
+    Labels {
+        node_id: NodeId,
+        labels: Vec<String>,
+        set_at: DateTime<Utc>,
+    },
+    /// A signed proposal for a change that needs several admins to sign off on it
+    Proposal { proposal: Vec<u8> },
+    /// A signed approval of `proposal`, which is included so nodes that missed it can catch up
+    Approval {
+        proposal: Vec<u8>,
+        approval: Vec<u8>,
+    },
+    /// A signed revocation banning a node from the network
+    Revocation { revocation: Vec<u8> },
+    /// The signer has rotated its keys, `handover` is signed with the key it replaced
+    KeyHandover { handover: Vec<u8> },
+    /// Descriptive information about the signer, shown to operators
+    Metadata {
+        metadata: NodeMetadata,
+        sent_at: DateTime<Utc>,
+    },
+    /// The signer is shutting down on purpose, so its silence is not a failure
+    Leaving { time: DateTime<Utc> },
+    /// A `Heartbeat` that also summarizes the signer's replicated state, sent instead of one
+    /// once every peer understands it
+    DigestHeartbeat {
+        sent_at: DateTime<Utc>,
+        protocol_version: u16,
+        capabilities: Capabilities,
+        digest: StateDigest,
     },
 }
 
+impl Signable for GossipMessage {
+    const SIGNING_CONTEXT: &'static [u8] = b"room_101/gossip/signed-message/v1";
+    const LEGACY_JSON: bool = true;
+}
+
 impl GossipMessage {
     pub fn heartbeat_now() -> GossipMessage {
         GossipMessage::Heartbeat {
             sent_at: Utc::now(),
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: Capabilities::ours(),
+        }
+    }
+
+    pub fn digest_heartbeat_now(digest: StateDigest) -> GossipMessage {
+        GossipMessage::DigestHeartbeat {
+            sent_at: Utc::now(),
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: Capabilities::ours(),
+            digest,
+        }
+    }
+
+    /// Introduce ourselves with our current ticket
+    pub async fn introduction_now(hostname: Option<String>) -> Result<GossipMessage> {
+        let identity = Identity::get().await?;
+        let ticket = node_ticket().context("Node ticket not yet initialized")?;
+
+        Ok(GossipMessage::Introduction {
+            node_id: identity.id(),
+            ticket,
+            time: Utc::now(),
+            hostname,
+            age_public_key: identity.age_key.to_public().to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: Capabilities::ours(),
+        })
+    }
+
+    /// Capabilities every peer must have advertised before this message may be broadcast
+    pub fn required_capabilities(&self) -> Capabilities {
+        match self {
+            GossipMessage::Heartbeat { .. }
+            | GossipMessage::Introduction { .. }
+            | GossipMessage::InviteRedemption { .. } => Capabilities::NONE,
+            // Only kept so the tags after them stay put, nothing sends them
+            GossipMessage::Ping | GossipMessage::Pong => Capabilities::NONE,
+            GossipMessage::Admission { .. } => Capabilities::ADMISSION,
+            GossipMessage::InviteConsumed { .. } => Capabilities::INVITES,
+            GossipMessage::Policy { .. } | GossipMessage::Labels { .. } => Capabilities::POLICY,
+            GossipMessage::Proposal { .. } | GossipMessage::Approval { .. } => {
+                Capabilities::APPROVALS
+            }
+            GossipMessage::Revocation { .. } => Capabilities::REVOCATION,
+            GossipMessage::KeyHandover { .. } => Capabilities::HANDOVER,
+            GossipMessage::Metadata { .. } => Capabilities::METADATA,
+            GossipMessage::Leaving { .. } => Capabilities::LEAVE,
+            GossipMessage::DigestHeartbeat { .. } => Capabilities::DIGEST,
+        }
+    }
+
+    /// Where a copy of this message is kept for peers that missed it, see [`ReplicaSlot`]
+    ///
+    /// Messages that only matter while they are fresh, such as heartbeats, are not kept. Messages
+    /// that replace earlier ones carry the time they were signed, which decides the newest the
+    /// same way on every node.
+    pub fn replica_slot(&self, signer: NodeId) -> Option<ReplicaSlot> {
+        match self {
+            GossipMessage::Introduction { time, .. } => {
+                Some(ReplicaSlot::Latest("introduction", signer, *time))
+            }
+            GossipMessage::Metadata { sent_at, .. } => {
+                Some(ReplicaSlot::Latest("metadata", signer, *sent_at))
+            }
+            GossipMessage::Admission {
+                node_id,
+                approved_at,
+            } => Some(ReplicaSlot::Latest("admission", *node_id, *approved_at)),
+            GossipMessage::Labels {
+                node_id, set_at, ..
+            } => Some(ReplicaSlot::Latest("labels", *node_id, *set_at)),
+            GossipMessage::InviteConsumed { .. } => Some(ReplicaSlot::Unique("invite")),
+            GossipMessage::Policy { .. } => Some(ReplicaSlot::Unique("policy")),
+            GossipMessage::Proposal { .. } => Some(ReplicaSlot::Unique("proposal")),
+            GossipMessage::Approval { .. } => Some(ReplicaSlot::Unique("approval")),
+            GossipMessage::Revocation { .. } => Some(ReplicaSlot::Unique("revocation")),
+            GossipMessage::KeyHandover { .. } => Some(ReplicaSlot::Unique("handover")),
+            GossipMessage::Ping
+            | GossipMessage::Pong
+            | GossipMessage::Heartbeat { .. }
+            | GossipMessage::DigestHeartbeat { .. }
+            | GossipMessage::InviteRedemption { .. }
+            | GossipMessage::Leaving { .. } => None,
+        }
+    }
+
+    /// Whether this message is only accepted from peers that have been admitted
+    ///
+    /// Introductions and invite redemptions double as join requests, so they are let through
+    /// from anyone, as is metadata so operators can see who is asking. Key handovers come from a
+    /// node ID nobody knows yet, the old key's signature inside is what gets checked.
+    pub fn requires_admission(&self) -> bool {
+        !matches!(
+            self,
+            GossipMessage::Introduction { .. }
+                | GossipMessage::Metadata { .. }
+                | GossipMessage::InviteRedemption { .. }
+                | GossipMessage::KeyHandover { .. }
+        )
+    }
+
+    /// Whether this message is only accepted when signed by an admin
+    ///
+    /// Anything that changes who is part of the network, or what they are trusted with, is
+    /// admin only. `Policy`, `Proposal`, `Approval` and `Revocation` messages are not, the
+    /// documents they carry are checked on their own.
+    pub fn requires_admin(&self) -> bool {
+        matches!(
+            self,
+            GossipMessage::Admission { .. }
+                | GossipMessage::InviteConsumed { .. }
+                | GossipMessage::Labels { .. }
+        )
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+
+    /// The tag each variant has been sent with, changing one breaks decoding between releases
+    fn expected_tag(message: &GossipMessage) -> u8 {
+        match message {
+            GossipMessage::Ping => 0,
+            GossipMessage::Pong => 1,
+            GossipMessage::Heartbeat { .. } => 2,
+            GossipMessage::Introduction { .. } => 3,
+            GossipMessage::Admission { .. } => 4,
+            GossipMessage::InviteRedemption { .. } => 5,
+            GossipMessage::InviteConsumed { .. } => 6,
+            GossipMessage::Policy { .. } => 7,
+            GossipMessage::Labels { .. } => 8,
+            GossipMessage::Proposal { .. } => 9,
+            GossipMessage::Approval { .. } => 10,
+            GossipMessage::Revocation { .. } => 11,
+            GossipMessage::KeyHandover { .. } => 12,
+            GossipMessage::Metadata { .. } => 13,
+            GossipMessage::Leaving { .. } => 14,
+            GossipMessage::DigestHeartbeat { .. } => 15,
+        }
+    }
+
+    #[test]
+    fn test_variant_tags_are_stable() {
+        let node_id = ::iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        let now = Utc::now();
+        let messages = [
+            GossipMessage::Ping,
+            GossipMessage::Pong,
+            GossipMessage::heartbeat_now(),
+            GossipMessage::Introduction {
+                node_id,
+                ticket: NodeTicket::new(node_id.into()),
+                time: now,
+                hostname: None,
+                age_public_key: String::new(),
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: Capabilities::ours(),
+            },
+            GossipMessage::Admission {
+                node_id,
+                approved_at: now,
+            },
+            GossipMessage::InviteRedemption { token: vec![] },
+            GossipMessage::InviteConsumed {
+                token: vec![],
+                node_id,
+            },
+            GossipMessage::Policy { policy: vec![] },
+            GossipMessage::Labels {
+                node_id,
+                labels: vec![],
+                set_at: now,
+            },
+            GossipMessage::Proposal { proposal: vec![] },
+            GossipMessage::Approval {
+                proposal: vec![],
+                approval: vec![],
+            },
+            GossipMessage::Revocation { revocation: vec![] },
+            GossipMessage::KeyHandover { handover: vec![] },
+            GossipMessage::Metadata {
+                metadata: NodeMetadata::default(),
+                sent_at: now,
+            },
+            GossipMessage::Leaving { time: now },
+            GossipMessage::digest_heartbeat_now(StateDigest::default()),
+        ];
+
+        for (position, message) in messages.iter().enumerate() {
+            let encoded = postcard::to_stdvec(message).unwrap();
+            assert_eq!(encoded[0], expected_tag(message), "{message:?}");
+            assert_eq!(position as u8, expected_tag(message), "{message:?}");
         }
     }
 }
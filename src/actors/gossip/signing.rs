@@ -1,10 +1,67 @@
 use std::marker::PhantomData;
 
 use anyhow::Result;
+use chrono::{DateTime, Utc};
 use ed25519_dalek::Signature;
-use iroh::{PublicKey, SecretKey};
+use iroh::{NodeId, PublicKey, SecretKey};
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use thiserror::Error;
 
+/// Magic bytes at the start of every binary encoded message
+const MAGIC: &[u8; 4] = b"R101";
+
+/// Version of the binary envelope, bumped whenever the layout changes
+///
+/// Version 2 added the protocol version and capabilities to `Heartbeat` and `Introduction`, and
+/// version 3 the time `Labels` and `Metadata` were signed. Envelopes of earlier versions are still
+/// accepted, which messages a peer understands is decided by the capabilities it advertises.
+const WIRE_VERSION: u8 = 3;
+
+/// Something that can be signed with [`SignedMessage`]
+///
+/// Each type prepends its own context to the payload before signing, so a signature over one kind
+/// of document can never be passed off as another, even when the bytes parse as both.
+pub trait Signable: Serialize + DeserializeOwned {
+    /// Domain separation string, unique to this type
+    const SIGNING_CONTEXT: &'static [u8];
+
+    /// Whether the JSON encoding used before the binary envelope is still accepted, only gossip
+    /// messages were ever sent that way
+    const LEGACY_JSON: bool = false;
+}
+
+#[derive(Error, Debug)]
+pub enum SigningError {
+    #[error("unrecognised message encoding")]
+    UnknownEncoding,
+
+    #[error("unsupported wire version {0} (expected at most {WIRE_VERSION})")]
+    UnsupportedWireVersion(u8),
+
+    #[error("malformed envelope {0}")]
+    MalformedEnvelope(String),
+
+    #[error("invalid signature from {0}")]
+    InvalidSignature(PublicKey),
+
+    /// The signature is valid but the payload is not a message this build understands, usually
+    /// because the sender runs a newer version
+    #[error("unknown payload from {0}: {1}")]
+    UnknownPayload(PublicKey, String),
+
+    #[error("{0} is not an admin")]
+    NotAdmin(PublicKey),
+
+    #[error("policy version {new} does not supersede version {current}")]
+    StalePolicy { current: u64, new: u64 },
+}
+
+/// A message signed by the node that sent it
+///
+/// On the wire this is the magic bytes, a version byte and then the postcard encoding of this
+/// struct, with `data` itself being the postcard encoding of the message. The older JSON encoding
+/// of gossip messages is still accepted by [`SignedMessage::verify_and_decode`] while nodes are
+/// being upgraded, see [`Signable::LEGACY_JSON`].
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SignedMessage<M: Serialize + DeserializeOwned> {
     phantom: PhantomData<M>,
@@ -13,28 +70,185 @@ pub struct SignedMessage<M: Serialize + DeserializeOwned> {
     signature: Signature,
 }
 
-impl<M: Serialize + DeserializeOwned> SignedMessage<M> {
-    pub fn verify_and_decode(bytes: &[u8]) -> Result<(PublicKey, M)> {
-        let signed_message: Self = serde_json::from_slice(bytes)?;
+impl<M: Signable> SignedMessage<M> {
+    pub fn verify_and_decode(bytes: &[u8]) -> Result<(PublicKey, M), SigningError> {
+        if let Some(rest) = bytes.strip_prefix(MAGIC) {
+            let Some((&version, body)) = rest.split_first() else {
+                return Err(SigningError::MalformedEnvelope(
+                    "missing wire version".to_string(),
+                ));
+            };
+            if version > WIRE_VERSION {
+                return Err(SigningError::UnsupportedWireVersion(version));
+            }
+            return Self::verify_and_decode_binary(body);
+        }
+
+        if M::LEGACY_JSON && bytes.first() == Some(&b'{') {
+            return Self::verify_and_decode_legacy(bytes);
+        }
+
+        Err(SigningError::UnknownEncoding)
+    }
+
+    pub fn sign_and_encode(secret_key: &SecretKey, message: &M) -> Result<Vec<u8>> {
+        let data = postcard::to_stdvec(message)?;
+        let signature = secret_key.sign(&Self::signing_payload(&data));
+        let signed_message = Self {
+            phantom: PhantomData,
+            from: secret_key.public(),
+            data,
+            signature,
+        };
+
+        let mut encoded = Vec::with_capacity(MAGIC.len() + 1 + signed_message.data.len() + 96);
+        encoded.extend_from_slice(MAGIC);
+        encoded.push(WIRE_VERSION);
+        postcard::to_io(&signed_message, &mut encoded)?;
+        Ok(encoded)
+    }
+
+    fn verify_and_decode_binary(body: &[u8]) -> Result<(PublicKey, M), SigningError> {
+        let signed_message: Self = postcard::from_bytes(body)
+            .map_err(|err| SigningError::MalformedEnvelope(err.to_string()))?;
         signed_message
             .from
-            .verify(&signed_message.data, &signed_message.signature)?;
-        let message: M = serde_json::from_slice(&signed_message.data)?;
+            .verify(
+                &Self::signing_payload(&signed_message.data),
+                &signed_message.signature,
+            )
+            .map_err(|_| SigningError::InvalidSignature(signed_message.from))?;
+        let message: M = postcard::from_bytes(&signed_message.data)
+            .map_err(|err| SigningError::UnknownPayload(signed_message.from, err.to_string()))?;
         Ok((signed_message.from, message))
     }
 
-    pub fn sign_and_encode(secret_key: &SecretKey, message: &M) -> Result<Vec<u8>> {
+    /// Decode the JSON-of-JSON encoding used before the binary envelope existed
+    ///
+    /// TODO: remove once every node in the network sends the binary envelope
+    fn verify_and_decode_legacy(bytes: &[u8]) -> Result<(PublicKey, M), SigningError> {
+        let signed_message: Self = serde_json::from_slice(bytes)
+            .map_err(|err| SigningError::MalformedEnvelope(err.to_string()))?;
+        signed_message
+            .from
+            .verify(&signed_message.data, &signed_message.signature)
+            .map_err(|_| SigningError::InvalidSignature(signed_message.from))?;
+        let message: M = serde_json::from_slice(&signed_message.data)
+            .map_err(|err| SigningError::UnknownPayload(signed_message.from, err.to_string()))?;
+        Ok((signed_message.from, message))
+    }
+
+    fn signing_payload(data: &[u8]) -> Vec<u8> {
+        [M::SIGNING_CONTEXT, data].concat()
+    }
+
+    #[cfg(test)]
+    fn sign_and_encode_legacy(secret_key: &SecretKey, message: &M) -> Result<Vec<u8>> {
         let data = serde_json::to_vec(&message)?;
         let signature = secret_key.sign(&data);
-        let from: PublicKey = secret_key.public();
         let signed_message = Self {
             phantom: PhantomData,
-            from,
+            from: secret_key.public(),
             data,
             signature,
         };
-        let encoded = serde_json::to_vec(&signed_message)?;
-        Ok(encoded)
+        Ok(serde_json::to_vec(&signed_message)?)
+    }
+}
+
+/// The set of nodes allowed to publish secrets and policy, distributed as a signed document
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Policy {
+    pub version: u64,
+    pub admins: Vec<NodeId>,
+    /// Number of distinct admins that must sign off on a sensitive change before it applies
+    pub threshold: u32,
+    pub issued_at: DateTime<Utc>,
+}
+
+/// A policy along with who signed it and the signed bytes it came from
+#[derive(Debug, Clone)]
+pub struct SignedPolicy {
+    pub issued_by: NodeId,
+    pub policy: Policy,
+    pub bytes: Vec<u8>,
+}
+
+impl Signable for Policy {
+    const SIGNING_CONTEXT: &'static [u8] = b"room_101/policy/v1";
+}
+
+impl SignedPolicy {
+    pub fn sign(secret_key: &SecretKey, policy: Policy) -> Result<Self> {
+        let bytes = SignedMessage::sign_and_encode(secret_key, &policy)?;
+        Ok(Self {
+            issued_by: secret_key.public(),
+            policy,
+            bytes,
+        })
+    }
+
+    pub fn verify(bytes: Vec<u8>) -> Result<Self, SigningError> {
+        let (issued_by, policy) = SignedMessage::<Policy>::verify_and_decode(&bytes)?;
+        Ok(Self {
+            issued_by,
+            policy,
+            bytes,
+        })
+    }
+
+    /// Check that this policy may replace `current`
+    ///
+    /// It must have a newer version and be signed by one of the current admins, or by a key that
+    /// replaced one through a handover. `predecessors` are the keys the issuer used before. Before
+    /// any policy exists the caller is responsible for checking the issuer may publish the first
+    /// one.
+    pub fn supersedes(
+        &self,
+        current: Option<&SignedPolicy>,
+        predecessors: &[NodeId],
+    ) -> Result<(), SigningError> {
+        let Some(current) = current else {
+            return Ok(());
+        };
+
+        if self.policy.version <= current.policy.version {
+            return Err(SigningError::StalePolicy {
+                current: current.policy.version,
+                new: self.policy.version,
+            });
+        }
+
+        if predecessors
+            .iter()
+            .any(|predecessor| authorize_admin(Some(current), *predecessor).is_ok())
+        {
+            return Ok(());
+        }
+        authorize_admin(Some(current), self.issued_by)
+    }
+}
+
+/// Number of distinct admin signatures a sensitive change needs under the current policy
+///
+/// Without a policy a single admin is enough.
+pub fn required_approvals(policy: Option<&SignedPolicy>) -> usize {
+    policy.map_or(1, |policy| policy.policy.threshold.max(1) as usize)
+}
+
+/// Check that `signer` is an admin under the current policy
+///
+/// With no policy in place every admitted node is an admin, which keeps networks that predate
+/// policies working until one is published.
+pub fn authorize_admin(
+    policy: Option<&SignedPolicy>,
+    signer: PublicKey,
+) -> Result<(), SigningError> {
+    match policy {
+        Some(policy) if !policy.policy.admins.contains(&signer) => {
+            Err(SigningError::NotAdmin(signer))
+        }
+        _ => Ok(()),
     }
 }
 
@@ -51,6 +265,22 @@ mod tests {
         number: u32,
     }
 
+    impl Signable for TestMessage {
+        const SIGNING_CONTEXT: &'static [u8] = b"room_101/test/v1";
+        const LEGACY_JSON: bool = true;
+    }
+
+    /// Same layout as `TestMessage`, but a different kind of document
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct OtherMessage {
+        content: String,
+        number: u32,
+    }
+
+    impl Signable for OtherMessage {
+        const SIGNING_CONTEXT: &'static [u8] = b"room_101/test-other/v1";
+    }
+
     #[test]
     fn test_sign_and_encode_produces_valid_bytes() {
         let secret_key = SecretKey::generate(&mut thread_rng());
@@ -65,9 +295,9 @@ mod tests {
         let encoded = result.unwrap();
         assert!(!encoded.is_empty());
 
-        // Should be valid JSON
-        let parsed: serde_json::Value = serde_json::from_slice(&encoded).unwrap();
-        assert!(parsed.is_object());
+        // Should start with the binary envelope header
+        assert!(encoded.starts_with(MAGIC));
+        assert_eq!(encoded[MAGIC.len()], WIRE_VERSION);
     }
 
     #[test]
@@ -169,4 +399,323 @@ mod tests {
         assert_eq!(public_key, secret_key1.public());
         assert_ne!(public_key, secret_key2.public());
     }
+
+    #[test]
+    fn test_verify_and_decode_legacy_json_message() {
+        let secret_key = SecretKey::generate(&mut thread_rng());
+        let message = TestMessage {
+            content: "Legacy message".to_string(),
+            number: 7,
+        };
+
+        let encoded = SignedMessage::sign_and_encode_legacy(&secret_key, &message).unwrap();
+        let (public_key, decoded) =
+            SignedMessage::<TestMessage>::verify_and_decode(&encoded).unwrap();
+
+        assert_eq!(public_key, secret_key.public());
+        assert_eq!(decoded, message);
+    }
+
+    #[test]
+    fn test_verify_fails_with_tampered_legacy_data() {
+        let secret_key = SecretKey::generate(&mut thread_rng());
+        let message = TestMessage {
+            content: "Legacy message".to_string(),
+            number: 7,
+        };
+
+        let encoded = SignedMessage::sign_and_encode_legacy(&secret_key, &message).unwrap();
+
+        // Swap the first byte of the signed payload for something else
+        let mut signed: serde_json::Value = serde_json::from_slice(&encoded).unwrap();
+        signed["data"][0] = serde_json::json!(b'[');
+        let tampered = serde_json::to_vec(&signed).unwrap();
+        assert!(SignedMessage::<TestMessage>::verify_and_decode(&tampered).is_err());
+    }
+
+    #[test]
+    fn test_binary_encoding_is_smaller_than_legacy() {
+        let secret_key = SecretKey::generate(&mut thread_rng());
+        let message = TestMessage {
+            content: "Size comparison".to_string(),
+            number: 1,
+        };
+
+        let binary = SignedMessage::sign_and_encode(&secret_key, &message).unwrap();
+        let legacy = SignedMessage::sign_and_encode_legacy(&secret_key, &message).unwrap();
+
+        assert!(binary.len() * 3 < legacy.len());
+    }
+
+    #[test]
+    fn test_verify_fails_with_unsupported_wire_version() {
+        let secret_key = SecretKey::generate(&mut thread_rng());
+        let message = TestMessage {
+            content: "Future message".to_string(),
+            number: 2,
+        };
+
+        let mut encoded = SignedMessage::sign_and_encode(&secret_key, &message).unwrap();
+        encoded[MAGIC.len()] = WIRE_VERSION + 1;
+
+        let err = SignedMessage::<TestMessage>::verify_and_decode(&encoded).unwrap_err();
+        assert!(matches!(err, SigningError::UnsupportedWireVersion(_)));
+    }
+
+    #[test]
+    fn test_verify_accepts_earlier_wire_versions() {
+        let secret_key = SecretKey::generate(&mut thread_rng());
+        let message = TestMessage {
+            content: "Older peer".to_string(),
+            number: 4,
+        };
+
+        let mut encoded = SignedMessage::sign_and_encode(&secret_key, &message).unwrap();
+        encoded[MAGIC.len()] = WIRE_VERSION - 1;
+
+        let (from, decoded) = SignedMessage::<TestMessage>::verify_and_decode(&encoded).unwrap();
+        assert_eq!(from, secret_key.public());
+        assert_eq!(decoded.number, 4);
+    }
+
+    #[test]
+    fn test_verify_fails_without_signing_context() {
+        let secret_key = SecretKey::generate(&mut thread_rng());
+        let message = TestMessage {
+            content: "Context test".to_string(),
+            number: 3,
+        };
+
+        // Sign the bare payload, as a signature made for some other purpose would be
+        let data = postcard::to_stdvec(&message).unwrap();
+        let signed_message = SignedMessage::<TestMessage> {
+            phantom: PhantomData,
+            from: secret_key.public(),
+            signature: secret_key.sign(&data),
+            data,
+        };
+        let mut encoded = MAGIC.to_vec();
+        encoded.push(WIRE_VERSION);
+        encoded.extend(postcard::to_stdvec(&signed_message).unwrap());
+
+        let result = SignedMessage::<TestMessage>::verify_and_decode(&encoded);
+        assert!(matches!(result, Err(SigningError::InvalidSignature(_))));
+    }
+
+    #[test]
+    fn test_signature_is_bound_to_its_document_type() {
+        let secret_key = SecretKey::generate(&mut thread_rng());
+        let message = TestMessage {
+            content: "Context test".to_string(),
+            number: 4,
+        };
+
+        let encoded = SignedMessage::sign_and_encode(&secret_key, &message).unwrap();
+        let result = SignedMessage::<OtherMessage>::verify_and_decode(&encoded);
+        assert!(matches!(result, Err(SigningError::InvalidSignature(_))));
+    }
+
+    #[test]
+    fn test_legacy_json_is_refused_for_documents() {
+        let secret_key = SecretKey::generate(&mut thread_rng());
+        let message = TestMessage {
+            content: "Legacy document".to_string(),
+            number: 5,
+        };
+
+        // Same layout, but only `TestMessage` ever had a JSON form
+        let encoded = SignedMessage::sign_and_encode_legacy(&secret_key, &message).unwrap();
+        let result = SignedMessage::<OtherMessage>::verify_and_decode(&encoded);
+        assert!(matches!(result, Err(SigningError::UnknownEncoding)));
+    }
+
+    #[test]
+    fn test_verify_fails_with_unknown_encoding() {
+        let result = SignedMessage::<TestMessage>::verify_and_decode(b"garbage");
+        assert!(result.is_err());
+
+        let result = SignedMessage::<TestMessage>::verify_and_decode(b"");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_gossip_message_round_trip() {
+        use crate::actors::gossip::GossipMessage;
+        use iroh::NodeAddr;
+        use iroh_base::ticket::NodeTicket;
+
+        let secret_key = SecretKey::generate(&mut thread_rng());
+        let node_addr = NodeAddr::new(secret_key.public())
+            .with_direct_addresses(["127.0.0.1:8080".parse().unwrap()]);
+        let message = GossipMessage::Introduction {
+            node_id: secret_key.public(),
+            ticket: NodeTicket::new(node_addr.clone()),
+            time: chrono::Utc::now(),
+            hostname: Some("test-host".to_string()),
+            age_public_key: "age1test".to_string(),
+            protocol_version: 1,
+            capabilities: Default::default(),
+        };
+
+        let encoded = SignedMessage::sign_and_encode(&secret_key, &message).unwrap();
+        let (_, decoded) = SignedMessage::<GossipMessage>::verify_and_decode(&encoded).unwrap();
+
+        let GossipMessage::Introduction {
+            node_id,
+            ticket,
+            hostname,
+            ..
+        } = decoded
+        else {
+            panic!("Decoded the wrong message type");
+        };
+        assert_eq!(node_id, secret_key.public());
+        assert_eq!(ticket.node_addr(), &node_addr);
+        assert_eq!(hostname.as_deref(), Some("test-host"));
+    }
+
+    #[test]
+    fn test_legacy_heartbeat_without_capabilities_decodes() {
+        use crate::actors::gossip::{GossipMessage, capabilities::Capabilities};
+
+        #[derive(Debug, Serialize, Deserialize)]
+        enum LegacyMessage {
+            Heartbeat { sent_at: DateTime<Utc> },
+        }
+
+        impl Signable for LegacyMessage {
+            const SIGNING_CONTEXT: &'static [u8] = GossipMessage::SIGNING_CONTEXT;
+            const LEGACY_JSON: bool = true;
+        }
+
+        let secret_key = SecretKey::generate(&mut thread_rng());
+        let encoded = SignedMessage::sign_and_encode_legacy(
+            &secret_key,
+            &LegacyMessage::Heartbeat {
+                sent_at: chrono::Utc::now(),
+            },
+        )
+        .unwrap();
+
+        let (_, decoded) = SignedMessage::<GossipMessage>::verify_and_decode(&encoded).unwrap();
+        let GossipMessage::Heartbeat {
+            protocol_version,
+            capabilities,
+            ..
+        } = decoded
+        else {
+            panic!("Decoded the wrong message type");
+        };
+        assert_eq!(protocol_version, 0);
+        assert_eq!(capabilities, Capabilities::NONE);
+    }
+
+    #[test]
+    fn test_unknown_payload_is_distinguished_from_bad_signature() {
+        #[derive(Debug, Serialize, Deserialize)]
+        enum OldMessage {
+            Ping,
+        }
+
+        #[derive(Debug, Serialize, Deserialize)]
+        enum NewMessage {
+            Ping,
+            SomethingNew { value: u32 },
+        }
+
+        impl Signable for OldMessage {
+            const SIGNING_CONTEXT: &'static [u8] = b"room_101/test/v1";
+        }
+
+        impl Signable for NewMessage {
+            const SIGNING_CONTEXT: &'static [u8] = b"room_101/test/v1";
+        }
+
+        let secret_key = SecretKey::generate(&mut thread_rng());
+        let encoded =
+            SignedMessage::sign_and_encode(&secret_key, &NewMessage::SomethingNew { value: 1 })
+                .unwrap();
+
+        let err = SignedMessage::<OldMessage>::verify_and_decode(&encoded).unwrap_err();
+        match err {
+            SigningError::UnknownPayload(from, _) => assert_eq!(from, secret_key.public()),
+            other => panic!("Expected an unknown payload error, got {other:?}"),
+        }
+
+        // Variants both sides know about still decode fine
+        let encoded = SignedMessage::sign_and_encode(&secret_key, &NewMessage::Ping).unwrap();
+        let (_, decoded) = SignedMessage::<OldMessage>::verify_and_decode(&encoded).unwrap();
+        assert!(matches!(decoded, OldMessage::Ping));
+    }
+
+    fn test_policy(secret_key: &SecretKey, version: u64, admins: Vec<NodeId>) -> SignedPolicy {
+        let policy = Policy {
+            version,
+            admins,
+            threshold: 1,
+            issued_at: chrono::Utc::now(),
+        };
+        SignedPolicy::sign(secret_key, policy).unwrap()
+    }
+
+    #[test]
+    fn test_policy_round_trip() {
+        let admin = SecretKey::generate(&mut thread_rng());
+        let signed = test_policy(&admin, 1, vec![admin.public()]);
+
+        let verified = SignedPolicy::verify(signed.bytes.clone()).unwrap();
+        assert_eq!(verified.issued_by, admin.public());
+        assert_eq!(verified.policy, signed.policy);
+    }
+
+    #[test]
+    fn test_authorize_admin() {
+        let admin = SecretKey::generate(&mut thread_rng());
+        let other = SecretKey::generate(&mut thread_rng());
+        let policy = test_policy(&admin, 1, vec![admin.public()]);
+
+        assert!(authorize_admin(Some(&policy), admin.public()).is_ok());
+        assert!(matches!(
+            authorize_admin(Some(&policy), other.public()),
+            Err(SigningError::NotAdmin(_))
+        ));
+
+        // Without a policy everyone is trusted
+        assert!(authorize_admin(None, other.public()).is_ok());
+    }
+
+    #[test]
+    fn test_policy_supersedes() {
+        let admin = SecretKey::generate(&mut thread_rng());
+        let other = SecretKey::generate(&mut thread_rng());
+        let current = test_policy(&admin, 2, vec![admin.public()]);
+
+        // Newer version from an admin
+        let newer = test_policy(&admin, 3, vec![admin.public(), other.public()]);
+        assert!(newer.supersedes(Some(&current), &[]).is_ok());
+        assert!(newer.supersedes(None, &[]).is_ok());
+
+        // Same or older version
+        let stale = test_policy(&admin, 2, vec![admin.public()]);
+        assert!(matches!(
+            stale.supersedes(Some(&current), &[]),
+            Err(SigningError::StalePolicy { .. })
+        ));
+
+        // Newer version from someone who is not an admin
+        let usurper = test_policy(&other, 3, vec![other.public()]);
+        assert!(matches!(
+            usurper.supersedes(Some(&current), &[]),
+            Err(SigningError::NotAdmin(_))
+        ));
+
+        // An admin that has since rotated to a new key
+        let rotated = SecretKey::generate(&mut thread_rng());
+        let handed_over = test_policy(&rotated, 3, vec![rotated.public()]);
+        assert!(
+            handed_over
+                .supersedes(Some(&current), &[admin.public()])
+                .is_ok()
+        );
+    }
 }
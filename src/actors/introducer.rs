@@ -1,79 +1,307 @@
-use anyhow::{Context, Result, anyhow};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
 use chrono::Utc;
-use ractor::{Actor, ActorProcessingErr, ActorRef};
-use tracing::info;
+use iroh::NodeId;
+use ractor::{Actor, ActorProcessingErr, ActorRef, time::send_interval};
+use serde_json::json;
+use tracing::{debug, info, trace, warn};
 
 use crate::{
     actors::gossip::{
-        GossipEvent, GossipMessage, gossip_receiver::GossipReceiverMessage, gossip_sender,
+        GossipEvent, GossipMessage, capabilities::Capabilities, gossip_receiver, gossip_sender,
+        iroh::IrohMessage,
     },
-    db::{Identity, Peer},
+    db::{AuditEvent, Peer},
+    network::metadata::NodeMetadata,
 };
 
+/// How often we repeat our introduction, in case a peer missed it
+const REINTRODUCE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// How often we check whether our hostname or kernel changed
+const METADATA_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Shortest time between two introductions sent in reply to peers, so a burst of new peers
+/// only gets one reply
+const REPLY_COOLDOWN: Duration = Duration::from_secs(30);
+
 pub struct IntroducerActor;
 
+#[derive(Debug)]
+pub enum IntroducerMessage {
+    Gossip(GossipEvent),
+    /// Repeat our introduction
+    Reintroduce,
+    /// Introduce ourselves again if our metadata changed
+    CheckMetadata,
+}
+
+impl From<GossipEvent> for IntroducerMessage {
+    fn from(event: GossipEvent) -> Self {
+        IntroducerMessage::Gossip(event)
+    }
+}
+
+#[derive(Debug)]
+pub struct IntroducerState {
+    /// What we publish about ourselves
+    metadata: NodeMetadata,
+    /// When we last introduced ourselves
+    last_introduced: Option<Instant>,
+    /// Peers we have received an introduction from since we started
+    introduced_by: HashSet<NodeId>,
+    /// Last protocol version and capabilities each peer advertised, so we only write changes
+    protocols: HashMap<NodeId, (u16, Capabilities)>,
+    /// Last hostname each peer claimed, so duplicates are only flagged once
+    hostnames: HashMap<NodeId, String>,
+}
+
+impl IntroducerState {
+    fn new(metadata: NodeMetadata) -> Self {
+        Self {
+            metadata,
+            last_introduced: None,
+            introduced_by: HashSet::new(),
+            protocols: HashMap::new(),
+            hostnames: HashMap::new(),
+        }
+    }
+
+    /// Broadcast our introduction followed by our metadata
+    async fn introduce(&mut self) -> Result<()> {
+        let introduction = GossipMessage::introduction_now(self.metadata.hostname.clone()).await?;
+        gossip_sender::send(introduction).await?;
+        gossip_sender::send(GossipMessage::Metadata {
+            metadata: self.metadata.clone(),
+            sent_at: Utc::now(),
+        })
+        .await?;
+
+        self.last_introduced = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Introduce ourselves to a peer whose introduction we just saw for the first time
+    ///
+    /// It may have missed ours, for example if it joined after we last sent it.
+    async fn reply_to(&mut self, node_id: NodeId) -> Result<()> {
+        if !self.introduced_by.insert(node_id) {
+            return Ok(());
+        }
+        if self
+            .last_introduced
+            .is_some_and(|sent| sent.elapsed() < REPLY_COOLDOWN)
+        {
+            trace!(%node_id, "Introduced ourselves recently, not replying");
+            return Ok(());
+        }
+
+        debug!(%node_id, "Replying to introduction from a new peer");
+        self.introduce().await
+    }
+
+    /// Store what a peer told us about itself in its introduction
+    ///
+    /// Introductions are accepted from peers that have not been admitted, so one that names a node
+    /// other than its signer is dropped. Otherwise anyone could replace an admitted peer's ticket
+    /// and age key with their own.
+    async fn introduced(
+        &mut self,
+        sender_node_id: NodeId,
+        introduction: GossipMessage,
+    ) -> Result<()> {
+        let GossipMessage::Introduction {
+            node_id,
+            ticket,
+            hostname,
+            age_public_key,
+            protocol_version,
+            capabilities,
+            ..
+        } = introduction
+        else {
+            return Ok(());
+        };
+        if node_id != sender_node_id {
+            warn!(
+                %sender_node_id,
+                claimed_node_id = %node_id,
+                "Ignoring introduction signed by a different node than it introduces"
+            );
+            return Ok(());
+        }
+
+        let peer =
+            Peer::update_from_introduction(node_id, ticket, hostname, age_public_key).await?;
+        self.check_hostname(node_id, peer.and_then(|peer| peer.hostname))
+            .await?;
+        self.record_protocol(node_id, protocol_version, capabilities)
+            .await?;
+        self.reply_to(node_id).await
+    }
+
+    /// Flag `node_id` if another peer already claims the hostname it just told us about
+    async fn check_hostname(&mut self, node_id: NodeId, hostname: Option<String>) -> Result<()> {
+        let Some(hostname) = hostname else {
+            return Ok(());
+        };
+        if self.hostnames.get(&node_id) == Some(&hostname) {
+            return Ok(());
+        }
+        self.hostnames.insert(node_id, hostname.clone());
+
+        let others = Peer::sharing_hostname(node_id, &hostname).await?;
+        if others.is_empty() {
+            return Ok(());
+        }
+
+        warn!(%node_id, %hostname, ?others, "Peer claims a hostname another peer already uses");
+        AuditEvent::log(
+            "DUPLICATE_HOSTNAME".to_string(),
+            "Several peers claim the same hostname".to_string(),
+            json!({
+                "node_id": node_id.to_string(),
+                "hostname": hostname,
+                "others": others.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            }),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn record_protocol(
+        &mut self,
+        node_id: NodeId,
+        protocol_version: u16,
+        capabilities: Capabilities,
+    ) -> Result<()> {
+        if self.protocols.get(&node_id) == Some(&(protocol_version, capabilities)) {
+            return Ok(());
+        }
+
+        debug!(
+            %node_id,
+            protocol_version,
+            %capabilities,
+            "Peer advertised protocol version"
+        );
+        Peer::update_protocol(node_id, protocol_version, capabilities).await?;
+        let first_seen = self
+            .protocols
+            .insert(node_id, (protocol_version, capabilities))
+            .is_none();
+
+        // Our metadata is held back until everyone understands it, this peer may have been the
+        // last one that did not
+        if first_seen && capabilities.contains(Capabilities::METADATA) {
+            gossip_sender::send(GossipMessage::Metadata {
+                metadata: self.metadata.clone(),
+                sent_at: Utc::now(),
+            })
+            .await?;
+        }
+
+        // Queued messages may have been waiting for this peer to support them
+        gossip_sender::flush_outbox().await?;
+
+        Ok(())
+    }
+}
+
 impl Actor for IntroducerActor {
-    type Msg = GossipEvent;
-    type State = ();
-    type Arguments = ();
+    type Msg = IntroducerMessage;
+    type State = IntroducerState;
+    type Arguments = (NodeMetadata,);
 
     async fn pre_start(
         &self,
-        _myself: ActorRef<Self::Msg>,
-        _config: Self::Arguments,
+        myself: ActorRef<Self::Msg>,
+        (metadata,): Self::Arguments,
     ) -> Result<Self::State, ActorProcessingErr> {
         info!("Starting Introducer Actor");
 
-        let actor = ractor::registry::where_is("gossip_receiver".to_string())
-            .ok_or_else(|| anyhow!("Could not find gossip_receiver actor"))?;
-
-        actor.send_message::<GossipReceiverMessage>(GossipReceiverMessage::Subscribe(
-            "introducer".to_string(),
-        ))?;
+        gossip_receiver::subscribe("introducer", myself.clone())?;
+        send_interval(REINTRODUCE_INTERVAL, myself.get_cell(), || {
+            IntroducerMessage::Reintroduce
+        });
+        send_interval(METADATA_CHECK_INTERVAL, myself.get_cell(), || {
+            IntroducerMessage::CheckMetadata
+        });
 
-        Ok(())
+        Ok(IntroducerState::new(metadata))
     }
 
     async fn handle(
         &self,
         _myself: ractor::ActorRef<Self::Msg>,
         message: Self::Msg,
-        _state: &mut Self::State,
+        state: &mut Self::State,
     ) -> Result<(), ractor::ActorProcessingErr> {
-        match message {
-            GossipEvent::Message(_sender_node_id, gossip_message) => {
-                if let GossipMessage::Introduction {
-                    node_id,
-                    ticket,
-                    hostname,
-                    age_public_key,
+        let event = match message {
+            IntroducerMessage::Gossip(event) => event,
+            IntroducerMessage::Reintroduce => {
+                trace!("Repeating our introduction");
+                state.introduce().await?;
+                return Ok(());
+            }
+            IntroducerMessage::CheckMetadata => {
+                let metadata = state.metadata.refresh();
+                if metadata != state.metadata {
+                    info!(
+                        ?metadata,
+                        "Our metadata changed, introducing ourselves again"
+                    );
+                    state.metadata = metadata;
+                    state.introduce().await?;
+                }
+                return Ok(());
+            }
+        };
+
+        match event {
+            GossipEvent::Message(sender_node_id, gossip_message) => match gossip_message {
+                introduction @ GossipMessage::Introduction { .. } => {
+                    state.introduced(sender_node_id, introduction).await?;
+                }
+                GossipMessage::Metadata { metadata, .. } => {
+                    let peer = Peer::update_metadata(sender_node_id, metadata).await?;
+                    state
+                        .check_hostname(sender_node_id, peer.and_then(|peer| peer.hostname))
+                        .await?;
+                }
+                GossipMessage::Heartbeat {
+                    protocol_version,
+                    capabilities,
                     ..
-                } = gossip_message
-                {
-                    Peer::update_from_introduction(node_id, ticket, hostname, age_public_key)
+                }
+                | GossipMessage::DigestHeartbeat {
+                    protocol_version,
+                    capabilities,
+                    ..
+                } => {
+                    state
+                        .record_protocol(sender_node_id, protocol_version, capabilities)
                         .await?;
                 }
-            }
+                _ => {}
+            },
             GossipEvent::NeighborUp(node_id) => {
                 if !Peer::is_known(node_id).await?
                     && let Some(_peer) = Peer::insert_from_node_id(node_id).await?
                 {
-                    let identity = Identity::get().await?;
-                    let ticket = crate::actors::gossip::node_ticket()
-                        .context("Node ticket not yet initialized")?;
-
-                    let introduction = GossipMessage::Introduction {
-                        node_id: identity.id(),
-                        ticket,
-                        time: Utc::now(),
-                        hostname: None,
-                        age_public_key: identity.age_key.to_public().to_string(),
-                    };
-
-                    gossip_sender::send(introduction).await?;
+                    state.introduce().await?;
+                }
+
+                // Now that we are connected the endpoint knows how to reach the peer
+                if let Some(iroh) = ractor::registry::where_is("iroh".to_string()) {
+                    iroh.send_message(IrohMessage::RefreshPeerAddresses(node_id))?;
                 }
             }
             GossipEvent::NeighborDown(_node_id) => {}
+            GossipEvent::Lagged => {}
         }
 
         Ok(())
@@ -84,13 +312,59 @@ impl Actor for IntroducerActor {
         _myself: ActorRef<Self::Msg>,
         _state: &mut Self::State,
     ) -> Result<(), ActorProcessingErr> {
-        let actor = ractor::registry::where_is("gossip_receiver".to_string())
-            .ok_or_else(|| anyhow!("Could not find gossip_receiver actor"))?;
-
-        actor.send_message::<GossipReceiverMessage>(GossipReceiverMessage::Unsubscribe(
-            "introducer".to_string(),
-        ))?;
+        gossip_receiver::unsubscribe("introducer")?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use iroh::NodeAddr;
+    use iroh_base::ticket::NodeTicket;
+
+    use crate::db::PeerAdmission;
+
+    #[tokio::test]
+    async fn test_forged_introduction_does_not_change_peer() {
+        let victim = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        let attacker = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        let age_public_key = age::x25519::Identity::generate().to_public().to_string();
+        Peer::update_from_introduction(
+            victim,
+            NodeTicket::new(NodeAddr::new(victim)),
+            Some("db1".to_string()),
+            age_public_key.clone(),
+        )
+        .await
+        .unwrap();
+        Peer::set_admission(victim, PeerAdmission::Approved)
+            .await
+            .unwrap();
+
+        let forged = GossipMessage::Introduction {
+            node_id: victim,
+            ticket: NodeTicket::new(
+                NodeAddr::new(victim).with_direct_addresses(["192.0.2.1:4433".parse().unwrap()]),
+            ),
+            time: Utc::now(),
+            hostname: Some("evil".to_string()),
+            age_public_key: age::x25519::Identity::generate().to_public().to_string(),
+            protocol_version: 1,
+            capabilities: Capabilities::ours(),
+        };
+        let mut state = IntroducerState::new(NodeMetadata::default());
+        state.introduced(attacker, forged).await.unwrap();
+
+        let peer = Peer::get(victim).await.unwrap().unwrap();
+        assert_eq!(peer.hostname.as_deref(), Some("db1"));
+        assert!(peer.node_addr().direct_addresses.is_empty());
+        assert_eq!(
+            peer.age_public_key.map(|key| key.to_string()),
+            Some(age_public_key)
+        );
+        assert!(peer.capabilities.is_none());
+    }
+}
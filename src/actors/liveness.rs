@@ -0,0 +1,176 @@
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use ractor::{Actor, ActorProcessingErr, ActorRef, time::send_interval};
+use serde_json::json;
+use tokio::process::Command;
+use tracing::{debug, info, warn};
+
+use crate::{
+    actors::gossip::{GossipEvent, GossipMessage, gossip_receiver},
+    config::HeartbeatConfig,
+    db::{AuditEvent, Peer},
+    network::liveness::{FailureDetector, Liveness, Transition},
+};
+
+/// How often quiet peers are checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Tracks whether each peer is online from its heartbeats
+pub struct LivenessActor;
+
+#[derive(Debug)]
+pub enum LivenessMessage {
+    Gossip(GossipEvent),
+    /// Look for peers that have gone quiet
+    Check,
+    /// Heartbeats are sent at a new interval, see [`FailureDetector::set_interval`]
+    Configure(HeartbeatConfig),
+}
+
+impl From<GossipEvent> for LivenessMessage {
+    fn from(event: GossipEvent) -> Self {
+        LivenessMessage::Gossip(event)
+    }
+}
+
+#[derive(Debug)]
+pub struct LivenessState {
+    detector: FailureDetector,
+    /// Shell command run on every transition, see `--notify-command`
+    notify_command: Option<String>,
+}
+
+impl Actor for LivenessActor {
+    type Msg = LivenessMessage;
+    type State = LivenessState;
+    type Arguments = (Option<String>, HeartbeatConfig);
+
+    async fn pre_start(
+        &self,
+        myself: ActorRef<Self::Msg>,
+        (notify_command, heartbeat): Self::Arguments,
+    ) -> Result<Self::State, ActorProcessingErr> {
+        info!("Starting Liveness Actor");
+
+        // Peers that were up when we stopped get the usual grace period to show up again
+        let mut detector = FailureDetector::with_interval(heartbeat.max_delay());
+        let now = Instant::now();
+        for peer in Peer::list().await? {
+            if let Some(liveness) = peer.liveness
+                && matches!(liveness, Liveness::Online | Liveness::Suspect)
+            {
+                detector.track(peer.node_id, liveness, now);
+            }
+        }
+
+        gossip_receiver::subscribe("liveness", myself.clone())?;
+        send_interval(CHECK_INTERVAL, myself.get_cell(), || LivenessMessage::Check);
+
+        Ok(LivenessState {
+            detector,
+            notify_command,
+        })
+    }
+
+    async fn handle(
+        &self,
+        _myself: ActorRef<Self::Msg>,
+        message: Self::Msg,
+        state: &mut Self::State,
+    ) -> Result<(), ActorProcessingErr> {
+        let transitions = match message {
+            LivenessMessage::Gossip(GossipEvent::Message(
+                sender_node_id,
+                GossipMessage::Heartbeat { .. } | GossipMessage::DigestHeartbeat { .. },
+            )) => state
+                .detector
+                .heartbeat(sender_node_id, Instant::now())
+                .into_iter()
+                .collect(),
+            LivenessMessage::Gossip(GossipEvent::Message(
+                sender_node_id,
+                GossipMessage::Leaving { .. },
+            )) => state.detector.leave(sender_node_id).into_iter().collect(),
+            LivenessMessage::Gossip(_) => Vec::new(),
+            LivenessMessage::Check => state.detector.check(Instant::now()),
+            LivenessMessage::Configure(heartbeat) => {
+                state.detector.set_interval(heartbeat.max_delay());
+                Vec::new()
+            }
+        };
+
+        for transition in transitions {
+            state.record(transition).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn post_stop(
+        &self,
+        _myself: ActorRef<Self::Msg>,
+        _state: &mut Self::State,
+    ) -> Result<(), ActorProcessingErr> {
+        gossip_receiver::unsubscribe("liveness")?;
+
+        Ok(())
+    }
+}
+
+impl LivenessState {
+    /// Persist a transition, audit it and notify the operator
+    async fn record(&mut self, transition: Transition) -> Result<()> {
+        let Transition { node_id, from, to } = transition;
+
+        if Peer::set_liveness(node_id, to).await?.is_none() {
+            // The peer was removed, such as by a revocation
+            self.detector.forget(node_id);
+            return Ok(());
+        }
+
+        match to {
+            Liveness::Online => info!(%node_id, %from, "Peer is online"),
+            Liveness::Suspect => warn!(%node_id, %from, "Peer is suspect, heartbeats are late"),
+            Liveness::Offline => warn!(%node_id, %from, "Peer is offline"),
+            Liveness::Left => info!(%node_id, %from, "Peer left the network"),
+        }
+
+        AuditEvent::log(
+            "PEER_LIVENESS_CHANGED".to_string(),
+            format!("Peer is now {to}"),
+            json!({
+                "node_id": node_id.to_string(),
+                "from": from.to_string(),
+                "to": to.to_string(),
+            }),
+        )
+        .await?;
+
+        if let Some(command) = &self.notify_command {
+            notify(command, transition);
+        }
+
+        Ok(())
+    }
+}
+
+/// Run the operator's notification command in the background
+///
+/// The transition is passed in the environment, so the command can be a simple script.
+fn notify(command: &str, transition: Transition) {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg(command)
+        .env("ROOM_101_NODE_ID", transition.node_id.to_string())
+        .env("ROOM_101_PREVIOUS_STATE", transition.from.to_string())
+        .env("ROOM_101_STATE", transition.to.to_string());
+
+    tokio::spawn(async move {
+        match cmd.status().await {
+            Ok(status) if status.success() => debug!("Notification command succeeded"),
+            Ok(status) => warn!(%status, "Notification command failed"),
+            Err(err) => warn!(?err, "Failed to run notification command"),
+        }
+    });
+}
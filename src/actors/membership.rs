@@ -0,0 +1,876 @@
+use anyhow::{Result, bail};
+use iroh::NodeId;
+use iroh_gossip::proto::TopicId;
+use ractor::{Actor, ActorProcessingErr, ActorRef};
+use serde_json::json;
+use tracing::{debug, info, warn};
+
+use crate::{
+    actors::gossip::{
+        GossipEvent, GossipMessage, gossip_receiver, gossip_sender,
+        iroh::IrohMessage,
+        signing::{SignedPolicy, required_approvals},
+    },
+    config::PolicyConfig,
+    db::{
+        ApprovalRecord, AuditEvent, Identity, Invite, KeyHandoverRecord, Peer, PeerAdmission,
+        PolicyRecord, ProposalRecord, ProposalStatus, ReplicaRecord, RevocationRecord,
+    },
+    network::{
+        handover::SignedHandover,
+        invite::SignedInvite,
+        proposal::{ProposalAction, SignedApproval, SignedProposal},
+        revocation::SignedRevocation,
+    },
+};
+
+/// Applies membership records received over gossip and publishes the ones queued locally
+pub struct MembershipActor;
+
+#[derive(Debug)]
+pub struct MembershipState {
+    /// Topic of our network, invites for any other network are refused
+    network: TopicId,
+    /// The invite we joined with, if any
+    invite: Option<SignedInvite>,
+    /// Who may publish the first policy
+    policy: PolicyConfig,
+}
+
+impl Actor for MembershipActor {
+    type Msg = GossipEvent;
+    type State = MembershipState;
+    type Arguments = (TopicId, Option<String>, PolicyConfig);
+
+    async fn pre_start(
+        &self,
+        myself: ActorRef<Self::Msg>,
+        (network, invite, policy): Self::Arguments,
+    ) -> Result<Self::State, ActorProcessingErr> {
+        info!("Starting Membership Actor");
+
+        let invite = invite
+            .map(|token| SignedInvite::parse(&token, network))
+            .transpose()?;
+        gossip_receiver::subscribe("membership", myself)?;
+
+        Ok(MembershipState {
+            network,
+            invite,
+            policy,
+        })
+    }
+
+    async fn handle(
+        &self,
+        _myself: ActorRef<Self::Msg>,
+        message: Self::Msg,
+        state: &mut Self::State,
+    ) -> Result<(), ActorProcessingErr> {
+        match message {
+            GossipEvent::Message(sender_node_id, GossipMessage::Admission { node_id, .. }) => {
+                if needs_proposal(sender_node_id, "admission").await? {
+                    return Ok(());
+                }
+                admit(node_id, sender_node_id).await?;
+            }
+            GossipEvent::Message(sender_node_id, GossipMessage::InviteRedemption { token }) => {
+                redeem_invite(token, state.network, sender_node_id, sender_node_id).await?;
+            }
+            GossipEvent::Message(
+                sender_node_id,
+                GossipMessage::InviteConsumed { token, node_id },
+            ) => {
+                redeem_invite(token, state.network, node_id, sender_node_id).await?;
+            }
+            GossipEvent::Message(sender_node_id, GossipMessage::Policy { policy }) => {
+                apply_policy(policy, sender_node_id, &state.policy).await?;
+            }
+            GossipEvent::Message(_, GossipMessage::Proposal { proposal }) => {
+                if let Some(proposal) = record_proposal(proposal).await? {
+                    apply_if_approved(&proposal).await?;
+                }
+            }
+            GossipEvent::Message(_, GossipMessage::Approval { proposal, approval }) => {
+                record_approval(proposal, approval).await?;
+            }
+            GossipEvent::Message(sender_node_id, GossipMessage::Revocation { revocation }) => {
+                apply_revocation(revocation, sender_node_id).await?;
+            }
+            GossipEvent::Message(sender_node_id, GossipMessage::KeyHandover { handover }) => {
+                apply_handover(handover, sender_node_id).await?;
+            }
+            GossipEvent::Message(
+                sender_node_id,
+                GossipMessage::Labels {
+                    node_id, labels, ..
+                },
+            ) => {
+                if needs_proposal(sender_node_id, "labels").await? {
+                    return Ok(());
+                }
+                if Peer::set_labels(node_id, labels.clone()).await?.is_some() {
+                    info!(%node_id, ?labels, "Peer labels updated");
+                    AuditEvent::log(
+                        "PEER_LABELS_SET".to_string(),
+                        "Peer labels set by an admin".to_string(),
+                        json!({
+                            "node_id": node_id.to_string(),
+                            "labels": labels,
+                            "set_by": sender_node_id.to_string(),
+                        }),
+                    )
+                    .await?;
+                }
+            }
+            GossipEvent::Message(..) => {}
+            GossipEvent::NeighborUp(_node_id) => {
+                // Now that someone is listening, publish anything the CLI queued up
+                gossip_sender::flush_outbox().await?;
+
+                // Keep asking to be let in until the issuer tells us our invite was accepted
+                if let Some(invite) = &state.invite {
+                    let our_id = Identity::get().await?.id();
+                    if Invite::is_redeemed_by(invite.token.id, our_id).await? {
+                        debug!(invite_id = %invite.token.id, "Our invite was accepted");
+                        state.invite = None;
+                    } else {
+                        gossip_sender::send(GossipMessage::InviteRedemption {
+                            token: invite.bytes.clone(),
+                        })
+                        .await?;
+                    }
+                }
+            }
+            GossipEvent::NeighborDown(_node_id) => {}
+            GossipEvent::Lagged => {}
+        }
+
+        Ok(())
+    }
+
+    async fn post_stop(
+        &self,
+        _myself: ActorRef<Self::Msg>,
+        _state: &mut Self::State,
+    ) -> Result<(), ActorProcessingErr> {
+        gossip_receiver::unsubscribe("membership")?;
+
+        Ok(())
+    }
+}
+
+/// Mark a peer as approved because an admitted peer vouched for it
+async fn admit(node_id: NodeId, approved_by: NodeId) -> Result<()> {
+    // Our own admission is gossiped to everyone else, we never go into our own peer table
+    if node_id == Identity::get().await?.id() {
+        debug!(%approved_by, "This node was admitted");
+        return Ok(());
+    }
+    // Stale admissions are replayed by anti-entropy, so this is not an error
+    if RevocationRecord::is_revoked(node_id).await? {
+        warn!(%node_id, %approved_by, "Ignoring admission of a revoked peer");
+        return Ok(());
+    }
+    if Peer::is_admitted(node_id).await? {
+        debug!(%node_id, %approved_by, "Peer is already admitted");
+        return Ok(());
+    }
+
+    if !Peer::is_known(node_id).await? {
+        Peer::insert_from_node_id(node_id).await?;
+    }
+    Peer::set_admission(node_id, PeerAdmission::Approved).await?;
+
+    info!(%node_id, %approved_by, "Peer admitted to the network");
+    AuditEvent::log(
+        "PEER_APPROVED".to_string(),
+        "Peer approved by another member of the network".to_string(),
+        json!({
+            "node_id": node_id.to_string(),
+            "approved_by": approved_by.to_string(),
+        }),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Admit the node redeeming an invite, unless the invite is invalid or was already used
+///
+/// `reported_by` is the node that told us about the redemption, either the redeemer itself or the
+/// issuer announcing that the invite has been consumed. Only the issuer acts on a redemption, the
+/// rest of the network waits for its announcement.
+async fn redeem_invite(
+    token: Vec<u8>,
+    network: TopicId,
+    redeemer: NodeId,
+    reported_by: NodeId,
+) -> Result<()> {
+    let invite = match check_invite(token, network, redeemer, reported_by).await {
+        Ok(Some(invite)) => invite,
+        Ok(None) => return Ok(()),
+        Err(err) => {
+            warn!(?err, %redeemer, "Rejecting invite redemption");
+            AuditEvent::log(
+                "INVITE_REJECTED".to_string(),
+                "Rejected an invite redemption".to_string(),
+                json!({
+                    "node_id": redeemer.to_string(),
+                    "reported_by": reported_by.to_string(),
+                    "reason": err.to_string(),
+                }),
+            )
+            .await?;
+            return Ok(());
+        }
+    };
+
+    Invite::mark_consumed(&invite, redeemer).await?;
+    admit(redeemer, invite.issuer).await?;
+    Peer::add_labels(redeemer, &invite.token.labels).await?;
+
+    info!(
+        %redeemer,
+        invite_id = %invite.token.id,
+        labels = ?invite.token.labels,
+        "Invite redeemed"
+    );
+    AuditEvent::log(
+        "INVITE_REDEEMED".to_string(),
+        "Invite redeemed by a new node".to_string(),
+        json!({
+            "invite_id": invite.token.id.to_string(),
+            "issuer": invite.issuer.to_string(),
+            "node_id": redeemer.to_string(),
+            "labels": invite.token.labels,
+        }),
+    )
+    .await?;
+
+    // The issuer is the authority on whether its invite was used, so it tells everyone else
+    if invite.issuer == Identity::get().await?.id() {
+        gossip_sender::send_or_queue(GossipMessage::InviteConsumed {
+            token: invite.bytes,
+            node_id: redeemer,
+        })
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Verify an invite redemption, returns `None` if there is nothing for us to do
+async fn check_invite(
+    token: Vec<u8>,
+    network: TopicId,
+    redeemer: NodeId,
+    reported_by: NodeId,
+) -> Result<Option<SignedInvite>> {
+    let invite = SignedInvite::verify(token, network)?;
+
+    let our_id = Identity::get().await?.id();
+    if redeemer == our_id {
+        // Remembered so we stop redeeming it, and can restart once it has expired
+        debug!(invite_id = %invite.token.id, "Our invite redemption was accepted");
+        Invite::mark_consumed(&invite, our_id).await?;
+        return Ok(None);
+    }
+
+    // A leaked token could otherwise be raced by several nodes, each admitted by whichever nodes
+    // heard of it first, so only the issuer picks the redeemer
+    if reported_by == redeemer && invite.issuer != our_id {
+        debug!(%redeemer, invite_id = %invite.token.id, "Leaving invite redemption to its issuer");
+        return Ok(None);
+    }
+    if reported_by != redeemer && reported_by != invite.issuer {
+        bail!(
+            "Invite consumption was reported by {reported_by} instead of the issuer {}",
+            invite.issuer
+        );
+    }
+
+    if invite.issuer != our_id && !Peer::is_admitted(invite.issuer).await? {
+        bail!("Invite was issued by {} who is not admitted", invite.issuer);
+    }
+    if !PolicyRecord::is_admin(invite.issuer).await? {
+        bail!("Invite was issued by {} who is not an admin", invite.issuer);
+    }
+    if PolicyRecord::required_approvals().await? > 1 {
+        bail!("Invites can not be used while changes need several approvals");
+    }
+
+    if let Some(existing) = Invite::get(invite.token.id).await?
+        && let Some(consumed_by) = existing.consumed_by
+    {
+        if consumed_by == redeemer {
+            debug!(%redeemer, invite_id = %invite.token.id, "Invite already redeemed by this node");
+            return Ok(None);
+        }
+        bail!("Invite was already redeemed by {consumed_by}");
+    }
+
+    // Peers told of a consumption may see it after the expiry, only the redeemer is held to it
+    if reported_by == redeemer && invite.token.is_expired() {
+        bail!("Invite expired at {}", invite.token.expires_at);
+    }
+
+    Ok(Some(invite))
+}
+
+/// Replace our policy with a newer one, if it was signed by a current admin
+async fn apply_policy(
+    policy: Vec<u8>,
+    sender_node_id: NodeId,
+    config: &PolicyConfig,
+) -> Result<()> {
+    let policy = match SignedPolicy::verify(policy) {
+        Ok(policy) => policy,
+        Err(err) => {
+            warn!(?err, %sender_node_id, "Ignoring invalid policy");
+            return Ok(());
+        }
+    };
+    let current = PolicyRecord::current().await?;
+
+    if current.is_none() {
+        let our_id = Identity::get().await?.id();
+        let admitted = policy.issued_by == our_id || Peer::is_admitted(policy.issued_by).await?;
+        if !may_publish_first(&policy, admitted, config) {
+            return Ok(());
+        }
+    }
+
+    // Once several admins must agree, a new policy only arrives by way of a proposal
+    if required_approvals(current.as_ref()) > 1 {
+        debug!(
+            version = policy.policy.version,
+            "Ignoring policy that was not approved through a proposal"
+        );
+        return Ok(());
+    }
+
+    if let Err(err) = store_policy(&policy, current.as_ref(), sender_node_id).await {
+        debug!(?err, version = policy.policy.version, "Ignoring policy");
+    }
+    Ok(())
+}
+
+/// Whether `policy` may become the first policy of the network
+///
+/// Otherwise any member could race to publish a policy naming only itself as admin, so only the
+/// configured bootstrap admins may, unless trust on first use was turned on.
+fn may_publish_first(policy: &SignedPolicy, admitted: bool, config: &PolicyConfig) -> bool {
+    if !admitted {
+        warn!(issued_by = %policy.issued_by, "Ignoring policy issued by a peer that is not admitted");
+        return false;
+    }
+    if !config.may_publish_first(policy.issued_by) {
+        warn!(
+            issued_by = %policy.issued_by,
+            "Ignoring first policy issued by a peer that is not a bootstrap admin"
+        );
+        return false;
+    }
+    true
+}
+
+/// Store `policy` if it supersedes `current`
+async fn store_policy(
+    policy: &SignedPolicy,
+    current: Option<&SignedPolicy>,
+    received_from: NodeId,
+) -> Result<()> {
+    let predecessors = KeyHandoverRecord::lineage(policy.issued_by).await?;
+    policy.supersedes(current, &predecessors)?;
+    PolicyRecord::store(policy).await?;
+
+    info!(
+        version = policy.policy.version,
+        admins = ?policy.policy.admins,
+        threshold = policy.policy.threshold,
+        "Applied new policy"
+    );
+    AuditEvent::log(
+        "POLICY_UPDATED".to_string(),
+        "Applied a new policy".to_string(),
+        json!({
+            "version": policy.policy.version,
+            "issued_by": policy.issued_by.to_string(),
+            "received_from": received_from.to_string(),
+            "admins": policy.policy.admins.iter().map(|admin| admin.to_string()).collect::<Vec<_>>(),
+            "threshold": policy.policy.threshold,
+        }),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Whether a direct admin message must be ignored because the policy wants a proposal instead
+async fn needs_proposal(sender_node_id: NodeId, kind: &str) -> Result<bool> {
+    if PolicyRecord::required_approvals().await? > 1 {
+        warn!(%sender_node_id, kind, "Ignoring change that was not approved through a proposal");
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+/// Store a proposal published by an admin, returns `None` if it is invalid
+pub async fn record_proposal(proposal: Vec<u8>) -> Result<Option<SignedProposal>> {
+    let proposal = match SignedProposal::verify(proposal) {
+        Ok(proposal) => proposal,
+        Err(err) => {
+            warn!(?err, "Ignoring invalid proposal");
+            return Ok(None);
+        }
+    };
+
+    let our_id = Identity::get().await?.id();
+    if !PolicyRecord::is_admin(proposal.proposed_by).await? {
+        warn!(
+            proposed_by = %proposal.proposed_by,
+            proposal_id = %proposal.proposal.id,
+            "Ignoring proposal from a peer that is not an admin"
+        );
+        return Ok(None);
+    }
+    if proposal.proposed_by != our_id && !Peer::is_admitted(proposal.proposed_by).await? {
+        warn!(
+            proposed_by = %proposal.proposed_by,
+            "Ignoring proposal from a peer that is not admitted"
+        );
+        return Ok(None);
+    }
+
+    if ProposalRecord::store(&proposal).await? {
+        info!(
+            proposal_id = %proposal.proposal.id,
+            proposed_by = %proposal.proposed_by,
+            action = %proposal.proposal.action,
+            "New proposal"
+        );
+        AuditEvent::log(
+            "PROPOSAL_CREATED".to_string(),
+            "A change was proposed".to_string(),
+            json!({
+                "proposal_id": proposal.proposal.id.to_string(),
+                "proposed_by": proposal.proposed_by.to_string(),
+                "action": proposal.proposal.action.to_string(),
+                "expires_at": proposal.proposal.expires_at,
+            }),
+        )
+        .await?;
+    }
+
+    Ok(Some(proposal))
+}
+
+/// Store an admin's approval of a proposal and apply the proposal if it now has enough of them
+async fn record_approval(proposal: Vec<u8>, approval: Vec<u8>) -> Result<()> {
+    let Some(proposal) = record_proposal(proposal).await? else {
+        return Ok(());
+    };
+    let approval = match SignedApproval::verify(approval, &proposal) {
+        Ok(approval) => approval,
+        Err(err) => {
+            warn!(?err, proposal_id = %proposal.proposal.id, "Ignoring invalid approval");
+            return Ok(());
+        }
+    };
+
+    if !PolicyRecord::is_admin(approval.approver).await? {
+        warn!(
+            approver = %approval.approver,
+            proposal_id = %proposal.proposal.id,
+            "Ignoring approval from a peer that is not an admin"
+        );
+        return Ok(());
+    }
+
+    if ApprovalRecord::store(&approval).await? {
+        info!(
+            proposal_id = %proposal.proposal.id,
+            approver = %approval.approver,
+            "Proposal approved"
+        );
+        AuditEvent::log(
+            "PROPOSAL_APPROVED".to_string(),
+            "An admin approved a proposal".to_string(),
+            json!({
+                "proposal_id": proposal.proposal.id.to_string(),
+                "approver": approval.approver.to_string(),
+            }),
+        )
+        .await?;
+    }
+
+    apply_if_approved(&proposal).await
+}
+
+/// Apply a pending proposal once enough distinct admins have approved it
+pub async fn apply_if_approved(proposal: &SignedProposal) -> Result<()> {
+    let proposal_id = proposal.proposal.id;
+    let Some(record) = ProposalRecord::get(proposal_id).await? else {
+        return Ok(());
+    };
+    if record.status != ProposalStatus::Pending {
+        return Ok(());
+    }
+
+    if proposal.proposal.is_expired() {
+        ProposalRecord::set_status(proposal_id, ProposalStatus::Expired).await?;
+        info!(%proposal_id, "Proposal expired");
+        AuditEvent::log(
+            "PROPOSAL_EXPIRED".to_string(),
+            "A proposal expired before it was approved".to_string(),
+            json!({
+                "proposal_id": proposal_id.to_string(),
+            }),
+        )
+        .await?;
+        return Ok(());
+    }
+
+    let policy = PolicyRecord::current().await?;
+    let approvals = ApprovalRecord::for_proposal(proposal).await?;
+    let approvers = PolicyRecord::distinct_admins(proposal.signers(&approvals)).await?;
+    let required = required_approvals(policy.as_ref());
+    if approvers.len() < required {
+        debug!(%proposal_id, approvals = approvers.len(), required, "Proposal needs more approvals");
+        return Ok(());
+    }
+
+    let approvers: Vec<String> = approvers
+        .iter()
+        .map(|node_id| node_id.to_string())
+        .collect();
+    match apply_action(
+        &proposal.proposal.action,
+        proposal.proposed_by,
+        policy.as_ref(),
+    )
+    .await
+    {
+        Ok(()) => {
+            ProposalRecord::set_status(proposal_id, ProposalStatus::Applied).await?;
+            info!(%proposal_id, action = %proposal.proposal.action, "Proposal applied");
+            AuditEvent::log(
+                "PROPOSAL_APPLIED".to_string(),
+                "A proposal reached its approval threshold and was applied".to_string(),
+                json!({
+                    "proposal_id": proposal_id.to_string(),
+                    "action": proposal.proposal.action.to_string(),
+                    "approvers": approvers,
+                }),
+            )
+            .await?;
+        }
+        Err(err) => {
+            ProposalRecord::set_status(proposal_id, ProposalStatus::Failed).await?;
+            warn!(?err, %proposal_id, "Failed to apply proposal");
+            AuditEvent::log(
+                "PROPOSAL_FAILED".to_string(),
+                "A proposal was approved but could not be applied".to_string(),
+                json!({
+                    "proposal_id": proposal_id.to_string(),
+                    "action": proposal.proposal.action.to_string(),
+                    "approvers": approvers,
+                    "reason": err.to_string(),
+                }),
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn apply_action(
+    action: &ProposalAction,
+    proposed_by: NodeId,
+    policy: Option<&SignedPolicy>,
+) -> Result<()> {
+    match action {
+        ProposalAction::Admit { node_id } => admit(*node_id, proposed_by).await,
+        ProposalAction::SetLabels { node_id, labels } => {
+            if Peer::set_labels(*node_id, labels.clone()).await?.is_none() {
+                bail!("No peer with node ID {node_id}");
+            }
+            Ok(())
+        }
+        ProposalAction::Policy { policy: signed } => {
+            let new_policy = SignedPolicy::verify(signed.clone())?;
+            store_policy(&new_policy, policy, proposed_by).await
+        }
+        ProposalAction::Revoke { revocation } => {
+            let revocation = SignedRevocation::verify(revocation.clone())?;
+            if !PolicyRecord::is_admin(revocation.revoked_by).await? {
+                bail!(
+                    "Revocation was issued by {} who is not an admin",
+                    revocation.revoked_by
+                );
+            }
+            revoke(&revocation, proposed_by).await
+        }
+    }
+}
+
+/// Ban a node because an admin published a revocation for it
+async fn apply_revocation(revocation: Vec<u8>, sender_node_id: NodeId) -> Result<()> {
+    let revocation = match SignedRevocation::verify(revocation) {
+        Ok(revocation) => revocation,
+        Err(err) => {
+            warn!(?err, %sender_node_id, "Ignoring invalid revocation");
+            return Ok(());
+        }
+    };
+
+    let our_id = Identity::get().await?.id();
+    if !PolicyRecord::is_admin(revocation.revoked_by).await? {
+        warn!(
+            revoked_by = %revocation.revoked_by,
+            node_id = %revocation.revocation.node_id,
+            "Ignoring revocation issued by a peer that is not an admin"
+        );
+        return Ok(());
+    }
+    if revocation.revoked_by != our_id && !Peer::is_admitted(revocation.revoked_by).await? {
+        warn!(
+            revoked_by = %revocation.revoked_by,
+            "Ignoring revocation issued by a peer that is not admitted"
+        );
+        return Ok(());
+    }
+    if needs_proposal(sender_node_id, "revocation").await? {
+        return Ok(());
+    }
+
+    revoke(&revocation, sender_node_id).await
+}
+
+/// Persist a revocation and forget everything about the revoked node
+///
+/// The node is dropped from the peer table and static discovery. It may still have a copy of the
+/// secrets it held, so they are flagged for rotation and their names recorded in the audit log.
+pub async fn revoke(revocation: &SignedRevocation, received_from: NodeId) -> Result<()> {
+    let node_id = revocation.revocation.node_id;
+    if !RevocationRecord::store(revocation).await? {
+        debug!(%node_id, "Peer is already revoked");
+        return Ok(());
+    }
+
+    if node_id == Identity::get().await?.id() {
+        warn!("This node has been revoked, the rest of the network will ignore it");
+    }
+
+    Peer::delete(node_id).await?;
+    ReplicaRecord::forget(node_id).await?;
+    if let Some(iroh) = ractor::registry::where_is("iroh".to_string()) {
+        iroh.send_message(IrohMessage::ForgetPeer(node_id))?;
+    }
+    let secrets = RevocationRecord::flag_secrets(node_id).await?;
+
+    info!(
+        %node_id,
+        revoked_by = %revocation.revoked_by,
+        ?secrets,
+        "Peer revoked"
+    );
+    AuditEvent::log(
+        "PEER_REVOKED".to_string(),
+        "Peer revoked and removed from the network".to_string(),
+        json!({
+            "node_id": node_id.to_string(),
+            "revoked_by": revocation.revoked_by.to_string(),
+            "received_from": received_from.to_string(),
+            "reason": revocation.revocation.reason,
+            "secrets_to_rotate": secrets,
+        }),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Carry a peer's membership over to the keys it rotated to
+///
+/// The handover is signed by the old key and the gossip message carrying it by the new one, so
+/// both keys vouch for the move.
+async fn apply_handover(handover: Vec<u8>, sender_node_id: NodeId) -> Result<()> {
+    let handover = match SignedHandover::verify(handover)
+        .and_then(|handover| handover.handover_from(sender_node_id))
+    {
+        Ok(handover) => handover,
+        Err(err) => {
+            warn!(?err, %sender_node_id, "Ignoring invalid key handover");
+            return Ok(());
+        }
+    };
+    let old_node_id = handover.handover.old_node_id;
+    let new_node_id = handover.handover.new_node_id;
+
+    if old_node_id == Identity::get().await?.id() {
+        return Ok(());
+    }
+    if RevocationRecord::is_revoked(old_node_id).await?
+        || RevocationRecord::is_revoked(new_node_id).await?
+    {
+        warn!(%old_node_id, %new_node_id, "Ignoring key handover involving a revoked peer");
+        return Ok(());
+    }
+    if !Peer::is_admitted(old_node_id).await? {
+        debug!(%old_node_id, "Ignoring key handover from a peer that is not admitted");
+        return Ok(());
+    }
+
+    let age_public_key = match handover.handover.age_public_key.parse() {
+        Ok(age_public_key) => age_public_key,
+        Err(err) => {
+            warn!(?err, %old_node_id, "Ignoring key handover with an invalid age key");
+            return Ok(());
+        }
+    };
+
+    if handover.handover.rotates_node_key() && !KeyHandoverRecord::store(&handover).await? {
+        debug!(%old_node_id, "Key handover already applied");
+        return Ok(());
+    }
+    Peer::migrate(old_node_id, new_node_id, age_public_key).await?;
+
+    if handover.handover.rotates_node_key()
+        && let Some(iroh) = ractor::registry::where_is("iroh".to_string())
+    {
+        iroh.send_message(IrohMessage::ForgetPeer(old_node_id))?;
+    }
+    let secrets = KeyHandoverRecord::retarget_secrets(old_node_id, new_node_id).await?;
+
+    info!(%old_node_id, %new_node_id, "Peer rotated its keys");
+    AuditEvent::log(
+        "PEER_KEYS_ROTATED".to_string(),
+        "Peer rotated its keys".to_string(),
+        json!({
+            "old_node_id": old_node_id.to_string(),
+            "new_node_id": new_node_id.to_string(),
+            "age_public_key": handover.handover.age_public_key,
+            // Anything encrypted to the old age key has to be encrypted again
+            "secrets_to_reencrypt": secrets,
+        }),
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use crate::actors::gossip::signing::Policy;
+    use crate::network::invite::InviteToken;
+    use crate::network::revocation::Revocation;
+
+    fn sole_admin_policy(secret_key: &iroh::SecretKey) -> SignedPolicy {
+        let policy = Policy {
+            version: 1,
+            admins: vec![secret_key.public()],
+            threshold: 1,
+            issued_at: chrono::Utc::now(),
+        };
+        SignedPolicy::sign(secret_key, policy).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_replayed_admission_of_revoked_peer_is_ignored() {
+        let admin = iroh::SecretKey::generate(rand::rngs::OsRng);
+        let node_id = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        let revocation = SignedRevocation::sign(
+            &admin,
+            Revocation {
+                node_id,
+                reason: None,
+                revoked_at: chrono::Utc::now(),
+            },
+        )
+        .unwrap();
+        RevocationRecord::store(&revocation).await.unwrap();
+
+        admit(node_id, admin.public()).await.unwrap();
+        assert!(!Peer::is_admitted(node_id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_own_admission_is_not_stored() {
+        // Another test may have created our identity first
+        let _ = Identity::get_or_generate().await;
+        let our_id = Identity::get().await.unwrap().id();
+        let admin = iroh::SecretKey::generate(rand::rngs::OsRng);
+
+        admit(our_id, admin.public()).await.unwrap();
+        assert!(!Peer::is_known(our_id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_only_the_issuer_acts_on_a_redemption() {
+        // Another test may have created our identity first
+        let _ = Identity::get_or_generate().await;
+        let network = TopicId::from_bytes([3; 32]);
+        let issuer = iroh::SecretKey::generate(rand::rngs::OsRng);
+        Peer::insert_from_node_id(issuer.public()).await.unwrap();
+        Peer::set_admission(issuer.public(), PeerAdmission::Approved)
+            .await
+            .unwrap();
+        let token = InviteToken::new(
+            network,
+            vec![],
+            vec![],
+            chrono::Utc::now() + chrono::Duration::hours(1),
+        );
+        let token = SignedInvite::parse(&token.sign(&issuer).unwrap(), network)
+            .unwrap()
+            .bytes;
+
+        // Whoever presents the token first is not admitted by nodes other than the issuer
+        let redeemer = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        redeem_invite(token.clone(), network, redeemer, redeemer)
+            .await
+            .unwrap();
+        assert!(!Peer::is_admitted(redeemer).await.unwrap());
+
+        // Nor when someone other than the issuer claims the invite was consumed
+        let member = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        redeem_invite(token, network, redeemer, member)
+            .await
+            .unwrap();
+        assert!(!Peer::is_admitted(redeemer).await.unwrap());
+    }
+
+    #[test]
+    fn test_member_cannot_race_to_publish_first_policy() {
+        let admin = iroh::SecretKey::generate(rand::rngs::OsRng);
+        let member = iroh::SecretKey::generate(rand::rngs::OsRng);
+        let from_admin = sole_admin_policy(&admin);
+        let from_member = sole_admin_policy(&member);
+        let config = PolicyConfig {
+            bootstrap_admins: vec![admin.public()],
+            trust_first: false,
+        };
+
+        // The member's policy arrives first and is refused, the admin's is still accepted
+        assert!(!may_publish_first(&from_member, true, &config));
+        assert!(may_publish_first(&from_admin, true, &config));
+        assert!(!may_publish_first(&from_admin, false, &config));
+
+        // Nobody may publish the first policy unless someone was trusted to
+        let unconfigured = PolicyConfig::default();
+        assert!(!may_publish_first(&from_admin, true, &unconfigured));
+
+        // With trust on first use the race is decided by arrival, the loser is stale
+        let trusting = PolicyConfig {
+            trust_first: true,
+            ..unconfigured
+        };
+        assert!(may_publish_first(&from_member, true, &trusting));
+        assert!(from_admin.supersedes(Some(&from_member), &[]).is_err());
+    }
+}
@@ -1,7 +1,12 @@
+pub mod clock;
 pub mod gossip;
 pub mod introducer;
+pub mod liveness;
+pub mod membership;
+pub mod prober;
+pub mod reconciler;
 pub mod supervisor;
 pub mod systemd_secrets;
 
 // Re-export the main types from supervisor for easier access
-pub use supervisor::{AppConfig, SupervisorActor};
+pub use supervisor::{AppConfig, SupervisorActor, SupervisorMessage};
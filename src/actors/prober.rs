@@ -0,0 +1,76 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use ractor::{Actor, ActorProcessingErr, ActorRef, time::send_interval};
+use tracing::{debug, info, trace};
+
+use crate::{
+    actors::gossip::{capabilities::Capabilities, iroh::IrohMessage},
+    db::{Peer, PeerAdmission},
+    network::{liveness::Liveness, ping},
+};
+
+/// How often every peer is pinged
+const PROBE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Measures the round trip time to every online peer, see [`crate::network::ping`]
+pub struct ProberActor;
+
+#[derive(Debug)]
+pub enum ProberMessage {
+    /// Ping every online peer
+    Probe,
+}
+
+impl Actor for ProberActor {
+    type Msg = ProberMessage;
+    type State = ();
+    type Arguments = ();
+
+    async fn pre_start(
+        &self,
+        myself: ActorRef<Self::Msg>,
+        _args: Self::Arguments,
+    ) -> Result<Self::State, ActorProcessingErr> {
+        info!("Starting Prober Actor");
+
+        send_interval(PROBE_INTERVAL, myself.get_cell(), || ProberMessage::Probe);
+
+        Ok(())
+    }
+
+    async fn handle(
+        &self,
+        _myself: ActorRef<Self::Msg>,
+        message: Self::Msg,
+        _state: &mut Self::State,
+    ) -> Result<(), ActorProcessingErr> {
+        match message {
+            ProberMessage::Probe => probe().await?,
+        }
+
+        Ok(())
+    }
+}
+
+/// Ask the iroh actor to ping each online peer that answers pings, one connection per peer
+async fn probe() -> Result<()> {
+    let Some(iroh) = ractor::registry::where_is("iroh".to_string()) else {
+        debug!("Network is not running, not probing");
+        return Ok(());
+    };
+
+    let peers = Peer::list().await?.into_iter().filter(|peer| {
+        peer.admission == PeerAdmission::Approved
+            && peer.liveness == Some(Liveness::Online)
+            && peer
+                .capabilities
+                .is_some_and(|capabilities| capabilities.contains(Capabilities::DIRECT_PING))
+    });
+    for peer in peers {
+        trace!(node_id = %peer.node_id, "Pinging peer");
+        iroh.send_message(IrohMessage::Ping(peer.node_id, ping::PING_TIMEOUT, None))?;
+    }
+
+    Ok(())
+}
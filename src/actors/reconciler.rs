@@ -0,0 +1,280 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use iroh::NodeId;
+use ractor::{Actor, ActorProcessingErr, ActorRef, time::send_interval};
+use rand::seq::SliceRandom;
+use serde_json::json;
+use tracing::{debug, info, trace, warn};
+
+use crate::{
+    actors::gossip::{
+        GossipEvent, GossipMessage, capabilities::Capabilities, gossip_receiver, gossip_sender,
+        iroh::IrohMessage,
+    },
+    db::{AuditEvent, Peer, PeerAdmission, PolicyRecord, RevocationRecord},
+    network::{
+        digest::{Divergence, StateDigest},
+        liveness::Liveness,
+    },
+};
+
+/// How long our own digest is reused before it is recomputed
+const DIGEST_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A peer's state must differ from ours for this long before we act, so records that are still
+/// spreading through gossip do not count
+const DIVERGENCE_GRACE: Duration = Duration::from_secs(20);
+
+/// Least time between two syncs with the same peer
+const SYNC_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// How often we reconcile with a random peer, whether or not anything looks amiss
+const ANTI_ENTROPY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Least time between two reconciliations started because gossip lagged, it tends to lag in bursts
+const LAGGED_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// Compares the state digests in our peers' heartbeats with ours, and fetches what we missed
+///
+/// Every so often, and whenever gossip drops messages, we also reconcile with a random peer.
+pub struct ReconcilerActor;
+
+#[derive(Debug)]
+pub enum ReconcilerMessage {
+    Gossip(GossipEvent),
+    /// Reconcile with a random peer
+    AntiEntropy,
+}
+
+impl From<GossipEvent> for ReconcilerMessage {
+    fn from(event: GossipEvent) -> Self {
+        ReconcilerMessage::Gossip(event)
+    }
+}
+
+#[derive(Debug)]
+struct Diverged {
+    since: Instant,
+    last_sync: Option<Instant>,
+    /// Whether the divergence has been written to the audit log
+    reported: bool,
+}
+
+#[derive(Debug)]
+pub struct ReconcilerState {
+    digest: StateDigest,
+    refreshed_at: Option<Instant>,
+    diverged: HashMap<NodeId, Diverged>,
+    /// When we last reconciled because gossip lagged
+    lagged_at: Option<Instant>,
+}
+
+impl Actor for ReconcilerActor {
+    type Msg = ReconcilerMessage;
+    type State = ReconcilerState;
+    type Arguments = ();
+
+    async fn pre_start(
+        &self,
+        myself: ActorRef<Self::Msg>,
+        _args: Self::Arguments,
+    ) -> Result<Self::State, ActorProcessingErr> {
+        info!("Starting Reconciler Actor");
+
+        gossip_receiver::subscribe("reconciler", myself.clone())?;
+        send_interval(ANTI_ENTROPY_INTERVAL, myself.get_cell(), || {
+            ReconcilerMessage::AntiEntropy
+        });
+
+        Ok(ReconcilerState {
+            digest: StateDigest::default(),
+            refreshed_at: None,
+            diverged: HashMap::new(),
+            lagged_at: None,
+        })
+    }
+
+    async fn handle(
+        &self,
+        _myself: ActorRef<Self::Msg>,
+        message: Self::Msg,
+        state: &mut Self::State,
+    ) -> Result<(), ActorProcessingErr> {
+        match message {
+            ReconcilerMessage::Gossip(GossipEvent::Message(
+                sender_node_id,
+                GossipMessage::DigestHeartbeat {
+                    digest,
+                    capabilities,
+                    ..
+                },
+            )) => {
+                state.compare(sender_node_id, digest, capabilities).await?;
+            }
+            ReconcilerMessage::Gossip(GossipEvent::Lagged) => {
+                if state
+                    .lagged_at
+                    .is_none_or(|lagged_at| lagged_at.elapsed() >= LAGGED_COOLDOWN)
+                {
+                    state.lagged_at = Some(Instant::now());
+                    info!("Gossip dropped messages, reconciling with a peer");
+                    reconcile_with_random_peer().await?;
+                }
+            }
+            ReconcilerMessage::Gossip(_) => {}
+            ReconcilerMessage::AntiEntropy => {
+                reconcile_with_random_peer().await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn post_stop(
+        &self,
+        _myself: ActorRef<Self::Msg>,
+        _state: &mut Self::State,
+    ) -> Result<(), ActorProcessingErr> {
+        gossip_receiver::unsubscribe("reconciler")?;
+
+        Ok(())
+    }
+}
+
+impl ReconcilerState {
+    async fn compare(
+        &mut self,
+        node_id: NodeId,
+        theirs: StateDigest,
+        capabilities: Capabilities,
+    ) -> Result<()> {
+        if self
+            .refreshed_at
+            .is_none_or(|refreshed_at| refreshed_at.elapsed() >= DIGEST_REFRESH_INTERVAL)
+        {
+            self.digest = StateDigest::collect().await?;
+            self.refreshed_at = Some(Instant::now());
+        }
+
+        let Some(divergence) = self.digest.diverges_from(&theirs) else {
+            if self
+                .diverged
+                .remove(&node_id)
+                .is_some_and(|diverged| diverged.reported)
+            {
+                info!(%node_id, "Peer state is back in sync");
+                AuditEvent::log(
+                    "STATE_CONVERGED".to_string(),
+                    "Peer state is back in sync with ours".to_string(),
+                    json!({ "node_id": node_id.to_string() }),
+                )
+                .await?;
+            }
+            return Ok(());
+        };
+
+        let now = Instant::now();
+        let diverged = self.diverged.entry(node_id).or_insert(Diverged {
+            since: now,
+            last_sync: None,
+            reported: false,
+        });
+        if now.duration_since(diverged.since) < DIVERGENCE_GRACE {
+            return Ok(());
+        }
+
+        if !diverged.reported {
+            diverged.reported = true;
+            warn!(%node_id, parts = ?divergence.parts(), ours = ?self.digest, ?theirs, "Peer state differs from ours");
+            AuditEvent::log(
+                "STATE_DIVERGED".to_string(),
+                "Peer state differs from ours".to_string(),
+                json!({
+                    "node_id": node_id.to_string(),
+                    "parts": divergence.parts(),
+                    "policy_version": theirs.policy_version,
+                    "peer_count": theirs.peer_count,
+                }),
+            )
+            .await?;
+        }
+
+        if diverged
+            .last_sync
+            .is_some_and(|last_sync| now.duration_since(last_sync) < SYNC_COOLDOWN)
+        {
+            return Ok(());
+        }
+        diverged.last_sync = Some(now);
+
+        // The peer notices the divergence too and fetches what it is missing from us
+        if capabilities.contains(Capabilities::SYNC) {
+            return reconcile(node_id);
+        }
+        push(node_id, divergence).await
+    }
+}
+
+/// Ask the iroh actor to fetch what `node_id` holds that we missed
+fn reconcile(node_id: NodeId) -> Result<()> {
+    let Some(iroh) = ractor::registry::where_is("iroh".to_string()) else {
+        debug!(%node_id, "Network is not running, not reconciling");
+        return Ok(());
+    };
+    iroh.send_message(IrohMessage::Reconcile(node_id))?;
+    Ok(())
+}
+
+/// Reconcile with an online peer picked at random, so every pair of peers meets eventually
+async fn reconcile_with_random_peer() -> Result<()> {
+    let peers: Vec<NodeId> = Peer::list()
+        .await?
+        .into_iter()
+        .filter(|peer| {
+            peer.admission == PeerAdmission::Approved
+                && peer.liveness == Some(Liveness::Online)
+                && peer
+                    .capabilities
+                    .is_some_and(|capabilities| capabilities.contains(Capabilities::SYNC))
+        })
+        .map(|peer| peer.node_id)
+        .collect();
+
+    let Some(node_id) = peers.choose(&mut rand::thread_rng()).copied() else {
+        trace!("No online peer to reconcile with");
+        return Ok(());
+    };
+    debug!(%node_id, "Reconciling with a random peer");
+    reconcile(node_id)
+}
+
+/// Send the records a diverged peer that can not fetch them itself may have missed again
+///
+/// Gossip has no point to point delivery, so the records go to everyone, peers that already
+/// have them ignore them.
+async fn push(node_id: NodeId, divergence: Divergence) -> Result<()> {
+    if divergence.policy == Ordering::Greater
+        && let Some(policy) = PolicyRecord::current().await?
+    {
+        debug!(%node_id, version = policy.policy.version, "Resending our policy");
+        gossip_sender::send_or_queue(GossipMessage::Policy {
+            policy: policy.bytes,
+        })
+        .await?;
+    }
+
+    // A peer that missed a revocation keeps counting the revoked node
+    if divergence.peers {
+        for revocation in RevocationRecord::list().await? {
+            gossip_sender::send_or_queue(GossipMessage::Revocation {
+                revocation: revocation.signed,
+            })
+            .await?;
+        }
+    }
+
+    Ok(())
+}
@@ -1,58 +1,450 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
 use anyhow::Result;
-use ractor::{Actor, ActorProcessingErr, ActorRef};
-use tracing::info;
+use chrono::Utc;
+use ractor::{Actor, ActorCell, ActorProcessingErr, ActorRef, SupervisionEvent};
+use serde_json::json;
+use tracing::{error, info, warn};
+
+use crate::actors::gossip::{GossipMessage, gossip_sender, heartbeat::HeartbeatMessage};
+use crate::actors::liveness::LivenessMessage;
+use crate::config::{HeartbeatConfig, NetworkConfig, PolicyConfig, SystemdConfig};
+use crate::db::{AuditEvent, Peer};
+use crate::network::backoff::Backoff;
+use crate::network::metadata::NodeMetadata;
+
+/// How long shutdown waits for our leave announcement to go out
+const LEAVE_TIMEOUT: Duration = Duration::from_secs(2);
 
-use crate::db::Peer;
+/// How long shutdown waits for the children to stop
+const CHILD_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
+/// Up to this part of each restart delay is randomly cut off
+const RESTART_JITTER: f64 = 0.2;
+
+/// Settings the server runs with, resolved from the command line and the config file
 #[derive(Debug, Clone)]
-pub struct AppConfig {}
+pub struct AppConfig {
+    /// What this node publishes about itself
+    pub metadata: NodeMetadata,
+    /// Shell command run whenever a peer goes online, suspect or offline
+    pub notify_command: Option<String>,
+    pub network: NetworkConfig,
+    /// How often we tell our peers we are alive
+    pub heartbeat: HeartbeatConfig,
+    /// Where to keep a copy of our current ticket
+    pub ticket_file: Option<PathBuf>,
+    /// Invite token we joined with, redeemed whenever we gain a neighbor
+    pub invite: Option<String>,
+    /// Who may publish the first policy
+    pub policy: PolicyConfig,
+    pub systemd: SystemdConfig,
+}
+
+/// The actors the supervisor runs, in the order they are started
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Child {
+    Iroh,
+    Introducer,
+    Liveness,
+    Clock,
+    Membership,
+    Prober,
+    Reconciler,
+    SystemdSecrets,
+}
+
+/// How a child is restarted after it fails
+#[derive(Debug, Clone, Copy)]
+struct RestartPolicy {
+    /// Most restarts allowed within `window`, one more failure and the process exits
+    max_restarts: usize,
+    window: Duration,
+    /// Delay before the first restart, doubled for each restart within the window
+    initial_delay: Duration,
+    max_delay: Duration,
+}
+
+impl Child {
+    const ALL: [Child; 8] = [
+        Child::Iroh,
+        Child::Introducer,
+        Child::Liveness,
+        Child::Clock,
+        Child::Membership,
+        Child::Prober,
+        Child::Reconciler,
+        Child::SystemdSecrets,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Child::Iroh => "iroh",
+            Child::Introducer => "introducer",
+            Child::Liveness => "liveness",
+            Child::Clock => "clock",
+            Child::Membership => "membership",
+            Child::Prober => "prober",
+            Child::Reconciler => "reconciler",
+            Child::SystemdSecrets => "systemd_secrets",
+        }
+    }
+
+    fn policy(self) -> RestartPolicy {
+        match self {
+            // Restarting the network is expensive and noisy for our peers, so back off further
+            Child::Iroh => RestartPolicy {
+                max_restarts: 5,
+                window: Duration::from_secs(10 * 60),
+                initial_delay: Duration::from_secs(2),
+                max_delay: Duration::from_secs(60),
+            },
+            _ => RestartPolicy {
+                max_restarts: 10,
+                window: Duration::from_secs(10 * 60),
+                initial_delay: Duration::from_secs(1),
+                max_delay: Duration::from_secs(30),
+            },
+        }
+    }
+
+    /// Whether the child subscribes to the gossip receiver, which lives under the iroh actor
+    fn needs_gossip(self) -> bool {
+        !matches!(self, Child::Iroh | Child::SystemdSecrets)
+    }
+
+    /// Children that are stopped when this one fails, and started again along with it
+    fn dependents(self) -> Vec<Child> {
+        match self {
+            Child::Iroh => Child::ALL
+                .into_iter()
+                .filter(|child| child.needs_gossip())
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Restarts {
+    /// When the child was restarted within the policy window
+    recent: VecDeque<Instant>,
+    backoff: Backoff,
+}
+
+impl Restarts {
+    fn new(policy: RestartPolicy) -> Self {
+        Self {
+            recent: VecDeque::new(),
+            backoff: Backoff::new(policy.initial_delay, policy.max_delay, RESTART_JITTER),
+        }
+    }
+}
 
 pub struct SupervisorActor;
 
 #[derive(Debug)]
-pub enum SupervisorMessage {}
+pub enum SupervisorMessage {
+    /// Start a child that failed again, along with its dependents
+    Restart(Child),
+    /// Heartbeat at a new interval, also once the iroh actor has been restarted
+    SetHeartbeat(HeartbeatConfig),
+}
+
+#[derive(Debug)]
+pub struct SupervisorState {
+    config: AppConfig,
+    /// The children that are currently running
+    children: HashMap<Child, ActorCell>,
+    restarts: HashMap<Child, Restarts>,
+}
 
 impl Actor for SupervisorActor {
     type Msg = SupervisorMessage;
-    type State = ();
+    type State = SupervisorState;
     type Arguments = AppConfig;
 
     async fn pre_start(
         &self,
         myself: ActorRef<Self::Msg>,
-        _config: Self::Arguments,
+        config: Self::Arguments,
     ) -> Result<Self::State, ActorProcessingErr> {
         info!("Starting SupervisorActor with linked children");
 
-        // Get all the existing peers
-        let peers = Peer::list().await?;
+        let mut state = SupervisorState {
+            config,
+            children: HashMap::new(),
+            restarts: HashMap::new(),
+        };
+        for child in Child::ALL {
+            state.spawn(&myself, child).await?;
+        }
 
-        let (_iroh_actor, _iroh_handle) = Actor::spawn_linked(
-            Some("iroh".into()),
-            super::gossip::iroh::IrohActor,
-            (peers,),
-            myself.clone().into(),
-        )
-        .await?;
+        info!("All actors started successfully");
+        Ok(state)
+    }
 
-        // Start the introducer actor
-        let (_introducer_actor, _introducer_handle) = Actor::spawn_linked(
-            Some("introducer".into()),
-            super::introducer::IntroducerActor,
-            (),
-            myself.clone().into(),
-        )
-        .await?;
+    async fn handle(
+        &self,
+        myself: ActorRef<Self::Msg>,
+        message: Self::Msg,
+        state: &mut Self::State,
+    ) -> Result<(), ActorProcessingErr> {
+        match message {
+            SupervisorMessage::Restart(child) => state.restart(&myself, child).await?,
+            SupervisorMessage::SetHeartbeat(heartbeat_config) => {
+                state.config.heartbeat = heartbeat_config;
+                if let Some(heartbeat) = ractor::registry::where_is("heartbeat".to_string()) {
+                    heartbeat.send_message(HeartbeatMessage::Configure(heartbeat_config))?;
+                }
+                if let Some(liveness) = ractor::registry::where_is("liveness".to_string()) {
+                    liveness.send_message(LivenessMessage::Configure(heartbeat_config))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle_supervisor_evt(
+        &self,
+        myself: ActorRef<Self::Msg>,
+        event: SupervisionEvent,
+        state: &mut Self::State,
+    ) -> Result<(), ActorProcessingErr> {
+        let (cell, reason) = match event {
+            SupervisionEvent::ActorFailed(cell, err) => (cell, format!("{err:#}")),
+            SupervisionEvent::ActorTerminated(cell, _, reason) => (
+                cell,
+                reason.unwrap_or_else(|| "stopped unexpectedly".to_string()),
+            ),
+            _ => return Ok(()),
+        };
+
+        // Children we stopped on purpose, or replaced already, are not in the map
+        let Some(child) = state.child_of(&cell) else {
+            return Ok(());
+        };
+        state.children.remove(&child);
+        state.failed(&myself, child, reason).await?;
+
+        Ok(())
+    }
+
+    async fn post_stop(
+        &self,
+        myself: ActorRef<Self::Msg>,
+        _state: &mut Self::State,
+    ) -> Result<(), ActorProcessingErr> {
+        // Tell our peers we are going away on purpose while the network is still up
+        let leaving = GossipMessage::Leaving { time: Utc::now() };
+        match gossip_sender::send_and_wait(leaving, LEAVE_TIMEOUT).await {
+            Ok(true) => info!("Announced that we are leaving"),
+            Ok(false) => warn!("Could not announce that we are leaving"),
+            Err(err) => warn!(?err, "Failed to announce that we are leaving"),
+        }
+
+        myself
+            .stop_children_and_wait(None, Some(CHILD_SHUTDOWN_TIMEOUT))
+            .await;
+
+        Ok(())
+    }
+}
+
+impl SupervisorState {
+    fn child_of(&self, cell: &ActorCell) -> Option<Child> {
+        self.children
+            .iter()
+            .find(|(_, running)| running.get_id() == cell.get_id())
+            .map(|(child, _)| *child)
+    }
 
-        let (_systemd_secrets_actor, _systemd_secrets_handle) = Actor::spawn_linked(
-            Some("systemd_secrets".into()),
-            super::systemd_secrets::SystemdSecretsActor,
-            (),
-            myself.clone().into(),
+    async fn spawn(&mut self, myself: &ActorRef<SupervisorMessage>, child: Child) -> Result<()> {
+        let cell = match child {
+            Child::Iroh => {
+                spawn_child(
+                    myself,
+                    child,
+                    super::gossip::iroh::IrohActor,
+                    (Peer::list().await?, self.config.clone()),
+                )
+                .await?
+            }
+            Child::Introducer => {
+                spawn_child(
+                    myself,
+                    child,
+                    super::introducer::IntroducerActor,
+                    (self.config.metadata.clone(),),
+                )
+                .await?
+            }
+            Child::Liveness => {
+                spawn_child(
+                    myself,
+                    child,
+                    super::liveness::LivenessActor,
+                    (self.config.notify_command.clone(), self.config.heartbeat),
+                )
+                .await?
+            }
+            Child::Clock => spawn_child(myself, child, super::clock::ClockActor, ()).await?,
+            Child::Membership => {
+                spawn_child(
+                    myself,
+                    child,
+                    super::membership::MembershipActor,
+                    (
+                        self.config.network.topic,
+                        self.config.invite.clone(),
+                        self.config.policy.clone(),
+                    ),
+                )
+                .await?
+            }
+            Child::Prober => spawn_child(myself, child, super::prober::ProberActor, ()).await?,
+            Child::Reconciler => {
+                spawn_child(myself, child, super::reconciler::ReconcilerActor, ()).await?
+            }
+            Child::SystemdSecrets => {
+                spawn_child(
+                    myself,
+                    child,
+                    super::systemd_secrets::SystemdSecretsActor,
+                    self.config.systemd.clone(),
+                )
+                .await?
+            }
+        };
+        self.children.insert(child, cell);
+
+        Ok(())
+    }
+
+    /// Schedule a restart of a child that stopped, or give up if it keeps failing
+    async fn failed(
+        &mut self,
+        myself: &ActorRef<SupervisorMessage>,
+        child: Child,
+        reason: String,
+    ) -> Result<()> {
+        error!(child = child.name(), %reason, "Actor stopped unexpectedly");
+
+        // These can not run without the child, they are started again along with it
+        for dependent in child.dependents() {
+            if let Some(cell) = self.children.remove(&dependent)
+                && let Err(err) = cell.stop_and_wait(None, Some(CHILD_SHUTDOWN_TIMEOUT)).await
+            {
+                warn!(
+                    ?err,
+                    child = dependent.name(),
+                    "Failed to stop dependent actor"
+                );
+            }
+        }
+
+        let policy = child.policy();
+        let restarts = self
+            .restarts
+            .entry(child)
+            .or_insert_with(|| Restarts::new(policy));
+        let now = Instant::now();
+        restarts
+            .recent
+            .retain(|restarted_at| now.duration_since(*restarted_at) < policy.window);
+        if restarts.recent.is_empty() {
+            restarts.backoff.reset();
+        }
+
+        if restarts.recent.len() >= policy.max_restarts {
+            error!(
+                child = child.name(),
+                restarts = restarts.recent.len(),
+                window = ?policy.window,
+                "Actor keeps failing, shutting down"
+            );
+            AuditEvent::log(
+                "ACTOR_RESTART_LIMIT".to_string(),
+                format!("The {} actor keeps failing, shutting down", child.name()),
+                json!({
+                    "child": child.name(),
+                    "reason": reason,
+                    "restarts": restarts.recent.len(),
+                    "window_secs": policy.window.as_secs(),
+                }),
+            )
+            .await?;
+
+            myself.stop(Some(format!(
+                "{} failed more than {} times within {:?}",
+                child.name(),
+                policy.max_restarts,
+                policy.window
+            )));
+            return Ok(());
+        }
+
+        restarts.recent.push_back(now);
+        let attempt = restarts.recent.len();
+        let delay = restarts.backoff.next_delay();
+
+        AuditEvent::log(
+            "ACTOR_RESTART".to_string(),
+            format!("Restarting the {} actor", child.name()),
+            json!({
+                "child": child.name(),
+                "reason": reason,
+                "attempt": attempt,
+                "delay_ms": delay.as_millis() as u64,
+            }),
         )
         .await?;
+        warn!(child = child.name(), attempt, ?delay, "Restarting actor");
+        myself.send_after(delay, move || SupervisorMessage::Restart(child));
 
-        info!("All actors started successfully");
         Ok(())
     }
+
+    async fn restart(&mut self, myself: &ActorRef<SupervisorMessage>, child: Child) -> Result<()> {
+        if self.children.contains_key(&child) {
+            return Ok(());
+        }
+        // It is started along with the iroh actor once that is back
+        if child.needs_gossip() && !self.children.contains_key(&Child::Iroh) {
+            return Ok(());
+        }
+
+        for child in std::iter::once(child).chain(child.dependents()) {
+            if self.children.contains_key(&child) {
+                continue;
+            }
+
+            match self.spawn(myself, child).await {
+                Ok(()) => info!(child = child.name(), "Restarted actor"),
+                Err(err) => {
+                    self.failed(myself, child, format!("{err:#}")).await?;
+                    // Dependents are started once it is up
+                    if !child.dependents().is_empty() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+async fn spawn_child<A: Actor>(
+    myself: &ActorRef<SupervisorMessage>,
+    child: Child,
+    actor: A,
+    args: A::Arguments,
+) -> Result<ActorCell> {
+    let (actor_ref, _handle) =
+        Actor::spawn_linked(Some(child.name().into()), actor, args, myself.get_cell()).await?;
+    Ok(actor_ref.get_cell())
 }
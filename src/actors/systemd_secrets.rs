@@ -5,8 +5,9 @@ use ractor::{Actor, ActorProcessingErr, ActorRef};
 use serde_json::json;
 use thiserror::Error;
 use tokio::{io::AsyncWriteExt, process::Command};
-use tracing::{debug, error, trace};
+use tracing::{debug, trace};
 
+use crate::config::SystemdConfig;
 use crate::db::AuditEvent;
 
 pub struct SystemdSecretsActor;
@@ -14,8 +15,9 @@ pub struct SystemdSecretsActor;
 #[derive(Debug, Clone)]
 #[allow(dead_code)]
 pub enum SystemdSecretsActorMessage {
-    SetSecret(SystemdSecret, Vec<u8>),
-    DeleteSecret(SystemdSecret),
+    /// Write the named credential into the credstore
+    SetSecret(String, Vec<u8>),
+    DeleteSecret(String),
 }
 
 #[derive(Debug, Clone)]
@@ -44,20 +46,20 @@ pub enum SystemdSecretsError {
 
 impl Actor for SystemdSecretsActor {
     type Msg = SystemdSecretsActorMessage;
-    type State = ();
-    type Arguments = ();
+    type State = SystemdConfig;
+    type Arguments = SystemdConfig;
 
     async fn handle(
         &self,
         _myself: ActorRef<Self::Msg>,
         message: Self::Msg,
-        _state: &mut Self::State,
+        state: &mut Self::State,
     ) -> Result<(), ActorProcessingErr> {
         trace!(?message, "Handling Systemd Secrets message");
 
         match message {
-            SystemdSecretsActorMessage::SetSecret(systemd_secret, data) => {
-                systemd_secret.write(data).await?;
+            SystemdSecretsActorMessage::SetSecret(name, data) => {
+                SystemdSecret::new(state, &name).write(data).await?;
             }
             SystemdSecretsActorMessage::DeleteSecret(_systemd_secret) => todo!(),
         }
@@ -68,13 +70,20 @@ impl Actor for SystemdSecretsActor {
     async fn pre_start(
         &self,
         _myself: ActorRef<Self::Msg>,
-        _args: Self::Arguments,
-    ) -> Result<(), ractor::ActorProcessingErr> {
-        Ok(())
+        config: Self::Arguments,
+    ) -> Result<Self::State, ractor::ActorProcessingErr> {
+        Ok(config)
     }
 }
 
 impl SystemdSecret {
+    fn new(config: &SystemdConfig, name: &str) -> Self {
+        Self {
+            path: config.credstore_path.join(name),
+            user: config.user_scope,
+        }
+    }
+
     async fn write(self, content: Vec<u8>) -> Result<(), SystemdSecretsError> {
         trace!(path = ?self.path, "Writing secret");
 
@@ -1,19 +1,63 @@
 use clap::{Parser, Subcommand};
+use iroh::NodeId;
 use iroh_base::ticket::NodeTicket;
 use std::path::PathBuf;
-use tokio::sync::OnceCell;
+use std::time::Duration;
+use uuid::Uuid;
 
 #[derive(Parser, Debug)]
 #[command(name = "room_101")]
 #[command(about = "A peer-to-peer networking application")]
 pub struct Args {
-    /// Path to database location
-    pub db_path: String,
+    /// Path to database location, overrides `db_path` in the config file
+    pub db_path: Option<String>,
+
+    /// TOML file to read settings from, flags given here take precedence over it
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Name of the network to join, at most 32 bytes (default: ROOM_101)
+    #[arg(long, global = true)]
+    pub topic: Option<String>,
+
+    /// Do not publish or look up our addresses through the n0 discovery service
+    #[arg(long, global = true)]
+    pub no_n0_discovery: bool,
+
+    /// Do not use relay servers, peers must be reachable directly
+    #[arg(long, global = true)]
+    pub no_relay: bool,
 
     #[command(subcommand)]
     pub command: Commands,
 }
 
+/// Parse a duration such as `90s`, `15m`, `1h` or `7d`
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| format!("invalid duration '{value}'"))?;
+
+    let seconds = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("unknown duration unit '{unit}', use s, m, h or d")),
+    };
+
+    let seconds = amount
+        .checked_mul(seconds)
+        .ok_or_else(|| format!("duration '{value}' is too large"))?;
+
+    Ok(Duration::from_secs(seconds))
+}
+
 #[derive(Subcommand, Debug)]
 pub enum Commands {
     /// Run the P2P networking server
@@ -26,6 +70,14 @@ pub enum Commands {
     Init(InitArgs),
     /// Manage audit events
     Audit(AuditArgs),
+    /// Manage invites for new nodes
+    Invite(InviteArgs),
+    /// Manage the admin policy
+    Policy(PolicyArgs),
+    /// Review and sign proposals that need several admins to approve them
+    Approvals(ApprovalsArgs),
+    /// Manage the keys of this node
+    Identity(IdentityArgs),
 }
 
 #[derive(Parser, Debug)]
@@ -41,13 +93,44 @@ pub struct ServerArgs {
     pub bootstrap: Vec<String>,
 
     /// Directory to store systemd credentials (default: /var/lib/credstore)
-    #[arg(long, default_value = "/var/lib/credstore")]
-    pub systemd_secrets_path: String,
+    #[arg(long)]
+    pub systemd_secrets_path: Option<PathBuf>,
 
     /// Use user-scope systemd credentials instead of system-scope (default: system-scope)
     #[arg(long)]
     pub systemd_user_scope: bool,
 
+    /// Invite token to join the network with, as produced by `invite create`
+    #[arg(long)]
+    pub invite: Option<String>,
+
+    /// Friendly name other nodes show for this node
+    #[arg(long)]
+    pub display_name: Option<String>,
+
+    /// Labels this node declares for itself (comma separated), shown to other nodes
+    #[arg(long = "label", value_delimiter = ',')]
+    pub labels: Vec<String>,
+
+    /// Shell command to run when a peer goes online, suspect or offline. It gets
+    /// ROOM_101_NODE_ID, ROOM_101_STATE and ROOM_101_PREVIOUS_STATE in its environment
+    #[arg(long)]
+    pub notify_command: Option<String>,
+
+    /// How often to send a heartbeat, such as 1s or 30s (default: 1s). Every node of the network
+    /// should use the same setting, it decides how long peers wait before suspecting a node
+    #[arg(long, value_parser = parse_duration)]
+    pub heartbeat_interval: Option<Duration>,
+
+    /// Send heartbeats less often as the network grows, never more often than the interval
+    #[arg(long)]
+    pub adaptive_heartbeat: bool,
+
+    /// File to read the passphrase of a sealed identity from, otherwise the room_101_passphrase
+    /// systemd credential or ROOM_101_PASSPHRASE is used
+    #[arg(long)]
+    pub passphrase_file: Option<PathBuf>,
+
     #[command(flatten)]
     pub init: InitArgs,
 }
@@ -67,6 +150,40 @@ pub enum PeerCommands {
         /// The node ticket to add as a peer
         ticket: NodeTicket,
     },
+    /// Approve a pending peer so the network accepts its messages
+    Approve {
+        /// The node ID of the peer to approve
+        node_id: NodeId,
+    },
+    /// Replace the labels assigned to a peer
+    Label {
+        /// The node ID of the peer to label
+        node_id: NodeId,
+
+        /// Labels to assign (comma separated), leave empty to clear them
+        #[arg(value_delimiter = ',')]
+        labels: Vec<String>,
+    },
+    /// Permanently ban a peer from the network
+    Revoke {
+        /// The node ID of the peer to revoke
+        node_id: NodeId,
+
+        /// Why the peer is being revoked, recorded in the audit log
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// List peers that have been revoked
+    Revoked,
+    /// Measure the round trip time to a peer, through the server while it runs
+    Ping {
+        /// The node ID of the peer to ping
+        node_id: NodeId,
+
+        /// How long to wait for an answer, such as 10s or 1m
+        #[arg(long, default_value = "10s", value_parser = parse_duration)]
+        timeout: Duration,
+    },
 }
 
 #[derive(Parser, Debug)]
@@ -81,8 +198,163 @@ pub enum AuditCommands {
     List,
 }
 
-static ARGS: OnceCell<Args> = OnceCell::const_new();
+#[derive(Parser, Debug)]
+pub struct InviteArgs {
+    #[command(subcommand)]
+    pub command: InviteCommands,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum InviteCommands {
+    /// Create a single use invite token for a new node
+    Create {
+        /// Labels the new node is given when it joins (comma separated)
+        #[arg(long, value_delimiter = ',')]
+        labels: Vec<String>,
+
+        /// How long the invite stays valid, such as 30m, 1h or 7d
+        #[arg(long, default_value = "1h", value_parser = parse_duration)]
+        expires: Duration,
+    },
+    /// List invites we have issued or seen redeemed
+    List,
+}
+
+#[derive(Parser, Debug)]
+pub struct PolicyArgs {
+    #[command(subcommand)]
+    pub command: PolicyCommands,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum PolicyCommands {
+    /// Show the policy currently in force
+    Show,
+    /// Publish a new policy naming the admins of the network. Other nodes only accept the first
+    /// policy from a node listed in their server.bootstrap_admins
+    Set {
+        /// Node ID of an admin, repeat for each admin
+        #[arg(long = "admin", required = true)]
+        admins: Vec<NodeId>,
+
+        /// Number of distinct admins that must approve membership and policy changes
+        #[arg(long, default_value_t = 1)]
+        threshold: u32,
+    },
+}
+
+#[derive(Parser, Debug)]
+pub struct ApprovalsArgs {
+    #[command(subcommand)]
+    pub command: ApprovalsCommands,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ApprovalsCommands {
+    /// List proposals and how many approvals they have
+    List,
+    /// Approve a pending proposal
+    Sign {
+        /// The ID of the proposal to approve
+        proposal_id: Uuid,
+    },
+}
+
+#[derive(Parser, Debug)]
+pub struct IdentityArgs {
+    #[command(subcommand)]
+    pub command: IdentityCommands,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum IdentityCommands {
+    /// Generate new keys and hand our membership over to them, only while the server is stopped
+    Rotate {
+        /// Keep the current node key and only rotate the age key
+        #[arg(long, conflicts_with = "keep_age_key")]
+        keep_node_key: bool,
+
+        /// Keep the current age key and only rotate the node key
+        #[arg(long)]
+        keep_age_key: bool,
+    },
+    /// Write our keys to a passphrase protected backup file
+    Export {
+        /// Where to write the backup
+        path: PathBuf,
+
+        /// File to read the passphrase from, otherwise ROOM_101_PASSPHRASE is used
+        #[arg(long)]
+        passphrase_file: Option<PathBuf>,
+
+        /// Overwrite the backup file if it already exists
+        #[arg(long)]
+        force: bool,
+    },
+    /// Restore our keys from a backup made with `identity export`
+    Import {
+        /// The backup file to restore
+        path: PathBuf,
+
+        /// File to read the passphrase from, otherwise ROOM_101_PASSPHRASE is used
+        #[arg(long)]
+        passphrase_file: Option<PathBuf>,
+
+        /// Replace the identity of this node if it already has one
+        #[arg(long)]
+        force: bool,
+    },
+    /// Encrypt our keys at rest, the server unseals them when it starts
+    Seal {
+        /// File to read the passphrase from, otherwise ROOM_101_PASSPHRASE is used
+        #[arg(long, conflicts_with = "systemd_creds")]
+        passphrase_file: Option<PathBuf>,
+
+        /// Bind the keys to this host with systemd-creds instead of a passphrase
+        #[arg(long)]
+        systemd_creds: bool,
+
+        /// Use user-scope systemd credentials instead of system-scope
+        #[arg(long, requires = "systemd_creds")]
+        user_scope: bool,
+    },
+    /// Store our keys unencrypted again
+    Unseal {
+        /// File to read the passphrase from, otherwise ROOM_101_PASSPHRASE is used
+        #[arg(long)]
+        passphrase_file: Option<PathBuf>,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("15m"), Ok(Duration::from_secs(15 * 60)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(60 * 60)));
+        assert_eq!(
+            parse_duration("7d"),
+            Ok(Duration::from_secs(7 * 24 * 60 * 60))
+        );
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("1w").is_err());
+        assert!(parse_duration("").is_err());
+    }
 
-pub async fn args() -> &'static Args {
-    ARGS.get_or_init(|| async { Args::parse() }).await
+    #[test]
+    fn test_parse_duration_overflow() {
+        assert!(parse_duration("99999999999999999999m").is_err());
+        assert_eq!(
+            parse_duration(&format!("{}d", u64::MAX / 2)),
+            Err(format!("duration '{}d' is too large", u64::MAX / 2))
+        );
+        assert_eq!(
+            parse_duration(&format!("{}s", u64::MAX)),
+            Ok(Duration::from_secs(u64::MAX))
+        );
+    }
 }
@@ -0,0 +1,149 @@
+use anyhow::{Context, Result, bail, ensure};
+use chrono::Utc;
+use chrono_humanize::HumanTime;
+use serde_json::json;
+
+use crate::actors::gossip::GossipMessage;
+use crate::actors::gossip::signing::required_approvals;
+use crate::actors::membership::apply_if_approved;
+use crate::args::{ApprovalsArgs, ApprovalsCommands};
+use crate::db::{
+    ApprovalRecord, AuditEvent, Identity, OutboxMessage, PolicyRecord, ProposalRecord,
+    ProposalStatus,
+};
+use crate::network::proposal::{
+    PROPOSAL_LIFETIME, Proposal, ProposalAction, SignedApproval, SignedProposal,
+};
+
+#[allow(clippy::print_stdout)] // CLI output is appropriate here
+pub async fn run(approvals_args: &ApprovalsArgs) -> Result<()> {
+    match &approvals_args.command {
+        ApprovalsCommands::List => {
+            let proposals = ProposalRecord::list()
+                .await
+                .context("Failed to retrieve proposals from database")?;
+
+            if proposals.is_empty() {
+                println!("No proposals found in database");
+                return Ok(());
+            }
+
+            let policy = PolicyRecord::current().await?;
+            let required = required_approvals(policy.as_ref());
+
+            println!("Found {} proposal(s):", proposals.len());
+            for record in proposals {
+                println!("  Proposal ID: {}", record.proposal_id);
+                println!("    Action: {}", record.summary);
+                println!("    Proposed by: {}", record.proposed_by);
+                println!("    Status: {}", record.status);
+                if record.status == ProposalStatus::Pending {
+                    let proposal = record.signed_proposal()?;
+                    let approvals = ApprovalRecord::for_proposal(&proposal).await?;
+                    let approvers =
+                        PolicyRecord::distinct_admins(proposal.signers(&approvals)).await?;
+                    println!("    Approvals: {}/{}", approvers.len(), required);
+                    for approver in approvers {
+                        println!("      {approver}");
+                    }
+                    println!(
+                        "    Expires: {} ({})",
+                        HumanTime::from(record.expires_at),
+                        record.expires_at
+                    );
+                }
+                println!();
+            }
+            Ok(())
+        }
+        ApprovalsCommands::Sign { proposal_id } => {
+            let identity = Identity::get().await.context("Failed to get identity")?;
+            ensure!(
+                PolicyRecord::is_admin(identity.id()).await?,
+                "Only admins can approve proposals"
+            );
+
+            let Some(record) = ProposalRecord::get(*proposal_id).await? else {
+                bail!("No proposal with ID {proposal_id}");
+            };
+            ensure!(
+                record.status == ProposalStatus::Pending,
+                "Proposal is already {}",
+                record.status
+            );
+            let proposal = record.signed_proposal()?;
+            ensure!(
+                !proposal.proposal.is_expired(),
+                "Proposal expired at {}",
+                proposal.proposal.expires_at
+            );
+            ensure!(
+                proposal.proposed_by != identity.id(),
+                "Proposals are already approved by the admin who proposed them"
+            );
+
+            let approval = SignedApproval::sign(&identity.secret_key, &proposal)?;
+            if !ApprovalRecord::store(&approval).await? {
+                bail!("You have already approved this proposal");
+            }
+
+            OutboxMessage::queue(&GossipMessage::Approval {
+                proposal: proposal.bytes.clone(),
+                approval: approval.bytes.clone(),
+            })
+            .await?;
+
+            AuditEvent::log(
+                "PROPOSAL_APPROVED".to_string(),
+                "Proposal approved by operator".to_string(),
+                json!({
+                    "proposal_id": proposal_id.to_string(),
+                    "approver": identity.id().to_string(),
+                }),
+            )
+            .await?;
+
+            apply_if_approved(&proposal).await?;
+
+            let status = ProposalRecord::get(*proposal_id)
+                .await?
+                .map(|record| record.status)
+                .unwrap_or_default();
+            println!("Approved proposal {proposal_id}, it is now {status}");
+            Ok(())
+        }
+    }
+}
+
+/// Propose a change that needs several admins to approve it, queueing it for the network
+#[allow(clippy::print_stdout)] // CLI output is appropriate here
+pub async fn propose(identity: &Identity, action: ProposalAction) -> Result<SignedProposal> {
+    let proposal = SignedProposal::sign(
+        &identity.secret_key,
+        Proposal::new(action, Utc::now() + PROPOSAL_LIFETIME),
+    )?;
+    ProposalRecord::store(&proposal).await?;
+
+    OutboxMessage::queue(&GossipMessage::Proposal {
+        proposal: proposal.bytes.clone(),
+    })
+    .await?;
+
+    AuditEvent::log(
+        "PROPOSAL_CREATED".to_string(),
+        "Change proposed by operator".to_string(),
+        json!({
+            "proposal_id": proposal.proposal.id.to_string(),
+            "proposed_by": identity.id().to_string(),
+            "action": proposal.proposal.action.to_string(),
+            "expires_at": proposal.proposal.expires_at,
+        }),
+    )
+    .await?;
+
+    println!(
+        "Proposed to {}, other admins can approve it with `approvals sign {}`",
+        proposal.proposal.action, proposal.proposal.id
+    );
+    Ok(proposal)
+}
@@ -0,0 +1,246 @@
+use std::path::Path;
+
+use age::secrecy::SecretString;
+use age::x25519::Identity as AgeIdentity;
+use anyhow::{Context, Result, bail, ensure};
+use chrono::Utc;
+use iroh::SecretKey;
+use serde_json::json;
+
+use crate::actors::gossip::GossipMessage;
+use crate::args::{IdentityArgs, IdentityCommands};
+use crate::config::Config;
+use crate::db::sealed_identity::{self, PASSPHRASE_ENV};
+use crate::db::{AuditEvent, Identity, KeyHandoverRecord, OutboxMessage, SealedIdentity, Sealer};
+use crate::network::handover::{KeyHandover, SignedHandover};
+use crate::utils::ServerLock;
+
+#[allow(clippy::print_stdout)] // CLI output is appropriate here
+pub async fn run(identity_args: &IdentityArgs, config: &Config) -> Result<()> {
+    match &identity_args.command {
+        IdentityCommands::Rotate {
+            keep_node_key,
+            keep_age_key,
+        } => {
+            // The running server keeps signing with the keys it started with
+            let Some(_lock) = ServerLock::acquire(&config.db_path)? else {
+                bail!(
+                    "Stop the server before rotating keys, it keeps using the old ones until then"
+                );
+            };
+
+            let old = Identity::get().await.context("Failed to get identity")?;
+            let new = Identity {
+                secret_key: if *keep_node_key {
+                    old.secret_key.clone()
+                } else {
+                    SecretKey::generate(rand::rngs::OsRng)
+                },
+                age_key: if *keep_age_key {
+                    old.age_key.clone()
+                } else {
+                    AgeIdentity::generate()
+                },
+            };
+
+            // Signed with the old key, the server signs the message carrying it with the new one
+            let handover = SignedHandover::sign(
+                &old.secret_key,
+                KeyHandover {
+                    old_node_id: old.id(),
+                    new_node_id: new.id(),
+                    age_public_key: new.age_key.to_public().to_string(),
+                    rotated_at: Utc::now(),
+                },
+            )?;
+
+            let new = Identity::replace(new)
+                .await
+                .context("Failed to store new identity")?;
+            KeyHandoverRecord::store(&handover).await?;
+            OutboxMessage::queue(&GossipMessage::KeyHandover {
+                handover: handover.bytes,
+            })
+            .await?;
+
+            AuditEvent::log(
+                "IDENTITY_ROTATED".to_string(),
+                "Rotated identity keys".to_string(),
+                json!({
+                    "old_node_id": old.id().to_string(),
+                    "new_node_id": new.id().to_string(),
+                    "rotated_node_key": !keep_node_key,
+                    "rotated_age_key": !keep_age_key,
+                }),
+            )
+            .await?;
+
+            println!("Rotated identity:");
+            println!("  Old node ID: {}", old.id());
+            println!("  New node ID: {}", new.id());
+            println!("  Age public key: {}", new.age_key.to_public());
+            println!();
+            println!("Start the server to use the new keys, peers are told on connect");
+            Ok(())
+        }
+        IdentityCommands::Export {
+            path,
+            passphrase_file,
+            force,
+        } => {
+            ensure!(
+                *force || !path.exists(),
+                "{} already exists, use --force to overwrite it",
+                path.display()
+            );
+
+            let identity = Identity::get().await.context("Failed to get identity")?;
+            let passphrase = read_passphrase(passphrase_file.as_deref()).await?;
+            let exported = identity.export(passphrase)?;
+            write_private_file(path, &exported).await?;
+
+            AuditEvent::log(
+                "IDENTITY_EXPORTED".to_string(),
+                "Exported identity backup".to_string(),
+                json!({
+                    "node_id": identity.id().to_string(),
+                    "path": path.display().to_string(),
+                }),
+            )
+            .await?;
+
+            println!("Exported identity {} to {}", identity.id(), path.display());
+            Ok(())
+        }
+        IdentityCommands::Import {
+            path,
+            passphrase_file,
+            force,
+        } => {
+            let existing = Identity::find().await?;
+            if let Some(existing) = &existing
+                && !force
+            {
+                bail!(
+                    "This node already has the identity {}, use --force to replace it",
+                    existing.id()
+                );
+            }
+
+            let encrypted = tokio::fs::read(path)
+                .await
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            let passphrase = read_passphrase(passphrase_file.as_deref()).await?;
+            let identity = Identity::replace(Identity::import(&encrypted, passphrase)?)
+                .await
+                .context("Failed to store imported identity")?;
+
+            AuditEvent::log(
+                "IDENTITY_IMPORTED".to_string(),
+                "Imported identity backup".to_string(),
+                json!({
+                    "node_id": identity.id().to_string(),
+                    "replaced_node_id": existing.map(|existing| existing.id().to_string()),
+                    "path": path.display().to_string(),
+                }),
+            )
+            .await?;
+
+            println!(
+                "Imported identity {} from {}",
+                identity.id(),
+                path.display()
+            );
+            Ok(())
+        }
+        IdentityCommands::Seal {
+            passphrase_file,
+            systemd_creds,
+            user_scope,
+        } => {
+            if let Some(sealed) = SealedIdentity::get().await? {
+                bail!(
+                    "Identity is already sealed with {}, unseal it first to change the method",
+                    sealed.method
+                );
+            }
+
+            let identity = Identity::get().await.context("Failed to get identity")?;
+            let sealer = if *systemd_creds {
+                Sealer::SystemdCreds {
+                    user_scope: *user_scope,
+                }
+            } else {
+                Sealer::Passphrase(read_passphrase(passphrase_file.as_deref()).await?)
+            };
+            let sealed = SealedIdentity::seal(&identity, sealer).await?;
+
+            AuditEvent::log(
+                "IDENTITY_SEALED".to_string(),
+                "Sealed identity at rest".to_string(),
+                json!({
+                    "node_id": sealed.node_id.to_string(),
+                    "method": sealed.method.to_string(),
+                    "user_scope": sealed.user_scope,
+                }),
+            )
+            .await?;
+
+            println!("Sealed identity {} with {}", sealed.node_id, sealed.method);
+            Ok(())
+        }
+        IdentityCommands::Unseal { passphrase_file } => {
+            let sealed = SealedIdentity::get()
+                .await?
+                .context("Identity is not sealed")?;
+            let sealer = match passphrase_file {
+                Some(path) => Some(Sealer::Passphrase(read_passphrase(Some(path)).await?)),
+                None => None,
+            };
+            let identity = sealed.unseal(sealer).await?;
+            SealedIdentity::remove(&identity).await?;
+
+            AuditEvent::log(
+                "IDENTITY_UNSEALED".to_string(),
+                "Stored identity unsealed".to_string(),
+                json!({
+                    "node_id": identity.id().to_string(),
+                    "method": sealed.method.to_string(),
+                }),
+            )
+            .await?;
+
+            println!("Unsealed identity {}", identity.id());
+            Ok(())
+        }
+    }
+}
+
+pub async fn read_passphrase(passphrase_file: Option<&Path>) -> Result<SecretString> {
+    match passphrase_file {
+        Some(path) => sealed_identity::parse_passphrase(
+            &tokio::fs::read_to_string(path)
+                .await
+                .with_context(|| format!("Failed to read passphrase from {}", path.display()))?,
+        ),
+        None => sealed_identity::passphrase_from_environment()?
+            .with_context(|| format!("Pass --passphrase-file or set {PASSPHRASE_ENV}")),
+    }
+}
+
+/// Write a file only the current user can read
+async fn write_private_file(path: &Path, contents: &[u8]) -> Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .await
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    file.write_all(contents).await?;
+    file.flush().await?;
+    Ok(())
+}
@@ -1,12 +1,30 @@
 use anyhow::Result;
-use iroh::{Endpoint, Watcher};
+use iroh::Watcher;
 use iroh_base::ticket::NodeTicket;
 use tracing::{error, trace};
 
+use crate::args::InitArgs;
+use crate::config::NetworkConfig;
 use crate::db::Identity;
 
+/// Bind a temporary endpoint to find out our relay and direct addresses
+pub async fn discover_ticket(identity: &Identity, network: &NetworkConfig) -> Result<NodeTicket> {
+    let endpoint = network
+        .endpoint_builder(identity.secret_key.clone())
+        .bind()
+        .await?;
+
+    if network.relay {
+        let _relay = endpoint.home_relay().initialized().await;
+    }
+    let addr = endpoint.node_addr().initialized().await;
+    endpoint.close().await;
+
+    Ok(NodeTicket::new(addr))
+}
+
 #[allow(clippy::print_stdout)] // CLI output is appropriate here
-pub async fn run() -> Result<()> {
+pub async fn run(init_args: &InitArgs, network: &NetworkConfig) -> Result<()> {
     if Identity::get().await.is_ok() {
         println!("Identity already exists");
     } else {
@@ -22,21 +40,11 @@ pub async fn run() -> Result<()> {
     println!();
     println!("Finding best Iroh Relay...");
 
-    let endpoint = Endpoint::builder()
-        .secret_key(identity.clone().secret_key)
-        .discovery_n0()
-        .bind()
-        .await?;
-
-    let _relay = endpoint.home_relay().initialized().await;
-    let addr = endpoint.node_addr().initialized().await;
-    let ticket = NodeTicket::new(addr.clone());
+    let ticket = discover_ticket(&identity, network).await?;
     println!("Iroh Ticket: {ticket}");
 
     // Write ticket to file if specified
-    if let crate::args::Commands::Init(init_args) = &crate::args::args().await.command
-        && let Some(ref ticket_path) = init_args.ticket_file
-    {
+    if let Some(ref ticket_path) = init_args.ticket_file {
         match crate::utils::write_ticket_to_file(&ticket, ticket_path).await {
             Ok(()) => trace!("Successfully wrote ticket to file '{ticket_path:?}'"),
             Err(e) => error!("Failed to write ticket to file '{ticket_path:?}': {e:?}"),
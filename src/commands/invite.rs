@@ -0,0 +1,99 @@
+use anyhow::{Context, Result, ensure};
+use chrono::Utc;
+use chrono_humanize::HumanTime;
+use serde_json::json;
+
+use crate::args::{InviteArgs, InviteCommands};
+use crate::commands::init::discover_ticket;
+use crate::config::NetworkConfig;
+use crate::db::{AuditEvent, Identity, Invite, Peer, PeerAdmission, PolicyRecord};
+use crate::network::invite::{InviteToken, SignedInvite};
+
+/// Maximum number of known peers bundled into an invite as extra bootstrap tickets
+const MAX_BOOTSTRAP_PEERS: usize = 4;
+
+#[allow(clippy::print_stdout)] // CLI output is appropriate here
+pub async fn run(invite_args: &InviteArgs, network: &NetworkConfig) -> Result<()> {
+    match &invite_args.command {
+        InviteCommands::Create { labels, expires } => {
+            let identity = Identity::get().await.context("Failed to get identity")?;
+            ensure!(
+                PolicyRecord::is_admin(identity.id()).await?,
+                "Only admins can create invites"
+            );
+            ensure!(
+                PolicyRecord::required_approvals().await? <= 1,
+                "Invites can not be used while changes need several approvals, use `peers approve` instead"
+            );
+
+            println!("Finding our addresses...");
+            let mut bootstrap = vec![discover_ticket(&identity, network).await?];
+            bootstrap.extend(
+                Peer::list()
+                    .await?
+                    .into_iter()
+                    .filter(|peer| peer.admission == PeerAdmission::Approved)
+                    .take(MAX_BOOTSTRAP_PEERS)
+                    .map(|peer| peer.ticket),
+            );
+
+            let expires_at = Utc::now() + chrono::Duration::from_std(*expires)?;
+            let token = InviteToken::new(network.topic, bootstrap, labels.clone(), expires_at);
+            let encoded = token.sign(&identity.secret_key)?;
+
+            let invite = SignedInvite::parse(&encoded, network.topic)?;
+            Invite::create(&invite).await?;
+
+            AuditEvent::log(
+                "INVITE_CREATED".to_string(),
+                "Created invite token".to_string(),
+                json!({
+                    "invite_id": token.id.to_string(),
+                    "labels": token.labels,
+                    "expires_at": token.expires_at,
+                }),
+            )
+            .await?;
+
+            println!();
+            println!("Invite ID: {}", token.id);
+            println!("Expires: {} ({})", HumanTime::from(expires_at), expires_at);
+            println!();
+            println!("Run the new node with:");
+            println!("  room_101 <db_path> server --invite {encoded}");
+            Ok(())
+        }
+        InviteCommands::List => {
+            let invites = Invite::list()
+                .await
+                .context("Failed to retrieve invites from database")?;
+
+            if invites.is_empty() {
+                println!("No invites found in database");
+                return Ok(());
+            }
+
+            println!("Found {} invite(s):", invites.len());
+            for invite in invites {
+                println!("  Invite ID: {}", invite.invite_id);
+                println!("    Issuer: {}", invite.issuer);
+                println!("    Labels: {}", invite.labels.join(", "));
+                println!(
+                    "    Expires: {} ({})",
+                    HumanTime::from(invite.expires_at),
+                    invite.expires_at
+                );
+                match (invite.consumed_by, invite.consumed_at) {
+                    (Some(node_id), Some(consumed_at)) => println!(
+                        "    Consumed by: {} {}",
+                        node_id,
+                        HumanTime::from(consumed_at)
+                    ),
+                    _ => println!("    Consumed by: Nobody yet"),
+                }
+                println!();
+            }
+            Ok(())
+        }
+    }
+}
@@ -1,5 +1,9 @@
+pub mod approvals;
 pub mod audit;
+pub mod identity;
 pub mod init;
+pub mod invite;
 pub mod peers;
+pub mod policy;
 pub mod server;
 pub mod status;
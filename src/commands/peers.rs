@@ -1,11 +1,28 @@
-use anyhow::{Context, Result, ensure};
+use anyhow::{Context, Result, bail, ensure};
+use chrono::{TimeDelta, Utc};
 use chrono_humanize::HumanTime;
+use iroh::NodeId;
+use serde_json::json;
 
+use crate::actors::gossip::GossipMessage;
+use crate::actors::gossip::capabilities::Capabilities;
+use crate::actors::membership::revoke;
 use crate::args::{PeerCommands, PeersArgs};
-use crate::db::{Identity, Peer};
+use crate::commands::approvals::propose;
+use crate::config::Config;
+use crate::db::{
+    AuditEvent, Identity, OutboxMessage, Peer, PeerAdmission, PolicyRecord, RevocationRecord,
+};
+use crate::network::clock::CLOCK_SKEW_THRESHOLD;
+use crate::network::control::{self, ControlReply, ControlRequest};
+use crate::network::ping::{Pinged, ping_once};
+use crate::network::proposal::ProposalAction;
+use crate::network::revocation::{Revocation, SignedRevocation};
+use crate::utils::ServerLock;
 
 #[allow(clippy::print_stdout)] // CLI output is appropriate here
-pub async fn run(peers_args: &PeersArgs) -> Result<()> {
+pub async fn run(peers_args: &PeersArgs, config: &Config) -> Result<()> {
+    let network = &config.network;
     match &peers_args.command {
         PeerCommands::List => {
             let peers = Peer::list()
@@ -17,11 +34,69 @@ pub async fn run(peers_args: &PeersArgs) -> Result<()> {
                 return Ok(());
             }
 
-            println!("Found {} peer(s):", peers.len());
+            let pending = peers
+                .iter()
+                .filter(|peer| peer.admission == PeerAdmission::Pending)
+                .count();
+            let without_age_key = peers
+                .iter()
+                .filter(|peer| peer.age_public_key.is_none())
+                .count();
+            println!(
+                "Found {} peer(s), {} pending approval, {} without an age key:",
+                peers.len(),
+                pending,
+                without_age_key
+            );
+            let duplicates = Peer::duplicate_hostnames(&peers);
             for peer in peers {
                 println!("  Node ID: {}", peer.node_id);
+                if let Some(display_name) = peer
+                    .metadata
+                    .as_ref()
+                    .and_then(|metadata| metadata.display_name.as_ref())
+                {
+                    println!("    Display name: {}", display_name);
+                }
+                println!("    Admission: {}", peer.admission);
+                match (&peer.liveness, &peer.liveness_changed_at) {
+                    (Some(liveness), Some(changed_at)) => {
+                        println!(
+                            "    State: {} (since {})",
+                            liveness,
+                            HumanTime::from(*changed_at)
+                        )
+                    }
+                    (Some(liveness), None) => println!("    State: {}", liveness),
+                    _ => println!("    State: Unknown"),
+                }
+                if !peer.labels.is_empty() {
+                    println!("    Labels: {}", peer.labels.join(", "));
+                }
                 if let Some(hostname) = &peer.hostname {
-                    println!("    Hostname: {}", hostname);
+                    match duplicates.get(hostname) {
+                        Some(node_ids) => println!(
+                            "    Hostname: {} (DUPLICATE, also claimed by {})",
+                            hostname,
+                            node_ids
+                                .iter()
+                                .filter(|node_id| **node_id != peer.node_id)
+                                .map(|node_id| node_id.fmt_short())
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        ),
+                        None => println!("    Hostname: {}", hostname),
+                    }
+                }
+                if let Some(metadata) = &peer.metadata {
+                    match &metadata.kernel {
+                        Some(kernel) => println!("    OS: {} (kernel {})", metadata.os, kernel),
+                        None => println!("    OS: {}", metadata.os),
+                    }
+                    println!("    Version: {}", metadata.version);
+                    if !metadata.labels.is_empty() {
+                        println!("    Self-declared labels: {}", metadata.labels.join(", "));
+                    }
                 }
                 if let Some(last_seen) = &peer.last_seen {
                     let human_time = HumanTime::from(*last_seen);
@@ -32,7 +107,32 @@ pub async fn run(peers_args: &PeersArgs) -> Result<()> {
                 if peer.age_public_key.is_some() {
                     println!("    Has Age public key: YES");
                 } else {
-                    println!("    Has Age public key: NO");
+                    println!(
+                        "    Has Age public key: NO (never introduced itself, secrets can not be shared with it)"
+                    );
+                }
+                match (&peer.protocol_version, &peer.capabilities) {
+                    (Some(version), Some(capabilities)) => {
+                        println!("    Protocol: v{} ({})", version, capabilities)
+                    }
+                    _ => println!("    Protocol: Unknown"),
+                }
+                if let Some(latency) = latency(&peer.rtt_ms) {
+                    println!("    Latency: {}", latency);
+                }
+                if let Some(connection) = &peer.connection {
+                    println!("    Connection: {}", connection);
+                }
+                if let Some(offset_ms) = peer.clock_offset_ms {
+                    let offset = TimeDelta::milliseconds(offset_ms);
+                    if offset.abs() > CLOCK_SKEW_THRESHOLD {
+                        println!(
+                            "    Clock offset: {:+.1} s (SKEWED, check the clock on this peer)",
+                            offset_ms as f64 / 1000.0
+                        );
+                    } else {
+                        println!("    Clock offset: {:+.1} s", offset_ms as f64 / 1000.0);
+                    }
                 }
                 println!("    Ticket: {}", peer.ticket);
                 println!("    Node Addr: {:#?}", peer.ticket.node_addr());
@@ -49,8 +149,12 @@ pub async fn run(peers_args: &PeersArgs) -> Result<()> {
                 our_node_id != ticket_node_id,
                 "Cannot add yourself as a peer"
             );
+            ensure!(
+                !RevocationRecord::is_revoked(ticket_node_id).await?,
+                "Peer {ticket_node_id} has been revoked"
+            );
 
-            let result = Peer::insert_from_ticket(ticket.clone())
+            let result = Peer::insert_approved_from_ticket(ticket.clone())
                 .await
                 .context("Failed to add peer from ticket")?;
 
@@ -66,5 +170,240 @@ pub async fn run(peers_args: &PeersArgs) -> Result<()> {
             }
             Ok(())
         }
+        PeerCommands::Approve { node_id } => {
+            let identity = Identity::get().await.context("Failed to get identity")?;
+            ensure!(
+                PolicyRecord::is_admin(identity.id()).await?,
+                "Only admins can approve peers"
+            );
+            ensure!(
+                Peer::is_known(*node_id).await?,
+                "No peer with node ID {node_id}, it must connect or be added first"
+            );
+
+            if PolicyRecord::required_approvals().await? > 1 {
+                propose(&identity, ProposalAction::Admit { node_id: *node_id }).await?;
+                return Ok(());
+            }
+
+            let Some(peer) = Peer::set_admission(*node_id, PeerAdmission::Approved)
+                .await
+                .context("Failed to approve peer")?
+            else {
+                bail!("No peer with node ID {node_id}, it must connect or be added first");
+            };
+
+            // Tell the rest of the network next time the server is connected
+            OutboxMessage::queue(&GossipMessage::Admission {
+                node_id: peer.node_id,
+                approved_at: Utc::now(),
+            })
+            .await?;
+
+            AuditEvent::log(
+                "PEER_APPROVED".to_string(),
+                "Peer approved by operator".to_string(),
+                json!({
+                    "node_id": peer.node_id.to_string(),
+                }),
+            )
+            .await?;
+
+            println!("Approved peer {}", peer.node_id);
+            Ok(())
+        }
+        PeerCommands::Label { node_id, labels } => {
+            let identity = Identity::get().await.context("Failed to get identity")?;
+            ensure!(
+                PolicyRecord::is_admin(identity.id()).await?,
+                "Only admins can label peers"
+            );
+
+            if PolicyRecord::required_approvals().await? > 1 {
+                ensure!(
+                    Peer::is_known(*node_id).await?,
+                    "No peer with node ID {node_id}"
+                );
+                propose(
+                    &identity,
+                    ProposalAction::SetLabels {
+                        node_id: *node_id,
+                        labels: labels.clone(),
+                    },
+                )
+                .await?;
+                return Ok(());
+            }
+
+            let Some(peer) = Peer::set_labels(*node_id, labels.clone())
+                .await
+                .context("Failed to label peer")?
+            else {
+                bail!("No peer with node ID {node_id}");
+            };
+
+            OutboxMessage::queue(&GossipMessage::Labels {
+                node_id: peer.node_id,
+                labels: peer.labels.clone(),
+                set_at: Utc::now(),
+            })
+            .await?;
+
+            AuditEvent::log(
+                "PEER_LABELS_SET".to_string(),
+                "Peer labels set by operator".to_string(),
+                json!({
+                    "node_id": peer.node_id.to_string(),
+                    "labels": peer.labels,
+                }),
+            )
+            .await?;
+
+            println!("Labels for {}: {}", peer.node_id, peer.labels.join(", "));
+            Ok(())
+        }
+        PeerCommands::Revoke { node_id, reason } => {
+            let identity = Identity::get().await.context("Failed to get identity")?;
+            ensure!(
+                PolicyRecord::is_admin(identity.id()).await?,
+                "Only admins can revoke peers"
+            );
+            ensure!(*node_id != identity.id(), "Cannot revoke yourself");
+            ensure!(
+                !RevocationRecord::is_revoked(*node_id).await?,
+                "Peer {node_id} is already revoked"
+            );
+
+            let revocation = SignedRevocation::sign(
+                &identity.secret_key,
+                Revocation {
+                    node_id: *node_id,
+                    reason: reason.clone(),
+                    revoked_at: Utc::now(),
+                },
+            )?;
+
+            if PolicyRecord::required_approvals().await? > 1 {
+                propose(
+                    &identity,
+                    ProposalAction::Revoke {
+                        revocation: revocation.bytes,
+                    },
+                )
+                .await?;
+                return Ok(());
+            }
+
+            // The running server has to forget the peer itself, and can publish it right away
+            let Some(_lock) = ServerLock::acquire(&config.db_path)? else {
+                control::request(
+                    &config.db_path,
+                    ControlRequest::Revoke {
+                        revocation: revocation.bytes,
+                    },
+                )
+                .await?;
+                println!("Revoked peer {node_id}");
+                return Ok(());
+            };
+
+            revoke(&revocation, identity.id()).await?;
+            OutboxMessage::queue(&GossipMessage::Revocation {
+                revocation: revocation.bytes,
+            })
+            .await?;
+
+            println!("Revoked peer {node_id}");
+            Ok(())
+        }
+        PeerCommands::Revoked => {
+            let revocations = RevocationRecord::list()
+                .await
+                .context("Failed to retrieve revocations from database")?;
+
+            if revocations.is_empty() {
+                println!("No peers have been revoked");
+                return Ok(());
+            }
+
+            println!("Found {} revoked peer(s):", revocations.len());
+            for revocation in revocations {
+                println!("  Node ID: {}", revocation.node_id);
+                println!("    Revoked by: {}", revocation.revoked_by);
+                println!(
+                    "    Revoked at: {} ({})",
+                    HumanTime::from(revocation.revoked_at),
+                    revocation.revoked_at
+                );
+                if let Some(reason) = &revocation.reason {
+                    println!("    Reason: {}", reason);
+                }
+                println!();
+            }
+            Ok(())
+        }
+        PeerCommands::Ping { node_id, timeout } => {
+            // Our identity can only be online once, so the running server pings for us
+            let Some(_lock) = ServerLock::acquire(&config.db_path)? else {
+                let reply = control::request(
+                    &config.db_path,
+                    ControlRequest::Ping {
+                        node_id: *node_id,
+                        timeout: *timeout,
+                    },
+                )
+                .await
+                .with_context(|| format!("Failed to ping {node_id}"))?;
+                let ControlReply::Pinged { rtt, connection } = reply else {
+                    bail!("Unexpected reply from the server: {reply:?}");
+                };
+                print_pong(*node_id, &Pinged { rtt, connection });
+                return Ok(());
+            };
+            let identity = Identity::get().await.context("Failed to get identity")?;
+            ensure!(*node_id != identity.id(), "Cannot ping yourself");
+            let Some(peer) = Peer::get(*node_id).await? else {
+                bail!("No peer with node ID {node_id}");
+            };
+            if peer
+                .capabilities
+                .is_some_and(|capabilities| !capabilities.contains(Capabilities::DIRECT_PING))
+            {
+                println!("Warning: {node_id} does not advertise ping support, it may not answer");
+            }
+
+            let pinged = ping_once(&identity, &peer, network, *timeout)
+                .await
+                .with_context(|| format!("Failed to ping {node_id}"))?;
+            print_pong(*node_id, &pinged);
+            Ok(())
+        }
     }
 }
+
+#[allow(clippy::print_stdout)] // CLI output is appropriate here
+fn print_pong(node_id: NodeId, pinged: &Pinged) {
+    match &pinged.connection {
+        Some(connection) => println!(
+            "Pong from {node_id}: {:.1} ms via {connection}",
+            pinged.rtt.as_secs_f64() * 1000.0
+        ),
+        None => println!(
+            "Pong from {node_id}: {:.1} ms",
+            pinged.rtt.as_secs_f64() * 1000.0
+        ),
+    }
+}
+
+/// Summarize a window of round trip times, `None` if there are none
+fn latency(rtt_ms: &[f64]) -> Option<String> {
+    let last = rtt_ms.last()?;
+    let min = rtt_ms.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = rtt_ms.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let average = rtt_ms.iter().sum::<f64>() / rtt_ms.len() as f64;
+
+    Some(format!(
+        "{last:.1} ms (avg {average:.1}, min {min:.1}, max {max:.1} over {} samples)",
+        rtt_ms.len()
+    ))
+}
@@ -0,0 +1,101 @@
+use anyhow::{Context, Result, bail, ensure};
+use chrono::Utc;
+use chrono_humanize::HumanTime;
+use serde_json::json;
+
+use crate::actors::gossip::GossipMessage;
+use crate::actors::gossip::signing::{Policy, SignedPolicy, required_approvals};
+use crate::args::{PolicyArgs, PolicyCommands};
+use crate::commands::approvals::propose;
+use crate::db::{AuditEvent, Identity, KeyHandoverRecord, OutboxMessage, PolicyRecord};
+use crate::network::proposal::ProposalAction;
+
+#[allow(clippy::print_stdout)] // CLI output is appropriate here
+pub async fn run(policy_args: &PolicyArgs) -> Result<()> {
+    match &policy_args.command {
+        PolicyCommands::Show => {
+            let Some(current) = PolicyRecord::current().await? else {
+                println!("No policy has been published, every admitted peer is an admin");
+                return Ok(());
+            };
+
+            println!("Policy version {}:", current.policy.version);
+            println!("  Issued by: {}", current.issued_by);
+            println!(
+                "  Issued at: {} ({})",
+                HumanTime::from(current.policy.issued_at),
+                current.policy.issued_at
+            );
+            println!("  Approvals needed: {}", required_approvals(Some(&current)));
+            println!("  Admins:");
+            for admin in &current.policy.admins {
+                println!("    {admin}");
+            }
+            Ok(())
+        }
+        PolicyCommands::Set { admins, threshold } => {
+            let identity = Identity::get().await.context("Failed to get identity")?;
+            let current = PolicyRecord::current().await?;
+
+            let policy = Policy {
+                version: current
+                    .as_ref()
+                    .map_or(1, |current| current.policy.version + 1),
+                admins: admins.clone(),
+                threshold: *threshold,
+                issued_at: Utc::now(),
+            };
+            ensure!(
+                policy.threshold >= 1 && policy.threshold as usize <= policy.admins.len(),
+                "The threshold must be between 1 and the number of admins"
+            );
+            let signed = SignedPolicy::sign(&identity.secret_key, policy)?;
+
+            let predecessors = KeyHandoverRecord::lineage(identity.id()).await?;
+            if let Err(err) = signed.supersedes(current.as_ref(), &predecessors) {
+                bail!("Cannot publish policy: {err}");
+            }
+            ensure!(
+                signed.policy.admins.contains(&identity.id()) || current.is_some(),
+                "The first policy must include this node as an admin"
+            );
+
+            if required_approvals(current.as_ref()) > 1 {
+                propose(
+                    &identity,
+                    ProposalAction::Policy {
+                        policy: signed.bytes,
+                    },
+                )
+                .await?;
+                return Ok(());
+            }
+
+            PolicyRecord::store(&signed).await?;
+            OutboxMessage::queue(&GossipMessage::Policy {
+                policy: signed.bytes.clone(),
+            })
+            .await?;
+
+            AuditEvent::log(
+                "POLICY_UPDATED".to_string(),
+                "Published a new policy".to_string(),
+                json!({
+                    "version": signed.policy.version,
+                    "issued_by": signed.issued_by.to_string(),
+                    "admins": signed.policy.admins.iter().map(|admin| admin.to_string()).collect::<Vec<_>>(),
+                    "threshold": signed.policy.threshold,
+                }),
+            )
+            .await?;
+
+            println!(
+                "Published policy version {} with {} admin(s) and a threshold of {}",
+                signed.policy.version,
+                signed.policy.admins.len(),
+                signed.policy.threshold
+            );
+            Ok(())
+        }
+    }
+}
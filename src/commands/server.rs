@@ -1,43 +1,111 @@
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail, ensure};
 use iroh_base::ticket::NodeTicket;
-use ractor::Actor;
+use ractor::{Actor, ActorRef};
 use std::str::FromStr;
 use std::time::Duration;
+use tokio::signal::unix::{SignalKind, signal};
 use tracing::{debug, error, info, warn};
 
-use crate::actors::{AppConfig, SupervisorActor};
-use crate::args::ServerArgs;
-use crate::db::Peer;
+use crate::actors::gossip::iroh::IrohMessage;
+use crate::actors::{SupervisorActor, SupervisorMessage};
+use crate::args::{Args, ServerArgs};
+use crate::commands::identity::read_passphrase;
+use crate::config::{Config, ServerConfig};
+use crate::db::{Identity, Invite, Peer, SealedIdentity, Sealer};
+use crate::network::control;
+use crate::network::invite::SignedInvite;
+use crate::utils::ServerLock;
 
-pub async fn run(server_args: &ServerArgs) -> Result<()> {
+pub async fn run(args: &Args, server_args: &ServerArgs, config: &Config) -> Result<()> {
     info!("Starting Room 101 Server");
 
-    // Add any bootstrap tickets as Peers
-    if !server_args.bootstrap.is_empty() {
-        for ticket_str in &server_args.bootstrap {
+    // Held until the server exits, see `ServerLock`
+    let Some(_lock) = ServerLock::acquire(&config.db_path)? else {
+        bail!(
+            "A server is already running with the database at {}",
+            config.db_path
+        );
+    };
+
+    // Lets CLI commands hand work to us while we run, see `ControlRequest`
+    let control = control::listen(&config.db_path)?;
+
+    let mut running = config.server(server_args)?;
+    let server_config = running.clone();
+
+    // Unseal the identity up front so a wrong passphrase fails before anything starts
+    if let Some(sealed) = SealedIdentity::get().await? {
+        let sealer = match &server_config.passphrase_file {
+            Some(path) => Some(Sealer::Passphrase(read_passphrase(Some(path)).await?)),
+            None => None,
+        };
+        let identity = sealed.unseal(sealer).await?;
+        info!(node_id = %identity.id(), method = %sealed.method, "Unsealed identity");
+    }
+
+    // Add any bootstrap tickets as Peers, the operator vouched for them so they are approved
+    if !server_config.bootstrap.is_empty() {
+        for ticket_str in &server_config.bootstrap {
             let ticket = NodeTicket::from_str(ticket_str)?;
-            Peer::insert_from_ticket(ticket).await?;
+            Peer::insert_approved_from_ticket(ticket).await?;
         }
     }
 
-    // Create application configuration
-    let app_config = AppConfig {};
+    // Trust the bootstrap nodes bundled into our invite, the redemption itself is sent once we
+    // have a neighbor to send it to. Once it has been accepted the invite may stay in the config
+    // after it expires.
+    let app_config = server_config.app;
+    if let Some(token) = &app_config.invite {
+        let invite =
+            SignedInvite::parse(token, app_config.network.topic).context("Invalid invite token")?;
+        let redeemed = Invite::is_redeemed_by(invite.token.id, Identity::get().await?.id()).await?;
+        ensure!(
+            redeemed || !invite.token.is_expired(),
+            "Invite expired at {}",
+            invite.token.expires_at
+        );
+
+        info!(invite_id = %invite.token.id, issuer = %invite.issuer, "Joining with invite");
+        for ticket in invite.token.bootstrap {
+            Peer::insert_approved_from_ticket(ticket).await?;
+        }
+    }
+
+    info!(metadata = ?app_config.metadata, "Publishing node metadata");
 
     // Start the supervisor actor
     debug!("Starting SupervisorActor");
-    let (supervisor_actor, supervisor_handle) =
+    let (supervisor_actor, mut supervisor_handle) =
         Actor::spawn(Some("supervisor".into()), SupervisorActor, app_config).await?;
 
-    info!("SupervisorActor started, waiting for Ctrl+C...");
-
-    // Wait for Ctrl+C
-    tokio::signal::ctrl_c()
-        .await
-        .context("Failed to listen for ctrl-c")?;
+    info!("SupervisorActor started, waiting for Ctrl+C or SIGTERM...");
 
-    info!("Received Ctrl+C, initiating shutdown...");
+    let mut hangup = signal(SignalKind::hangup()).context("Failed to listen for SIGHUP")?;
+    // Created once, so a signal arriving while a reload runs is not missed
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let signal = loop {
+        // The supervisor only stops on its own when an actor keeps failing, exit with an error so
+        // the service manager can restart us
+        tokio::select! {
+            signal = &mut shutdown => break signal?,
+            _ = hangup.recv() => {
+                info!("Received SIGHUP, reloading the config");
+                if let Err(err) = reload(args, server_args, &mut running, &supervisor_actor).await {
+                    error!(?err, "Failed to reload the config, keeping the running one");
+                }
+            }
+            result = &mut supervisor_handle => {
+                if let Err(err) = result {
+                    error!(?err, "SupervisorActor task failed");
+                }
+                bail!("Actors kept failing and were shut down, see the audit log for details");
+            }
+        }
+    };
+    info!("Received {signal}, initiating shutdown...");
 
-    // Stop the supervisor actor, which will stop all linked actors
+    // Stop the supervisor actor, which announces that we are leaving and stops all linked actors
     supervisor_actor.stop(None);
 
     // Wait for supervisor to complete shutdown
@@ -55,6 +123,11 @@ pub async fn run(server_args: &ServerArgs) -> Result<()> {
         }
     }
 
+    control.abort();
+    if let Err(err) = std::fs::remove_file(control::socket_path(&config.db_path)) {
+        debug!(?err, "Failed to remove control socket");
+    }
+
     // TODO: Clean up database connection
     debug!("Closing database connection...");
     // if let Err(e) = db::close_db().await {
@@ -64,3 +137,90 @@ pub async fn run(server_args: &ServerArgs) -> Result<()> {
     info!("Application shutdown complete");
     Ok(())
 }
+
+/// Re-read the config file and apply the changes that do not need a restart
+///
+/// `running` is only updated with what was applied, so settings that still need a restart keep
+/// being reported on every reload until the server is restarted.
+async fn reload(
+    args: &Args,
+    server_args: &ServerArgs,
+    running: &mut ServerConfig,
+    supervisor: &ActorRef<SupervisorMessage>,
+) -> Result<()> {
+    if args.config.is_none() {
+        info!("No config file was given, nothing to reload");
+        return Ok(());
+    }
+
+    let reloaded = Config::load(args).await?.server(server_args)?;
+    let changes = running.changes(&reloaded);
+    if changes.is_empty() {
+        info!("Config reloaded, nothing changed");
+        return Ok(());
+    }
+
+    if !changes.bootstrap.is_empty() {
+        let tickets = changes
+            .bootstrap
+            .iter()
+            .map(|ticket| NodeTicket::from_str(ticket))
+            .collect::<Result<Vec<_>, _>>()
+            .context("Invalid bootstrap ticket")?;
+
+        let mut node_addrs = Vec::new();
+        for ticket in tickets {
+            if Peer::insert_approved_from_ticket(ticket.clone())
+                .await?
+                .is_some()
+            {
+                node_addrs.push(ticket.node_addr().clone());
+            }
+        }
+
+        info!(count = node_addrs.len(), "Joining new bootstrap peers");
+        if !node_addrs.is_empty()
+            && let Some(iroh) = ractor::registry::where_is("iroh".to_string())
+        {
+            iroh.send_message(IrohMessage::JoinPeers(node_addrs))?;
+        }
+        running.bootstrap = reloaded.bootstrap;
+    }
+
+    if let Some(heartbeat) = changes.heartbeat {
+        info!(interval = ?heartbeat.interval, adaptive = heartbeat.adaptive, "Heartbeat changed");
+        supervisor.send_message(SupervisorMessage::SetHeartbeat(heartbeat))?;
+        running.app.heartbeat = heartbeat;
+    }
+
+    if let Some(log_filter) = changes.log_filter {
+        if crate::tracing::set_log_filter(log_filter.as_deref())? {
+            info!(?log_filter, "Log filter changed");
+        } else {
+            warn!("RUST_LOG is set and takes precedence over the new log filter");
+        }
+        running.log_filter = log_filter;
+    }
+
+    if !changes.needs_restart.is_empty() {
+        warn!(
+            settings = ?changes.needs_restart,
+            "Some settings changed that only take effect after a restart"
+        );
+    }
+
+    Ok(())
+}
+
+/// Wait for Ctrl+C, or SIGTERM which is what systemd sends, returns the name of the signal
+async fn shutdown_signal() -> Result<&'static str> {
+    let mut terminate = signal(SignalKind::terminate()).context("Failed to listen for SIGTERM")?;
+
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => {
+            result.context("Failed to listen for ctrl-c")?;
+            Ok("Ctrl+C")
+        }
+        _ = terminate.recv() => Ok("SIGTERM"),
+    }
+}
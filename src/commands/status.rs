@@ -1,13 +1,34 @@
 use anyhow::Result;
 
-use crate::db::Peer;
+use crate::db::{Peer, SecretEntry};
+use crate::network::liveness::Liveness;
 
 #[allow(clippy::print_stdout)] // CLI output is appropriate here
 pub async fn run() -> Result<()> {
     let peers_count = Peer::count().await?;
+    let peers = Peer::list().await?;
+    let count = |liveness: Option<Liveness>| {
+        peers
+            .iter()
+            .filter(|peer| peer.liveness == liveness)
+            .count()
+    };
 
     println!("Status:");
     println!("  Peers count: {}", peers_count);
+    println!("    Online: {}", count(Some(Liveness::Online)));
+    println!("    Suspect: {}", count(Some(Liveness::Suspect)));
+    println!("    Offline: {}", count(Some(Liveness::Offline)));
+    println!("    Left: {}", count(Some(Liveness::Left)));
+    println!("    Never seen: {}", count(None));
+
+    let to_rotate = SecretEntry::to_rotate().await?;
+    if !to_rotate.is_empty() {
+        println!(
+            "  Secrets to rotate, a revoked peer held them: {}",
+            to_rotate.join(", ")
+        );
+    }
 
     Ok(())
 }
@@ -0,0 +1,637 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result, ensure};
+use iroh::{Endpoint, NodeId, RelayMode, SecretKey, endpoint::Builder};
+use iroh_gossip::proto::TopicId;
+use serde::Deserialize;
+
+use crate::actors::AppConfig;
+use crate::args::{Args, ServerArgs, parse_duration};
+use crate::network::metadata::NodeMetadata;
+use crate::utils::topic_from_name;
+
+/// Topic joined unless another one is configured, every node of a network must use the same one
+pub const DEFAULT_TOPIC: &str = "ROOM_101";
+
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Adaptive heartbeats aim for each node receiving about this many heartbeats per second
+const ADAPTIVE_HEARTBEATS_PER_SECOND: f64 = 10.0;
+
+/// Adaptive heartbeats never slow down past this, so a peer going away is still noticed within
+/// a few minutes
+const MAX_ADAPTIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+const DEFAULT_CREDSTORE_PATH: &str = "/var/lib/credstore";
+
+/// Settings read from the file given with `--config`, flags on the command line take precedence
+///
+/// ```toml
+/// db_path = "/var/lib/room_101/db"
+/// log_filter = "room_101=debug"
+/// topic = "ROOM_101"
+///
+/// [server]
+/// bootstrap = ["nodeadr..."]
+/// heartbeat_interval = "1s"
+/// adaptive_heartbeat = true
+/// bootstrap_admins = ["nodeid..."]
+///
+/// [systemd]
+/// credstore_path = "/var/lib/credstore"
+/// user_scope = false
+///
+/// [discovery]
+/// n0 = true
+/// relay = true
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub db_path: Option<String>,
+    /// Used when RUST_LOG is not set
+    pub log_filter: Option<String>,
+    pub topic: Option<String>,
+    pub server: ServerSection,
+    pub systemd: SystemdSection,
+    pub discovery: DiscoverySection,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSection {
+    pub bootstrap: Vec<String>,
+    /// Such as `1s` or `1m`
+    pub heartbeat_interval: Option<String>,
+    /// Slow heartbeats down as the network grows
+    pub adaptive_heartbeat: Option<bool>,
+    pub display_name: Option<String>,
+    pub labels: Vec<String>,
+    pub notify_command: Option<String>,
+    pub passphrase_file: Option<PathBuf>,
+    pub ticket_file: Option<PathBuf>,
+    /// Node ids allowed to publish the first policy of the network
+    pub bootstrap_admins: Vec<String>,
+    /// Accept the first policy published by any admitted node
+    pub trust_first_policy: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SystemdSection {
+    pub credstore_path: Option<PathBuf>,
+    pub user_scope: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoverySection {
+    /// Publish and look up addresses through the n0 DNS discovery service
+    pub n0: Option<bool>,
+    /// Use the n0 relay servers when a direct connection is not possible
+    pub relay: Option<bool>,
+}
+
+impl ConfigFile {
+    pub async fn read(path: &Path) -> Result<Self> {
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read config file {path:?}"))?;
+        Self::parse(&content).with_context(|| format!("Invalid config file {path:?}"))
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        Ok(toml::from_str(content)?)
+    }
+}
+
+/// How this node joins the network, used by the server and the CLI commands that go online
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetworkConfig {
+    pub topic: TopicId,
+    pub discovery_n0: bool,
+    pub relay: bool,
+}
+
+impl NetworkConfig {
+    /// An endpoint builder for our identity that uses the configured discovery
+    pub fn endpoint_builder(&self, secret_key: SecretKey) -> Builder {
+        let builder = Endpoint::builder().secret_key(secret_key);
+        let builder = if self.discovery_n0 {
+            builder.discovery_n0()
+        } else {
+            builder
+        };
+
+        if self.relay {
+            builder.relay_mode(RelayMode::Default)
+        } else {
+            builder.relay_mode(RelayMode::Disabled)
+        }
+    }
+}
+
+/// How often we send heartbeats
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeartbeatConfig {
+    pub interval: Duration,
+    /// Stretch the interval in large networks, every node hears every heartbeat so the traffic
+    /// grows with the square of the network size otherwise
+    pub adaptive: bool,
+}
+
+impl HeartbeatConfig {
+    /// Delay until the next heartbeat in a network with `peer_count` other nodes
+    pub fn delay(&self, peer_count: usize) -> Duration {
+        if !self.adaptive {
+            return self.interval;
+        }
+
+        let spread = Duration::from_secs_f64(peer_count as f64 / ADAPTIVE_HEARTBEATS_PER_SECOND);
+        spread.min(MAX_ADAPTIVE_INTERVAL).max(self.interval)
+    }
+
+    /// The longest delay between two heartbeats, whatever the size of the network
+    pub fn max_delay(&self) -> Duration {
+        if self.adaptive {
+            self.interval.max(MAX_ADAPTIVE_INTERVAL)
+        } else {
+            self.interval
+        }
+    }
+}
+
+/// Who may publish the first policy of the network, later ones must be signed by its admins
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PolicyConfig {
+    pub bootstrap_admins: Vec<NodeId>,
+    /// Trust whichever admitted node publishes a policy first, which lets any member race to
+    /// make itself the only admin
+    pub trust_first: bool,
+}
+
+impl PolicyConfig {
+    /// Whether a policy issued by `issuer` may be accepted while none is in force
+    pub fn may_publish_first(&self, issuer: NodeId) -> bool {
+        self.trust_first || self.bootstrap_admins.contains(&issuer)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SystemdConfig {
+    /// Directory the systemd credentials are written to
+    pub credstore_path: PathBuf,
+    /// Use user-scope credentials instead of system-scope
+    pub user_scope: bool,
+}
+
+/// The settings every command needs, resolved from the command line and the config file
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub db_path: String,
+    pub log_filter: Option<String>,
+    pub network: NetworkConfig,
+    /// The rest of the file, resolved by the commands that use it
+    pub file: ConfigFile,
+}
+
+/// Everything `server` needs to start
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub db_path: String,
+    pub log_filter: Option<String>,
+    pub bootstrap: Vec<String>,
+    pub passphrase_file: Option<PathBuf>,
+    pub app: AppConfig,
+}
+
+/// What changed between the running config and a reloaded one
+#[derive(Debug, Default, PartialEq)]
+pub struct ConfigChanges {
+    /// Bootstrap tickets that were added, removed ones stay known peers
+    pub bootstrap: Vec<String>,
+    pub heartbeat: Option<HeartbeatConfig>,
+    /// The new log filter, `Some(None)` if it was removed
+    pub log_filter: Option<Option<String>>,
+    /// Settings that changed but only take effect once the server is restarted
+    pub needs_restart: Vec<&'static str>,
+}
+
+impl ConfigChanges {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl ServerConfig {
+    /// Compare against a freshly loaded config
+    pub fn changes(&self, new: &ServerConfig) -> ConfigChanges {
+        let (old_app, new_app) = (&self.app, &new.app);
+
+        let needs_restart = [
+            ("db_path", self.db_path != new.db_path),
+            ("topic", old_app.network.topic != new_app.network.topic),
+            (
+                "discovery.n0",
+                old_app.network.discovery_n0 != new_app.network.discovery_n0,
+            ),
+            (
+                "discovery.relay",
+                old_app.network.relay != new_app.network.relay,
+            ),
+            (
+                "server.display_name",
+                old_app.metadata.display_name != new_app.metadata.display_name,
+            ),
+            (
+                "server.labels",
+                old_app.metadata.labels != new_app.metadata.labels,
+            ),
+            (
+                "server.notify_command",
+                old_app.notify_command != new_app.notify_command,
+            ),
+            (
+                "server.ticket_file",
+                old_app.ticket_file != new_app.ticket_file,
+            ),
+            (
+                "server.bootstrap_admins",
+                old_app.policy.bootstrap_admins != new_app.policy.bootstrap_admins,
+            ),
+            (
+                "server.trust_first_policy",
+                old_app.policy.trust_first != new_app.policy.trust_first,
+            ),
+            ("systemd", old_app.systemd != new_app.systemd),
+        ]
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(name, _)| name)
+        .collect();
+
+        ConfigChanges {
+            bootstrap: new
+                .bootstrap
+                .iter()
+                .filter(|ticket| !self.bootstrap.contains(ticket))
+                .cloned()
+                .collect(),
+            heartbeat: (old_app.heartbeat != new_app.heartbeat).then_some(new_app.heartbeat),
+            log_filter: (self.log_filter != new.log_filter).then(|| new.log_filter.clone()),
+            needs_restart,
+        }
+    }
+}
+
+impl Config {
+    /// Read the config file, if any, and apply the flags given on the command line over it
+    pub async fn load(args: &Args) -> Result<Self> {
+        let file = match &args.config {
+            Some(path) => ConfigFile::read(path).await?,
+            None => ConfigFile::default(),
+        };
+        Self::resolve(args, file)
+    }
+
+    fn resolve(args: &Args, file: ConfigFile) -> Result<Self> {
+        let db_path = args
+            .db_path
+            .clone()
+            .or_else(|| file.db_path.clone())
+            .context("No database path given, pass one or set db_path in the config file")?;
+
+        let topic = args
+            .topic
+            .as_deref()
+            .or(file.topic.as_deref())
+            .unwrap_or(DEFAULT_TOPIC);
+
+        let network = NetworkConfig {
+            topic: topic_from_name(topic)?,
+            discovery_n0: !args.no_n0_discovery && file.discovery.n0.unwrap_or(true),
+            relay: !args.no_relay && file.discovery.relay.unwrap_or(true),
+        };
+
+        Ok(Self {
+            db_path,
+            log_filter: file.log_filter.clone(),
+            network,
+            file,
+        })
+    }
+
+    /// Apply the flags given to `server` over the `[server]` and `[systemd]` sections
+    pub fn server(&self, server_args: &ServerArgs) -> Result<ServerConfig> {
+        let section = &self.file.server;
+
+        let heartbeat_interval =
+            match (&server_args.heartbeat_interval, &section.heartbeat_interval) {
+                (Some(interval), _) => *interval,
+                (None, Some(interval)) => parse_duration(interval)
+                    .map_err(|err| anyhow::anyhow!("Invalid server.heartbeat_interval: {err}"))?,
+                (None, None) => DEFAULT_HEARTBEAT_INTERVAL,
+            };
+        ensure!(
+            !heartbeat_interval.is_zero(),
+            "The heartbeat interval must be longer than zero"
+        );
+        let heartbeat = HeartbeatConfig {
+            interval: heartbeat_interval,
+            adaptive: server_args.adaptive_heartbeat || section.adaptive_heartbeat.unwrap_or(false),
+        };
+
+        let display_name = server_args
+            .display_name
+            .clone()
+            .or_else(|| section.display_name.clone());
+        let labels = override_list(&server_args.labels, &section.labels);
+
+        let systemd = SystemdConfig {
+            credstore_path: server_args
+                .systemd_secrets_path
+                .clone()
+                .or_else(|| self.file.systemd.credstore_path.clone())
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_CREDSTORE_PATH)),
+            user_scope: server_args.systemd_user_scope
+                || self.file.systemd.user_scope.unwrap_or(false),
+        };
+
+        let policy = PolicyConfig {
+            bootstrap_admins: section
+                .bootstrap_admins
+                .iter()
+                .map(|node_id| {
+                    node_id
+                        .parse()
+                        .with_context(|| format!("Invalid server.bootstrap_admins entry {node_id}"))
+                })
+                .collect::<Result<_>>()?,
+            trust_first: section.trust_first_policy.unwrap_or(false),
+        };
+
+        Ok(ServerConfig {
+            db_path: self.db_path.clone(),
+            log_filter: self.log_filter.clone(),
+            bootstrap: override_list(&server_args.bootstrap, &section.bootstrap),
+            passphrase_file: server_args
+                .passphrase_file
+                .clone()
+                .or_else(|| section.passphrase_file.clone()),
+            app: AppConfig {
+                metadata: NodeMetadata::collect(display_name, labels),
+                notify_command: server_args
+                    .notify_command
+                    .clone()
+                    .or_else(|| section.notify_command.clone()),
+                network: self.network.clone(),
+                heartbeat,
+                ticket_file: server_args
+                    .init
+                    .ticket_file
+                    .clone()
+                    .or_else(|| section.ticket_file.clone()),
+                invite: server_args.invite.clone(),
+                policy,
+                systemd,
+            },
+        })
+    }
+}
+
+/// Lists given on the command line replace the ones from the file rather than adding to them
+fn override_list(flags: &[String], file: &[String]) -> Vec<String> {
+    if flags.is_empty() {
+        file.to_vec()
+    } else {
+        flags.to_vec()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::args::Commands;
+
+    const FILE: &str = r#"
+        db_path = "/var/lib/room_101/db"
+        log_filter = "room_101=debug"
+        topic = "TEST_NETWORK"
+
+        [server]
+        bootstrap = ["ticket-a", "ticket-b"]
+        heartbeat_interval = "5s"
+        adaptive_heartbeat = true
+        labels = ["env=prod"]
+
+        [systemd]
+        credstore_path = "/etc/credstore"
+        user_scope = true
+
+        [discovery]
+        relay = false
+    "#;
+
+    fn server_config(argv: &[&str]) -> (Config, ServerConfig) {
+        let args = Args::try_parse_from(argv).unwrap();
+        let config = Config::resolve(&args, ConfigFile::parse(FILE).unwrap()).unwrap();
+        let Commands::Server(server_args) = &args.command else {
+            panic!("Not a server command");
+        };
+        let server = config.server(server_args).unwrap();
+        (config, server)
+    }
+
+    #[test]
+    fn test_file_values_are_used() {
+        let (config, server) = server_config(&["room_101", "server"]);
+
+        assert_eq!(config.db_path, "/var/lib/room_101/db");
+        assert_eq!(config.log_filter.as_deref(), Some("room_101=debug"));
+        assert_eq!(
+            config.network.topic,
+            topic_from_name("TEST_NETWORK").unwrap()
+        );
+        assert!(config.network.discovery_n0);
+        assert!(!config.network.relay);
+
+        assert_eq!(server.bootstrap, vec!["ticket-a", "ticket-b"]);
+        assert_eq!(
+            server.app.heartbeat,
+            HeartbeatConfig {
+                interval: Duration::from_secs(5),
+                adaptive: true,
+            }
+        );
+        assert_eq!(server.app.metadata.labels, vec!["env=prod"]);
+        assert_eq!(
+            server.app.systemd,
+            SystemdConfig {
+                credstore_path: PathBuf::from("/etc/credstore"),
+                user_scope: true,
+            }
+        );
+    }
+
+    #[test]
+    fn test_flags_override_file() {
+        let (config, server) = server_config(&[
+            "room_101",
+            "/tmp/db",
+            "--topic",
+            "OTHER",
+            "--no-n0-discovery",
+            "server",
+            "ticket-c",
+            "--heartbeat-interval",
+            "2s",
+            "--label",
+            "web",
+            "--systemd-secrets-path",
+            "/tmp/credstore",
+        ]);
+
+        assert_eq!(config.db_path, "/tmp/db");
+        assert_eq!(config.network.topic, topic_from_name("OTHER").unwrap());
+        assert!(!config.network.discovery_n0);
+
+        assert_eq!(server.bootstrap, vec!["ticket-c"]);
+        assert_eq!(server.app.heartbeat.interval, Duration::from_secs(2));
+        assert_eq!(server.app.metadata.labels, vec!["web"]);
+        assert_eq!(
+            server.app.systemd.credstore_path,
+            PathBuf::from("/tmp/credstore")
+        );
+    }
+
+    #[test]
+    fn test_defaults_without_file() {
+        let args = Args::try_parse_from(["room_101", "/tmp/db", "status"]).unwrap();
+        let config = Config::resolve(&args, ConfigFile::default()).unwrap();
+
+        assert_eq!(
+            config.network,
+            NetworkConfig {
+                topic: topic_from_name(DEFAULT_TOPIC).unwrap(),
+                discovery_n0: true,
+                relay: true,
+            }
+        );
+
+        let args = Args::try_parse_from(["room_101", "status"]).unwrap();
+        assert!(Config::resolve(&args, ConfigFile::default()).is_err());
+    }
+
+    #[test]
+    fn test_changes() {
+        let (_, running) = server_config(&["room_101", "server"]);
+        assert!(running.changes(&running).is_empty());
+
+        let (_, reloaded) = server_config(&[
+            "room_101",
+            "server",
+            "ticket-a",
+            "ticket-c",
+            "--heartbeat-interval",
+            "10s",
+            "--display-name",
+            "web-1",
+        ]);
+        let mut reloaded = reloaded;
+        reloaded.log_filter = None;
+
+        assert_eq!(
+            running.changes(&reloaded),
+            ConfigChanges {
+                bootstrap: vec!["ticket-c".to_string()],
+                heartbeat: Some(HeartbeatConfig {
+                    interval: Duration::from_secs(10),
+                    adaptive: true,
+                }),
+                log_filter: Some(None),
+                needs_restart: vec!["server.display_name"],
+            }
+        );
+    }
+
+    #[test]
+    fn test_adaptive_heartbeat_delay() {
+        let fixed = HeartbeatConfig {
+            interval: Duration::from_secs(1),
+            adaptive: false,
+        };
+        assert_eq!(fixed.delay(1000), Duration::from_secs(1));
+
+        let adaptive = HeartbeatConfig {
+            adaptive: true,
+            ..fixed
+        };
+        assert_eq!(adaptive.delay(3), Duration::from_secs(1));
+        assert_eq!(adaptive.delay(50), Duration::from_secs(5));
+        assert_eq!(adaptive.delay(1000), MAX_ADAPTIVE_INTERVAL);
+
+        // A configured interval above the cap is kept
+        let slow = HeartbeatConfig {
+            interval: Duration::from_secs(60),
+            adaptive: true,
+        };
+        assert_eq!(slow.delay(1000), Duration::from_secs(60));
+
+        assert_eq!(fixed.max_delay(), Duration::from_secs(1));
+        assert_eq!(adaptive.max_delay(), MAX_ADAPTIVE_INTERVAL);
+        assert_eq!(slow.max_delay(), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_bootstrap_admins() {
+        let admin = SecretKey::generate(rand::rngs::OsRng).public();
+        let other = SecretKey::generate(rand::rngs::OsRng).public();
+        let args = Args::try_parse_from(["room_101", "/tmp/db", "server"]).unwrap();
+        let Commands::Server(server_args) = &args.command else {
+            panic!("Not a server command");
+        };
+
+        let file =
+            ConfigFile::parse(&format!("[server]\nbootstrap_admins = [\"{admin}\"]")).unwrap();
+        let config = Config::resolve(&args, file).unwrap();
+        let policy = config.server(server_args).unwrap().app.policy;
+        assert_eq!(policy.bootstrap_admins, vec![admin]);
+        assert!(policy.may_publish_first(admin));
+        assert!(!policy.may_publish_first(other));
+
+        // Nobody may publish the first policy unless trust on first use is asked for
+        let config = Config::resolve(&args, ConfigFile::default()).unwrap();
+        assert!(
+            !config
+                .server(server_args)
+                .unwrap()
+                .app
+                .policy
+                .may_publish_first(admin)
+        );
+        let file = ConfigFile::parse("[server]\ntrust_first_policy = true").unwrap();
+        let config = Config::resolve(&args, file).unwrap();
+        assert!(
+            config
+                .server(server_args)
+                .unwrap()
+                .app
+                .policy
+                .may_publish_first(other)
+        );
+
+        let file = ConfigFile::parse("[server]\nbootstrap_admins = [\"nope\"]").unwrap();
+        let config = Config::resolve(&args, file).unwrap();
+        assert!(config.server(server_args).is_err());
+    }
+
+    #[test]
+    fn test_unknown_keys_are_rejected() {
+        assert!(ConfigFile::parse("db_pth = \"/tmp/db\"").is_err());
+        assert!(ConfigFile::parse("[server]\nheartbeat = \"1s\"").is_err());
+    }
+}
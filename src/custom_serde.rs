@@ -123,6 +123,28 @@ pub mod optional_chrono_datetime_as_sql {
     }
 }
 
+/// Custom serde serialization/deserialization for raw bytes as SurrealDB bytes
+///
+/// A plain `Vec<u8>` is serialized as an array of numbers, this stores it using the `bytes` type
+/// instead.
+pub mod bytes_as_sql {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S>(x: &[u8], s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        surrealdb_types::Bytes::from(x.to_vec()).serialize(s)
+    }
+
+    pub fn deserialize<'de, D>(d: D) -> Result<Vec<u8>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        surrealdb_types::Bytes::deserialize(d).map(Vec::from)
+    }
+}
+
 /// Custom serde serialization module for Iroh NodeId
 ///
 /// Provides safe serialization/deserialization for iroh::NodeId using
@@ -147,6 +169,31 @@ pub mod node_id_serde {
     }
 }
 
+/// Custom serde serialization module for an optional Iroh NodeId
+pub mod optional_node_id_serde {
+    use iroh::NodeId;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S>(node_id: &Option<NodeId>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match node_id {
+            Some(node_id) => node_id.to_string().serialize(serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<NodeId>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Option::<String>::deserialize(deserializer)?
+            .map(|s| s.parse::<NodeId>().map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
 /// Custom serde serialization module for Iroh NodeTicket
 ///
 /// Provides safe serialization/deserialization for iroh_base::ticket::NodeTicket using
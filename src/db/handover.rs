@@ -0,0 +1,164 @@
+use std::collections::HashSet;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use iroh::NodeId;
+use serde::{Deserialize, Serialize};
+
+use super::{SenderStatus, db};
+use crate::network::handover::SignedHandover;
+
+/// A node ID that was retired in favour of a new one, kept as the signed handover
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyHandoverRecord {
+    #[serde(with = "crate::custom_serde::node_id_serde")]
+    pub old_node_id: NodeId,
+    #[serde(with = "crate::custom_serde::node_id_serde")]
+    pub new_node_id: NodeId,
+    #[serde(with = "crate::custom_serde::chrono_datetime_as_sql")]
+    pub rotated_at: DateTime<Utc>,
+    #[serde(with = "crate::custom_serde::bytes_as_sql")]
+    pub signed: Vec<u8>,
+}
+
+impl KeyHandoverRecord {
+    /// Store a node key handover we have not seen before, returns whether it was new
+    ///
+    /// Handovers that only rotate the age key do not retire anything and are not stored.
+    pub async fn store(handover: &SignedHandover) -> Result<bool> {
+        let old_node_id = handover.handover.old_node_id;
+        if !handover.handover.rotates_node_key() || Self::successor(old_node_id).await?.is_some() {
+            return Ok(false);
+        }
+
+        let record = KeyHandoverRecord {
+            old_node_id,
+            new_node_id: handover.handover.new_node_id,
+            rotated_at: handover.handover.rotated_at,
+            signed: handover.bytes.clone(),
+        };
+
+        let _: Option<KeyHandoverRecord> = db()
+            .await?
+            .create(("key_handover", old_node_id.to_string()))
+            .content(record)
+            .await
+            .context("Failed to store key handover")?;
+        SenderStatus::invalidate();
+        Ok(true)
+    }
+
+    /// The node ID that replaced `node_id`, if it has been retired
+    pub async fn successor(node_id: NodeId) -> Result<Option<NodeId>> {
+        let record: Option<KeyHandoverRecord> = db()
+            .await?
+            .select(("key_handover", node_id.to_string()))
+            .await
+            .context("Failed to get key handover")?;
+        Ok(record.map(|record| record.new_node_id))
+    }
+
+    /// `node_id` followed by every node ID it replaced, oldest last
+    pub async fn lineage(node_id: NodeId) -> Result<Vec<NodeId>> {
+        let mut lineage = vec![node_id];
+        let mut seen = HashSet::from([node_id]);
+
+        let mut current = node_id;
+        loop {
+            let previous: Vec<KeyHandoverRecord> = db()
+                .await?
+                .query("SELECT * FROM key_handover WHERE new_node_id = $node_id")
+                .bind(("node_id", current.to_string()))
+                .await?
+                .take(0)
+                .context("Failed to get key handover")?;
+
+            match previous.first() {
+                Some(record) if seen.insert(record.old_node_id) => {
+                    lineage.push(record.old_node_id);
+                    current = record.old_node_id;
+                }
+                _ => return Ok(lineage),
+            }
+        }
+    }
+
+    /// Point every secret encrypted for `old_node_id` at `new_node_id`, returns their names
+    pub async fn retarget_secrets(old_node_id: NodeId, new_node_id: NodeId) -> Result<Vec<String>> {
+        #[derive(serde::Deserialize)]
+        struct UpdatedSecret {
+            name: String,
+        }
+
+        let updated: Vec<UpdatedSecret> = db()
+            .await?
+            .query("UPDATE secret SET node_id = $new_node_id WHERE node_id = $old_node_id RETURN AFTER")
+            .bind(("old_node_id", old_node_id.to_string()))
+            .bind(("new_node_id", new_node_id.to_string()))
+            .await?
+            .take(0)
+            .context("Failed to retarget secrets")?;
+
+        Ok(updated.into_iter().map(|secret| secret.name).collect())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use crate::network::handover::KeyHandover;
+
+    fn handover(old: &iroh::SecretKey, new: NodeId) -> SignedHandover {
+        SignedHandover::sign(
+            old,
+            KeyHandover {
+                old_node_id: old.public(),
+                new_node_id: new,
+                age_public_key: age::x25519::Identity::generate().to_public().to_string(),
+                rotated_at: Utc::now(),
+            },
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_lineage_follows_handovers() {
+        let first = iroh::SecretKey::generate(rand::rngs::OsRng);
+        let second = iroh::SecretKey::generate(rand::rngs::OsRng);
+        let third = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+
+        assert!(
+            KeyHandoverRecord::store(&handover(&first, second.public()))
+                .await
+                .unwrap()
+        );
+        assert!(
+            !KeyHandoverRecord::store(&handover(&first, second.public()))
+                .await
+                .unwrap()
+        );
+        assert!(
+            KeyHandoverRecord::store(&handover(&second, third))
+                .await
+                .unwrap()
+        );
+
+        assert_eq!(
+            KeyHandoverRecord::lineage(third).await.unwrap(),
+            vec![third, second.public(), first.public()]
+        );
+        assert_eq!(
+            KeyHandoverRecord::successor(first.public()).await.unwrap(),
+            Some(second.public())
+        );
+        assert_eq!(KeyHandoverRecord::successor(third).await.unwrap(), None);
+
+        // Rotating only the age key keeps the node ID
+        assert!(
+            !KeyHandoverRecord::store(&handover(&first, first.public()))
+                .await
+                .unwrap()
+        );
+    }
+}
@@ -1,15 +1,27 @@
+use std::io::{Read, Write};
+
+use age::secrecy::SecretString;
 use age::x25519::Identity as AgeIdentity;
-use anyhow::{Result, anyhow};
+use anyhow::{Context, Result, anyhow, bail};
 use iroh::{NodeId, SecretKey};
 use rand::rngs;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use tracing::debug;
 
-use crate::db::AuditEvent;
+use crate::db::{AuditEvent, SealedIdentity};
 
 use super::db;
 
+/// Contents of an identity backup, before it is encrypted
+#[derive(Serialize, Deserialize)]
+struct IdentityBackup {
+    /// Hex encoded iroh secret key
+    secret_key: String,
+    #[serde(with = "crate::custom_serde::age_identity_serde")]
+    age_key: AgeIdentity,
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Identity {
     pub secret_key: SecretKey,
@@ -17,15 +29,24 @@ pub struct Identity {
     pub age_key: AgeIdentity,
 }
 
+/// Only shows the public half, so the keys never end up in logs
+impl std::fmt::Debug for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Identity")
+            .field("node_id", &self.id())
+            .field("age_public_key", &self.age_key.to_public().to_string())
+            .finish_non_exhaustive()
+    }
+}
+
 impl Identity {
     pub fn id(&self) -> NodeId {
         self.secret_key.public()
     }
 
     #[cfg(test)]
+    #[allow(dead_code)]
     pub fn from_string(secret_key_str: &str, age_key_str: &str) -> Result<Identity> {
-        use anyhow::Context;
-
         let secret_key = secret_key_str
             .parse::<SecretKey>()
             .context("Failed to parse SecretKey from string")?;
@@ -39,9 +60,91 @@ impl Identity {
         })
     }
 
-    pub async fn get() -> Result<Identity> {
-        db().await?
+    /// Encrypt the identity into an age file protected by a passphrase
+    pub fn export(&self, passphrase: SecretString) -> Result<Vec<u8>> {
+        self.export_with_recipient(age::scrypt::Recipient::new(passphrase))
+    }
+
+    fn export_with_recipient(&self, recipient: age::scrypt::Recipient) -> Result<Vec<u8>> {
+        let plaintext = self.to_backup_bytes()?;
+
+        let encryptor = age::Encryptor::with_recipients(std::iter::once(&recipient as _))
+            .context("Failed to set up identity encryption")?;
+        let mut encrypted = Vec::new();
+        let mut writer = encryptor.wrap_output(&mut encrypted)?;
+        writer.write_all(&plaintext)?;
+        writer.finish()?;
+
+        Ok(encrypted)
+    }
+
+    /// Decrypt an identity exported with [`Identity::export`]
+    pub fn import(encrypted: &[u8], passphrase: SecretString) -> Result<Identity> {
+        let decryptor = age::Decryptor::new(encrypted).context("Not an identity backup")?;
+        if !decryptor.is_scrypt() {
+            bail!("Identity backup is not protected by a passphrase");
+        }
+
+        let identity = age::scrypt::Identity::new(passphrase);
+        let mut reader = decryptor
+            .decrypt(std::iter::once(&identity as _))
+            .context("Failed to decrypt identity backup, is the passphrase correct?")?;
+        let mut plaintext = Vec::new();
+        reader.read_to_end(&mut plaintext)?;
+
+        Self::from_backup_bytes(&plaintext)
+    }
+
+    /// The unencrypted backup format, shared with [`SealedIdentity`]
+    ///
+    /// [`SealedIdentity`]: crate::db::SealedIdentity
+    pub(super) fn to_backup_bytes(&self) -> Result<Vec<u8>> {
+        let backup = IdentityBackup {
+            secret_key: hex::encode(self.secret_key.to_bytes()),
+            age_key: self.age_key.clone(),
+        };
+        Ok(serde_json::to_vec(&backup)?)
+    }
+
+    pub(super) fn from_backup_bytes(plaintext: &[u8]) -> Result<Identity> {
+        let backup: IdentityBackup =
+            serde_json::from_slice(plaintext).context("Identity backup is corrupt")?;
+        let secret_key: [u8; 32] = hex::decode(&backup.secret_key)
+            .context("Identity backup has an invalid secret key")?
+            .try_into()
+            .map_err(|_| anyhow!("Identity backup has an invalid secret key"))?;
+
+        Ok(Identity {
+            secret_key: SecretKey::from_bytes(&secret_key),
+            age_key: backup.age_key,
+        })
+    }
+
+    /// Our identity, or `None` if one has not been generated yet
+    ///
+    /// A sealed identity is unsealed the first time it is needed, see [`SealedIdentity`].
+    pub async fn find() -> Result<Option<Identity>> {
+        if let Some(identity) = SealedIdentity::unsealed().await {
+            return Ok(Some(identity));
+        }
+
+        let identity: Option<Identity> = db()
+            .await?
             .select(("identity", "self"))
+            .await
+            .context("Failed to get identity")?;
+        if identity.is_some() {
+            return Ok(identity);
+        }
+
+        match SealedIdentity::get().await? {
+            Some(sealed) => Ok(Some(sealed.unseal(None).await?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn get() -> Result<Identity> {
+        Self::find()
             .await?
             .ok_or(anyhow!("Please have an identity crisis"))
     }
@@ -72,11 +175,88 @@ impl Identity {
             .ok_or(anyhow!("Failed to create identity self"))
     }
 
+    /// Store our identity unsealed, used when a sealed identity is unsealed for good
+    pub(super) async fn store_plain(identity: &Identity) -> Result<()> {
+        let _: Option<Identity> = db()
+            .await?
+            .upsert(("identity", "self"))
+            .content(identity.clone())
+            .await
+            .context("Failed to store identity")?;
+        Ok(())
+    }
+
+    /// Remove the unsealed copy of our identity, used once it has been sealed
+    pub(super) async fn delete_plain() -> Result<()> {
+        let _: Option<Identity> = db()
+            .await?
+            .delete(("identity", "self"))
+            .await
+            .context("Failed to delete unsealed identity")?;
+        Ok(())
+    }
+
+    /// Overwrite our identity, used when rotating keys
+    ///
+    /// A sealed identity stays sealed with the same method.
+    pub async fn replace(identity: Identity) -> Result<Identity> {
+        if SealedIdentity::get().await?.is_some() {
+            SealedIdentity::reseal(&identity).await?;
+            return Ok(identity);
+        }
+
+        db().await?
+            .upsert(("identity", "self"))
+            .content(identity)
+            .await?
+            .ok_or(anyhow!("Failed to replace identity self"))
+    }
+
     pub async fn get_or_generate() -> Result<Identity> {
-        let ident: Option<Identity> = db().await?.select(("identity", "self")).await?;
-        Ok(match ident {
+        Ok(match Self::find().await? {
             Some(identity) => identity,
             None => Self::generate_and_create().await?,
         })
     }
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+
+    fn recipient(passphrase: &str) -> age::scrypt::Recipient {
+        let mut recipient = age::scrypt::Recipient::new(SecretString::from(passphrase.to_owned()));
+        // Keep the test fast, the default work factor takes around a second
+        recipient.set_work_factor(10);
+        recipient
+    }
+
+    #[test]
+    fn test_export_and_import_round_trip() {
+        let identity = Identity::generate();
+        let exported = identity
+            .export_with_recipient(recipient("correct horse"))
+            .unwrap();
+
+        let imported =
+            Identity::import(&exported, SecretString::from("correct horse".to_owned())).unwrap();
+        assert_eq!(imported.id(), identity.id());
+        assert_eq!(
+            imported.age_key.to_public().to_string(),
+            identity.age_key.to_public().to_string()
+        );
+    }
+
+    #[test]
+    fn test_import_fails_with_wrong_passphrase() {
+        let exported = Identity::generate()
+            .export_with_recipient(recipient("correct horse"))
+            .unwrap();
+
+        assert!(
+            Identity::import(&exported, SecretString::from("battery staple".to_owned())).is_err()
+        );
+        assert!(Identity::import(b"not a backup", SecretString::from("x".to_owned())).is_err());
+    }
+}
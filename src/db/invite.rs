@@ -0,0 +1,128 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use iroh::NodeId;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::db;
+use crate::network::invite::SignedInvite;
+
+/// An invite token we have issued or seen redeemed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Invite {
+    pub invite_id: String,
+    #[serde(with = "crate::custom_serde::node_id_serde")]
+    pub issuer: NodeId,
+    pub labels: Vec<String>,
+    #[serde(with = "crate::custom_serde::chrono_datetime_as_sql")]
+    pub expires_at: DateTime<Utc>,
+    #[serde(
+        with = "crate::custom_serde::optional_node_id_serde",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub consumed_by: Option<NodeId>,
+    #[serde(
+        with = "crate::custom_serde::optional_chrono_datetime_as_sql",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub consumed_at: Option<DateTime<Utc>>,
+}
+
+impl From<&SignedInvite> for Invite {
+    fn from(invite: &SignedInvite) -> Self {
+        Self {
+            invite_id: invite.token.id.to_string(),
+            issuer: invite.issuer,
+            labels: invite.token.labels.clone(),
+            expires_at: invite.token.expires_at,
+            consumed_by: None,
+            consumed_at: None,
+        }
+    }
+}
+
+impl Invite {
+    pub async fn create(invite: &SignedInvite) -> Result<Option<Invite>> {
+        let record: Invite = invite.into();
+        db().await?
+            .create(("invite", record.invite_id.clone()))
+            .content(record)
+            .await
+            .context("Failed to create invite")
+    }
+
+    pub async fn get(invite_id: Uuid) -> Result<Option<Invite>> {
+        db().await?
+            .select(("invite", invite_id.to_string()))
+            .await
+            .context("Failed to get invite")
+    }
+
+    pub async fn list() -> Result<Vec<Invite>> {
+        db().await?
+            .query("SELECT * FROM invite ORDER BY expires_at ASC")
+            .await?
+            .take(0)
+            .context("Failed to list invites")
+    }
+
+    /// Whether `node_id` is known to have redeemed the invite
+    pub async fn is_redeemed_by(invite_id: Uuid, node_id: NodeId) -> Result<bool> {
+        Ok(Self::get(invite_id)
+            .await?
+            .is_some_and(|invite| invite.consumed_by == Some(node_id)))
+    }
+
+    /// Record that a node redeemed the invite, creating the record if we never saw it issued
+    pub async fn mark_consumed(invite: &SignedInvite, node_id: NodeId) -> Result<Option<Invite>> {
+        let mut record: Invite = invite.into();
+        record.consumed_by = Some(node_id);
+        record.consumed_at = Some(Utc::now());
+
+        db().await?
+            .upsert(("invite", record.invite_id.clone()))
+            .content(record)
+            .await
+            .context("Failed to mark invite as consumed")
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use crate::network::invite::InviteToken;
+
+    #[tokio::test]
+    async fn test_mark_consumed() {
+        let issuer = iroh::SecretKey::generate(rand::rngs::OsRng);
+        let redeemer = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        let network = iroh_gossip::proto::TopicId::from_bytes([1; 32]);
+        let token = InviteToken::new(
+            network,
+            vec![],
+            vec!["web".to_string()],
+            Utc::now() + chrono::Duration::hours(1),
+        );
+        let invite = SignedInvite::parse(&token.sign(&issuer).unwrap(), network).unwrap();
+
+        Invite::create(&invite).await.unwrap();
+        let created = Invite::get(token.id).await.unwrap().unwrap();
+        assert_eq!(created.issuer, issuer.public());
+        assert_eq!(created.consumed_by, None);
+
+        Invite::mark_consumed(&invite, redeemer).await.unwrap();
+        let consumed = Invite::get(token.id).await.unwrap().unwrap();
+        assert_eq!(consumed.consumed_by, Some(redeemer));
+        assert!(consumed.consumed_at.is_some());
+        assert_eq!(consumed.labels, vec!["web".to_string()]);
+        assert!(Invite::is_redeemed_by(token.id, redeemer).await.unwrap());
+        assert!(
+            !Invite::is_redeemed_by(token.id, issuer.public())
+                .await
+                .unwrap()
+        );
+    }
+}
@@ -1,22 +1,52 @@
+use std::sync::OnceLock;
+
 use anyhow::Result;
 use surrealdb::Surreal;
-use surrealdb::engine::local::{Db, SurrealKv};
+use surrealdb::engine::local::Db;
 use tokio::sync::OnceCell;
 
 pub mod audit_event;
+pub mod handover;
 pub mod identity;
+pub mod invite;
+pub mod outbox;
 pub mod peer;
+pub mod policy;
+pub mod proposal;
+pub mod replica;
+pub mod revocation;
+pub mod sealed_identity;
 pub mod secret;
+pub mod sender_status;
 
 pub use audit_event::AuditEvent;
+pub use handover::KeyHandoverRecord;
 pub use identity::Identity;
-pub use peer::{Peer, PeerExt};
+pub use invite::Invite;
+pub use outbox::OutboxMessage;
+pub use peer::{Peer, PeerAdmission, PeerExt};
+pub use policy::PolicyRecord;
+pub use proposal::{ApprovalRecord, ProposalRecord, ProposalStatus};
+pub use replica::{ReplicaEntry, ReplicaRecord, ReplicaSlot};
+pub use revocation::RevocationRecord;
+pub use sealed_identity::{SealedIdentity, Sealer};
+pub use secret::SecretEntry;
+pub use sender_status::SenderStatus;
 use tracing::{debug, trace};
 
-use crate::args;
-
+#[cfg(not(test))]
 static DATABASE: OnceCell<Surreal<Db>> = OnceCell::const_new();
 
+/// Where the database is opened, set once the config has been resolved
+static DATABASE_PATH: OnceLock<String> = OnceLock::new();
+
+/// Set the path the database is opened at, must be called before the first [`db`] call
+pub fn set_path(path: String) -> Result<()> {
+    DATABASE_PATH
+        .set(path)
+        .map_err(|_| anyhow::anyhow!("Database path was already set"))
+}
+
 async fn initialize_schema(db: &Surreal<Db>) -> Result<()> {
     debug!("Initializing database schema");
     let schema_sql = include_str!("../../schema.surql");
@@ -27,10 +57,13 @@ async fn initialize_schema(db: &Surreal<Db>) -> Result<()> {
 
 #[cfg(not(test))]
 pub async fn db() -> Result<&'static Surreal<Db>> {
+    use anyhow::Context;
+    use surrealdb::engine::local::SurrealKv;
+
     DATABASE
         .get_or_try_init(|| async {
-            let args = args::args().await;
-            let db = Surreal::new::<SurrealKv>(args.db_path.clone()).await?;
+            let path = DATABASE_PATH.get().context("Database path was not set")?;
+            let db = Surreal::new::<SurrealKv>(path.clone()).await?;
 
             // TODO: handle better selecting of the NS/DB
             db.use_ns("prod").use_db("prod").await?;
@@ -48,19 +81,37 @@ pub async fn db() -> Result<&'static Surreal<Db>> {
 #[cfg(test)]
 static TEST_DATABASE: OnceCell<Surreal<Db>> = OnceCell::const_new();
 
+/// Runtime the test database lives on
+///
+/// Every `#[tokio::test]` gets its own runtime, so the database's background tasks have to be
+/// spawned somewhere that outlives any single test.
+#[cfg(test)]
+static TEST_RUNTIME: std::sync::LazyLock<tokio::runtime::Runtime> =
+    std::sync::LazyLock::new(|| {
+        #[allow(clippy::expect_used)]
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .expect("Failed to build test database runtime")
+    });
+
 #[cfg(test)]
 pub async fn db() -> Result<&'static Surreal<Db>> {
     use surrealdb::engine::local::Mem;
 
     TEST_DATABASE
         .get_or_try_init(|| async {
-            let db = Surreal::new::<Mem>(()).await?;
-            db.use_ns("test").use_db("test").await?;
+            TEST_RUNTIME
+                .spawn(async {
+                    let db = Surreal::new::<Mem>(()).await?;
+                    db.use_ns("test").use_db("test").await?;
 
-            // Initialize schema for test database too
-            initialize_schema(&db).await?;
+                    // Initialize schema for test database too
+                    initialize_schema(&db).await?;
 
-            Ok(db)
+                    Ok(db)
+                })
+                .await?
         })
         .await
 }
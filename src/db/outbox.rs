@@ -0,0 +1,108 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use surrealdb::RecordId;
+
+use super::db;
+use crate::actors::gossip::GossipMessage;
+
+/// A gossip message waiting to be broadcast by the server
+///
+/// CLI commands cannot talk to the gossip network themselves, so anything they need to publish is
+/// queued here and sent once the server has a neighbor to send it to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxMessage {
+    #[serde(skip_serializing)]
+    pub id: Option<RecordId>,
+    #[serde(with = "crate::custom_serde::bytes_as_sql")]
+    pub message: Vec<u8>,
+    #[serde(with = "crate::custom_serde::chrono_datetime_as_sql")]
+    pub created_at: DateTime<Utc>,
+}
+
+impl OutboxMessage {
+    /// Queue a message, unless the same message is already waiting
+    pub async fn queue(message: &GossipMessage) -> Result<()> {
+        let message = postcard::to_stdvec(message).context("Failed to encode outbox message")?;
+        if Self::list()
+            .await?
+            .iter()
+            .any(|queued| queued.message == message)
+        {
+            return Ok(());
+        }
+
+        let entry = OutboxMessage {
+            id: None,
+            message,
+            created_at: Utc::now(),
+        };
+
+        let _: Option<OutboxMessage> = db()
+            .await?
+            .create("outbox")
+            .content(entry)
+            .await
+            .context("Failed to queue outbox message")?;
+        Ok(())
+    }
+
+    pub async fn list() -> Result<Vec<Self>> {
+        db().await?
+            .query("SELECT * FROM outbox ORDER BY created_at ASC")
+            .await?
+            .take(0)
+            .context("Failed to list outbox messages")
+    }
+
+    pub fn decode(&self) -> Result<GossipMessage> {
+        postcard::from_bytes(&self.message).context("Failed to decode outbox message")
+    }
+
+    pub async fn delete(self) -> Result<()> {
+        if let Some(id) = self.id {
+            let _: Option<OutboxMessage> = db()
+                .await?
+                .delete(id)
+                .await
+                .context("Failed to delete outbox message")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_queue_and_delete() {
+        let node_id = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        let message = GossipMessage::Admission {
+            node_id,
+            approved_at: Utc::now(),
+        };
+
+        OutboxMessage::queue(&message).await.unwrap();
+        OutboxMessage::queue(&message).await.unwrap();
+
+        let mut queued: Vec<OutboxMessage> = OutboxMessage::list()
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|queued| {
+                matches!(queued.decode(), Ok(GossipMessage::Admission { node_id: id, .. }) if id == node_id)
+            })
+            .collect();
+        assert_eq!(queued.len(), 1, "the same message is only queued once");
+        let queued = queued.pop().unwrap();
+
+        queued.delete().await.unwrap();
+
+        let remaining = OutboxMessage::list().await.unwrap();
+        assert!(remaining.iter().all(|queued| {
+            !matches!(queued.decode(), Ok(GossipMessage::Admission { node_id: id, .. }) if id == node_id)
+        }));
+    }
+}
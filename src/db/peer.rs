@@ -1,11 +1,39 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
 use age::x25519::Recipient as AgeRecipient;
 use anyhow::{Context, Result, anyhow};
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeDelta, Utc};
 use iroh::{NodeAddr, NodeId};
 use iroh_base::ticket::NodeTicket;
 use serde::{Deserialize, Serialize};
 
-use super::db;
+use super::{RevocationRecord, SenderStatus, db};
+use crate::actors::gossip::capabilities::Capabilities;
+use crate::network::liveness::Liveness;
+use crate::network::metadata::{NodeMetadata, is_valid_hostname};
+
+/// Whether a peer has been allowed into the network
+///
+/// Peers we only know about because they connected to us start out pending, and everything they
+/// send apart from their introduction is ignored until they are approved.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PeerAdmission {
+    #[default]
+    Pending,
+    Approved,
+}
+
+impl std::fmt::Display for PeerAdmission {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerAdmission::Pending => f.write_str("pending"),
+            PeerAdmission::Approved => f.write_str("approved"),
+        }
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Peer {
@@ -22,6 +50,35 @@ pub struct Peer {
     pub last_seen: Option<DateTime<Utc>>,
     #[serde(with = "crate::custom_serde::age_recipient_serde", default)]
     pub age_public_key: Option<AgeRecipient>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol_version: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<Capabilities>,
+    #[serde(default)]
+    pub admission: PeerAdmission,
+    #[serde(default)]
+    pub labels: Vec<String>,
+    /// How we last reached the peer, such as `direct(192.0.2.1:4433)` or `relay(...)`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connection: Option<String>,
+    /// What the peer says about itself, see [`NodeMetadata`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<NodeMetadata>,
+    /// Whether the peer is up, `None` until we have heard a heartbeat from it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub liveness: Option<Liveness>,
+    #[serde(
+        with = "crate::custom_serde::optional_chrono_datetime_as_sql",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub liveness_changed_at: Option<DateTime<Utc>>,
+    /// Most recent round trip times in milliseconds, oldest first, see [`RTT_WINDOW`]
+    #[serde(default)]
+    pub rtt_ms: Vec<f64>,
+    /// How far the peer's clock is ahead of ours in milliseconds, negative if it is behind
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clock_offset_ms: Option<i64>,
 }
 
 impl From<NodeTicket> for Peer {
@@ -32,10 +89,31 @@ impl From<NodeTicket> for Peer {
             hostname: None,
             last_seen: None,
             age_public_key: None,
+            protocol_version: None,
+            capabilities: None,
+            admission: PeerAdmission::Pending,
+            labels: Vec::new(),
+            connection: None,
+            metadata: None,
+            liveness: None,
+            liveness_changed_at: None,
+            rtt_ms: Vec::new(),
+            clock_offset_ms: None,
         }
     }
 }
 
+/// How many round trip times are kept for each peer
+pub const RTT_WINDOW: usize = 10;
+
+/// How recently a peer must have been seen to hold back messages it does not support, see
+/// [`Peer::lacking_capabilities`]
+pub const CAPABILITY_WINDOW: TimeDelta = TimeDelta::minutes(15);
+
+/// `last_seen` times not written to the database yet, see [`Peer::flush_last_seen`]
+static PENDING_LAST_SEEN: LazyLock<Mutex<HashMap<NodeId, DateTime<Utc>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
 pub trait PeerExt<Peer> {
     fn to_node_ids(self) -> Vec<NodeId>;
 }
@@ -48,6 +126,7 @@ impl PeerExt<Peer> for Vec<Peer> {
 
 impl Peer {
     #[cfg(test)]
+    #[allow(dead_code)]
     pub fn from_string(node_id_str: &str, age_public_key_str: &str) -> Result<Peer> {
         let node_id = node_id_str
             .parse::<NodeId>()
@@ -64,25 +143,105 @@ impl Peer {
             hostname: None,
             last_seen: None,
             age_public_key: Some(age_public_key),
+            protocol_version: None,
+            capabilities: None,
+            admission: PeerAdmission::Pending,
+            labels: Vec::new(),
+            connection: None,
+            metadata: None,
+            liveness: None,
+            liveness_changed_at: None,
+            rtt_ms: Vec::new(),
+            clock_offset_ms: None,
         })
     }
 
     pub async fn insert_from_ticket(ticket: NodeTicket) -> Result<Option<Peer>> {
         let peer: Peer = ticket.into();
-        db().await?
+        let peer = db()
+            .await?
             .upsert(("peer", peer.node_id.to_string()))
             .content::<Peer>(peer)
             .await
-            .context("Failed to insert peer")
+            .context("Failed to insert peer")?;
+        SenderStatus::invalidate();
+        Ok(peer)
     }
 
     pub async fn insert_from_node_id(node_id: NodeId) -> Result<Option<Peer>> {
-        // Create a new mostly empty ticket for the peer
-        // TODO: can we fill in this data later?
+        // Create a new mostly empty ticket for the peer, the addresses are filled in by
+        // `update_addresses` once we are connected to it
         let ticket = NodeTicket::new(NodeAddr::new(node_id));
         Self::insert_from_ticket(ticket).await
     }
 
+    /// Add a peer the operator has vouched for, such as a bootstrap ticket
+    ///
+    /// Revoked peers are never added back, `None` is returned instead.
+    pub async fn insert_approved_from_ticket(ticket: NodeTicket) -> Result<Option<Peer>> {
+        let node_id = ticket.node_addr().node_id;
+        if RevocationRecord::is_revoked(node_id).await? {
+            return Ok(None);
+        }
+        Self::insert_from_ticket(ticket).await?;
+        Self::set_admission(node_id, PeerAdmission::Approved).await
+    }
+
+    pub async fn set_admission(node_id: NodeId, admission: PeerAdmission) -> Result<Option<Peer>> {
+        #[derive(serde::Serialize)]
+        struct UpdateAdmission {
+            admission: PeerAdmission,
+        }
+
+        let peer = db()
+            .await?
+            .update(("peer", node_id.to_string()))
+            .merge(UpdateAdmission { admission })
+            .await
+            .context("Failed to update peer admission")?;
+        SenderStatus::invalidate();
+        Ok(peer)
+    }
+
+    pub async fn is_admitted(node_id: NodeId) -> Result<bool> {
+        let peer: Option<Peer> = db()
+            .await?
+            .select(("peer", node_id.to_string()))
+            .await
+            .context("Failed to check if peer is admitted")?;
+
+        Ok(peer.is_some_and(|peer| peer.admission == PeerAdmission::Approved))
+    }
+
+    /// Add labels to a peer, keeping any it already has
+    pub async fn add_labels(node_id: NodeId, labels: &[String]) -> Result<Option<Peer>> {
+        let Some(peer) = Self::get(node_id).await? else {
+            return Ok(None);
+        };
+
+        let mut merged = peer.labels;
+        for label in labels {
+            if !merged.contains(label) {
+                merged.push(label.clone());
+            }
+        }
+
+        Self::set_labels(node_id, merged).await
+    }
+
+    pub async fn set_labels(node_id: NodeId, labels: Vec<String>) -> Result<Option<Peer>> {
+        #[derive(serde::Serialize)]
+        struct UpdateLabels {
+            labels: Vec<String>,
+        }
+
+        db().await?
+            .update(("peer", node_id.to_string()))
+            .merge(UpdateLabels { labels })
+            .await
+            .context("Failed to update peer labels")
+    }
+
     pub async fn list() -> Result<Vec<Peer>> {
         let database = db().await?;
         let result = database
@@ -92,12 +251,12 @@ impl Peer {
         Ok(result)
     }
 
-    // pub async fn get(node_id: NodeId) -> Result<Peer> {
-    //     db().await?
-    //         .select::<Option<Peer>>(("peer", node_id.to_string()))
-    //         .await?
-    //         .context("Could not find peer")
-    // }
+    pub async fn get(node_id: NodeId) -> Result<Option<Peer>> {
+        db().await?
+            .select(("peer", node_id.to_string()))
+            .await
+            .context("Failed to get peer")
+    }
 
     pub async fn count() -> Result<usize> {
         #[derive(serde::Deserialize)]
@@ -115,23 +274,65 @@ impl Peer {
         Ok(result.total)
     }
 
-    pub async fn bump_last_seen(node_id: NodeId) -> Result<()> {
+    /// Note that we just heard from a peer
+    ///
+    /// This only happens in memory, the times are written in one batch by
+    /// [`Peer::flush_last_seen`] so busy peers do not cost a write per message.
+    pub fn touch(node_id: NodeId) {
+        if let Ok(mut pending) = PENDING_LAST_SEEN.lock() {
+            pending.insert(node_id, Utc::now());
+        }
+    }
+
+    /// Write every `last_seen` time noted by [`Peer::touch`], returns how many were written
+    ///
+    /// Peers we do not have a record of are skipped.
+    pub async fn flush_last_seen() -> Result<usize> {
         #[derive(serde::Serialize)]
-        struct UpdateLastSeen {
-            #[serde(with = "crate::custom_serde::chrono_datetime_as_sql")]
-            last_seen: DateTime<Utc>,
+        struct LastSeen {
+            node_id: String,
+            last_seen: String,
         }
 
-        let _peer: Option<Peer> = db()
-            .await?
-            .update(("peer", node_id.to_string()))
-            .merge(UpdateLastSeen {
-                last_seen: Utc::now(),
+        let pending = match PENDING_LAST_SEEN.lock() {
+            Ok(mut pending) => std::mem::take(&mut *pending),
+            Err(_) => return Err(anyhow!("Pending last_seen times are poisoned")),
+        };
+        if pending.is_empty() {
+            return Ok(0);
+        }
+
+        let entries: Vec<LastSeen> = pending
+            .iter()
+            .map(|(node_id, last_seen)| LastSeen {
+                node_id: node_id.to_string(),
+                last_seen: last_seen.to_rfc3339(),
             })
-            .await
-            .unwrap_or(None);
+            .collect();
 
-        Ok(())
+        db().await?
+            .query(
+                "FOR $entry IN $entries {
+                    UPDATE type::thing('peer', $entry.node_id)
+                        SET last_seen = <datetime> $entry.last_seen;
+                }",
+            )
+            .bind(("entries", entries))
+            .await?
+            .check()
+            .context("Failed to write last_seen times")?;
+
+        Ok(pending.len())
+    }
+
+    pub async fn delete(node_id: NodeId) -> Result<Option<Peer>> {
+        let peer = db()
+            .await?
+            .delete(("peer", node_id.to_string()))
+            .await
+            .context("Failed to delete peer")?;
+        SenderStatus::invalidate();
+        Ok(peer)
     }
 
     pub async fn is_known(node_id: NodeId) -> Result<bool> {
@@ -153,9 +354,12 @@ impl Peer {
         let age_public_key = age_public_key_str
             .parse::<AgeRecipient>()
             .map_err(|e| anyhow!("Failed to parse Age Recipient from string: {e}"))?;
+        let hostname = hostname.filter(|hostname| is_valid_hostname(hostname));
 
         #[derive(serde::Serialize)]
         struct UpdateIntroduction {
+            #[serde(with = "crate::custom_serde::node_id_serde")]
+            node_id: NodeId,
             #[serde(with = "crate::custom_serde::node_ticket_serde")]
             ticket: NodeTicket,
             hostname: Option<String>,
@@ -163,10 +367,12 @@ impl Peer {
             age_public_key: Option<AgeRecipient>,
         }
 
+        // Upsert so introductions from nodes we are not yet connected to show up as pending
         let peer: Option<Peer> = db()
             .await?
-            .update(("peer", node_id.to_string()))
+            .upsert(("peer", node_id.to_string()))
             .merge(UpdateIntroduction {
+                node_id,
                 ticket,
                 hostname,
                 age_public_key: Some(age_public_key),
@@ -177,6 +383,232 @@ impl Peer {
         Ok(peer)
     }
 
+    /// Store the metadata a peer published about itself, its hostname replaces the introduced one
+    pub async fn update_metadata(node_id: NodeId, metadata: NodeMetadata) -> Result<Option<Peer>> {
+        #[derive(serde::Serialize)]
+        struct UpdateMetadata {
+            hostname: Option<String>,
+            metadata: NodeMetadata,
+        }
+
+        let metadata = metadata.sanitize();
+        db().await?
+            .update(("peer", node_id.to_string()))
+            .merge(UpdateMetadata {
+                hostname: metadata.hostname.clone(),
+                metadata,
+            })
+            .await
+            .context("Failed to update peer metadata")
+    }
+
+    /// Replace the addresses in a known peer's ticket with ones learned from a live connection
+    pub async fn update_addresses(node_addr: NodeAddr, connection: String) -> Result<Option<Peer>> {
+        #[derive(serde::Serialize)]
+        struct UpdateAddresses {
+            #[serde(with = "crate::custom_serde::node_ticket_serde")]
+            ticket: NodeTicket,
+            connection: String,
+        }
+
+        let node_id = node_addr.node_id;
+        db().await?
+            .update(("peer", node_id.to_string()))
+            .merge(UpdateAddresses {
+                ticket: NodeTicket::new(node_addr),
+                connection,
+            })
+            .await
+            .context("Failed to update peer addresses")
+    }
+
+    pub async fn set_liveness(node_id: NodeId, liveness: Liveness) -> Result<Option<Peer>> {
+        #[derive(serde::Serialize)]
+        struct UpdateLiveness {
+            liveness: Liveness,
+            #[serde(with = "crate::custom_serde::chrono_datetime_as_sql")]
+            liveness_changed_at: DateTime<Utc>,
+        }
+
+        db().await?
+            .update(("peer", node_id.to_string()))
+            .merge(UpdateLiveness {
+                liveness,
+                liveness_changed_at: Utc::now(),
+            })
+            .await
+            .context("Failed to update peer liveness")
+    }
+
+    /// Add a round trip time to the peer's window, dropping the oldest once it is full
+    pub async fn record_rtt(node_id: NodeId, rtt: Duration) -> Result<Option<Peer>> {
+        #[derive(serde::Serialize)]
+        struct UpdateRtt {
+            rtt_ms: Vec<f64>,
+        }
+
+        let Some(peer) = Self::get(node_id).await? else {
+            return Ok(None);
+        };
+
+        let mut rtt_ms = peer.rtt_ms;
+        rtt_ms.push(rtt.as_secs_f64() * 1000.0);
+        let excess = rtt_ms.len().saturating_sub(RTT_WINDOW);
+        rtt_ms.drain(..excess);
+
+        db().await?
+            .update(("peer", node_id.to_string()))
+            .merge(UpdateRtt { rtt_ms })
+            .await
+            .context("Failed to record peer round trip time")
+    }
+
+    pub async fn set_clock_offset(node_id: NodeId, offset: TimeDelta) -> Result<Option<Peer>> {
+        #[derive(serde::Serialize)]
+        struct UpdateClockOffset {
+            clock_offset_ms: i64,
+        }
+
+        db().await?
+            .update(("peer", node_id.to_string()))
+            .merge(UpdateClockOffset {
+                clock_offset_ms: offset.num_milliseconds(),
+            })
+            .await
+            .context("Failed to update peer clock offset")
+    }
+
+    /// The most recent round trip time measured to the peer
+    pub fn last_rtt(&self) -> Option<Duration> {
+        self.rtt_ms
+            .last()
+            .map(|ms| Duration::from_secs_f64(ms.max(0.0) / 1000.0))
+    }
+
+    /// Other peers that claim the same hostname as `node_id`
+    pub async fn sharing_hostname(node_id: NodeId, hostname: &str) -> Result<Vec<NodeId>> {
+        Ok(Self::list()
+            .await?
+            .into_iter()
+            .filter(|peer| peer.node_id != node_id && peer.hostname.as_deref() == Some(hostname))
+            .map(|peer| peer.node_id)
+            .collect())
+    }
+
+    /// Hostnames claimed by more than one node ID, along with the node IDs claiming them
+    pub fn duplicate_hostnames(peers: &[Peer]) -> BTreeMap<String, Vec<NodeId>> {
+        let mut hostnames: BTreeMap<String, Vec<NodeId>> = BTreeMap::new();
+        for peer in peers {
+            if let Some(hostname) = &peer.hostname {
+                hostnames
+                    .entry(hostname.clone())
+                    .or_default()
+                    .push(peer.node_id);
+            }
+        }
+        hostnames.retain(|_, node_ids| node_ids.len() > 1);
+        hostnames
+    }
+
+    /// Move a peer that rotated its keys over to its new node ID
+    ///
+    /// Admission, labels and everything else we know carry over. The addresses come from the new
+    /// node ID's introduction if we already had one, otherwise the old addresses are reused.
+    /// Returns `None` if we never knew the old node.
+    pub async fn migrate(
+        old_node_id: NodeId,
+        new_node_id: NodeId,
+        age_public_key: AgeRecipient,
+    ) -> Result<Option<Peer>> {
+        let Some(old) = Self::get(old_node_id).await? else {
+            return Ok(None);
+        };
+        let introduced = if old_node_id == new_node_id {
+            None
+        } else {
+            Self::get(new_node_id).await?
+        };
+
+        let mut node_addr = old.ticket.node_addr().clone();
+        node_addr.node_id = new_node_id;
+        let peer = Peer {
+            node_id: new_node_id,
+            ticket: introduced
+                .as_ref()
+                .map_or_else(|| NodeTicket::new(node_addr), |peer| peer.ticket.clone()),
+            hostname: introduced
+                .as_ref()
+                .and_then(|peer| peer.hostname.clone())
+                .or(old.hostname),
+            last_seen: introduced
+                .as_ref()
+                .and_then(|peer| peer.last_seen)
+                .or(old.last_seen),
+            age_public_key: Some(age_public_key),
+            ..old
+        };
+
+        let migrated: Option<Peer> = db()
+            .await?
+            .upsert(("peer", new_node_id.to_string()))
+            .content(peer)
+            .await
+            .context("Failed to migrate peer")?;
+        if old_node_id != new_node_id {
+            Self::delete(old_node_id).await?;
+        }
+        SenderStatus::invalidate();
+
+        Ok(migrated)
+    }
+
+    pub async fn update_protocol(
+        node_id: NodeId,
+        protocol_version: u16,
+        capabilities: Capabilities,
+    ) -> Result<()> {
+        #[derive(serde::Serialize)]
+        struct UpdateProtocol {
+            protocol_version: u16,
+            capabilities: Capabilities,
+        }
+
+        let _peer: Option<Peer> = db()
+            .await?
+            .update(("peer", node_id.to_string()))
+            .merge(UpdateProtocol {
+                protocol_version,
+                capabilities,
+            })
+            .await
+            .context("Failed to update peer protocol version")?;
+
+        Ok(())
+    }
+
+    /// Approved peers seen recently that have not advertised all of the given capabilities
+    ///
+    /// Peers we have not heard from within [`CAPABILITY_WINDOW`] are left out, so one that is
+    /// offline does not hold back messages for everyone else. Peers we have heard from but never
+    /// got a capability set from run a legacy build and count as lacking everything.
+    pub async fn lacking_capabilities(required: Capabilities) -> Result<Vec<Peer>> {
+        let seen_since = Utc::now() - CAPABILITY_WINDOW;
+        let peers = Self::list().await?;
+        Ok(peers
+            .into_iter()
+            .filter(|peer| peer.admission == PeerAdmission::Approved)
+            .filter(|peer| {
+                peer.last_seen
+                    .is_some_and(|last_seen| last_seen > seen_since)
+            })
+            .filter(|peer| {
+                !peer
+                    .capabilities
+                    .is_some_and(|capabilities| capabilities.contains(required))
+            })
+            .collect())
+    }
+
     pub fn node_addr(&self) -> &NodeAddr {
         self.ticket.node_addr()
     }
@@ -228,4 +660,252 @@ mod tests {
         // Verify the final peer has the updated ticket (ticket2)
         assert_eq!(matching_peers[0].ticket.node_addr(), ticket2.node_addr());
     }
+
+    #[tokio::test]
+    async fn test_admission_defaults_to_pending() {
+        let node_id = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+
+        let peer = Peer::insert_from_node_id(node_id).await.unwrap().unwrap();
+        assert_eq!(peer.admission, PeerAdmission::Pending);
+        assert!(!Peer::is_admitted(node_id).await.unwrap());
+
+        let peer = Peer::set_admission(node_id, PeerAdmission::Approved)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(peer.admission, PeerAdmission::Approved);
+        assert!(Peer::is_admitted(node_id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_introduction_from_unknown_node_is_pending() {
+        let node_id = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        let age_public_key = age::x25519::Identity::generate().to_public().to_string();
+        let ticket = NodeTicket::new(NodeAddr::new(node_id));
+
+        let peer = Peer::update_from_introduction(node_id, ticket, None, age_public_key)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(peer.node_id, node_id);
+        assert_eq!(peer.admission, PeerAdmission::Pending);
+        assert!(peer.age_public_key.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_last_seen_is_flushed_in_batches() {
+        let known = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        let unknown = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        Peer::insert_from_node_id(known).await.unwrap();
+
+        Peer::touch(known);
+        Peer::touch(unknown);
+        assert!(Peer::get(known).await.unwrap().unwrap().last_seen.is_none());
+
+        assert!(Peer::flush_last_seen().await.unwrap() >= 2);
+        assert!(Peer::get(known).await.unwrap().unwrap().last_seen.is_some());
+        assert!(!Peer::is_known(unknown).await.unwrap());
+    }
+
+    /// Run with `cargo test bench_ -- --ignored --nocapture`
+    #[tokio::test]
+    #[ignore = "benchmark"]
+    #[allow(clippy::print_stdout)]
+    async fn bench_last_seen_writes() {
+        use std::time::Instant;
+
+        const PEERS: usize = 100;
+        // Ten seconds worth of heartbeats, one per peer per second
+        const ROUNDS: usize = 10;
+
+        let peers: Vec<NodeId> = (0..PEERS)
+            .map(|_| iroh::SecretKey::generate(rand::rngs::OsRng).public())
+            .collect();
+        for node_id in &peers {
+            Peer::insert_from_node_id(*node_id).await.unwrap();
+        }
+
+        #[derive(serde::Serialize)]
+        struct UpdateLastSeen {
+            #[serde(with = "crate::custom_serde::chrono_datetime_as_sql")]
+            last_seen: DateTime<Utc>,
+        }
+
+        // A write per message, how the receive loop used to work
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            for node_id in &peers {
+                let _: Option<Peer> = db()
+                    .await
+                    .unwrap()
+                    .update(("peer", node_id.to_string()))
+                    .merge(UpdateLastSeen {
+                        last_seen: Utc::now(),
+                    })
+                    .await
+                    .unwrap();
+            }
+        }
+        let per_message = start.elapsed();
+
+        // Noted in memory and flushed once per interval
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            for node_id in &peers {
+                Peer::touch(*node_id);
+            }
+        }
+        Peer::flush_last_seen().await.unwrap();
+        let batched = start.elapsed();
+
+        println!(
+            "{PEERS} peers x {ROUNDS} heartbeats: per message {per_message:?}, batched {batched:?} ({:.1}x)",
+            per_message.as_secs_f64() / batched.as_secs_f64()
+        );
+        assert!(batched < per_message);
+    }
+
+    #[tokio::test]
+    async fn test_update_addresses_only_touches_known_peers() {
+        let known = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        let unknown = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        Peer::insert_from_node_id(known).await.unwrap();
+
+        let node_addr =
+            NodeAddr::new(known).with_direct_addresses(["192.0.2.1:4433".parse().unwrap()]);
+        let peer = Peer::update_addresses(node_addr.clone(), "direct(192.0.2.1:4433)".into())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(peer.node_addr(), &node_addr);
+        assert_eq!(peer.connection.as_deref(), Some("direct(192.0.2.1:4433)"));
+
+        let node_addr = NodeAddr::new(unknown);
+        assert!(
+            Peer::update_addresses(node_addr, "none".into())
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(!Peer::is_known(unknown).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_metadata_and_duplicate_hostnames() {
+        let first = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        let second = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        let hostname = format!("dup-{}", first.fmt_short());
+        let metadata = NodeMetadata {
+            hostname: Some(hostname.clone()),
+            display_name: Some("First".to_string()),
+            os: "linux x86_64".to_string(),
+            version: "0.1.0".to_string(),
+            ..Default::default()
+        };
+
+        Peer::insert_from_node_id(first).await.unwrap();
+        Peer::insert_from_node_id(second).await.unwrap();
+        let peer = Peer::update_metadata(first, metadata.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(peer.hostname.as_deref(), Some(hostname.as_str()));
+        assert_eq!(peer.metadata, Some(metadata.clone()));
+        assert!(
+            Peer::sharing_hostname(first, &hostname)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        Peer::update_metadata(second, metadata).await.unwrap();
+        assert_eq!(
+            Peer::sharing_hostname(first, &hostname).await.unwrap(),
+            vec![second]
+        );
+        let duplicates = Peer::duplicate_hostnames(&Peer::list().await.unwrap());
+        assert_eq!(duplicates.get(&hostname).map(Vec::len), Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_migrate_keeps_membership() {
+        let old = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        let new = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        let age_key = age::x25519::Identity::generate().to_public();
+
+        Peer::insert_approved_from_ticket(NodeTicket::new(NodeAddr::new(old)))
+            .await
+            .unwrap();
+        Peer::set_labels(old, vec!["web".to_string()])
+            .await
+            .unwrap();
+
+        let migrated = Peer::migrate(old, new, age_key.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(migrated.node_id, new);
+        assert_eq!(migrated.ticket.node_addr().node_id, new);
+        assert_eq!(migrated.admission, PeerAdmission::Approved);
+        assert_eq!(migrated.labels, vec!["web".to_string()]);
+        assert_eq!(
+            migrated.age_public_key.map(|key| key.to_string()),
+            Some(age_key.to_string())
+        );
+        assert!(!Peer::is_known(old).await.unwrap());
+        assert!(Peer::is_admitted(new).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_only_recently_seen_peers_hold_back_messages() {
+        let offline = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        let legacy = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        let upgraded = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        for node_id in [offline, legacy, upgraded] {
+            Peer::insert_from_node_id(node_id).await.unwrap();
+            Peer::set_admission(node_id, PeerAdmission::Approved)
+                .await
+                .unwrap();
+        }
+        Peer::update_protocol(upgraded, 1, Capabilities::ours())
+            .await
+            .unwrap();
+        Peer::touch(legacy);
+        Peer::touch(upgraded);
+        Peer::flush_last_seen().await.unwrap();
+
+        let lacking = Peer::lacking_capabilities(Capabilities::ADMISSION)
+            .await
+            .unwrap()
+            .to_node_ids();
+        assert!(lacking.contains(&legacy));
+        assert!(!lacking.contains(&offline));
+        assert!(!lacking.contains(&upgraded));
+    }
+
+    #[tokio::test]
+    async fn test_rtt_window_keeps_most_recent() {
+        let node_id = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        Peer::insert_from_node_id(node_id).await.unwrap();
+
+        for ms in 0..RTT_WINDOW as u64 + 3 {
+            Peer::record_rtt(node_id, Duration::from_millis(ms))
+                .await
+                .unwrap();
+        }
+
+        let peer = Peer::get(node_id).await.unwrap().unwrap();
+        assert_eq!(peer.rtt_ms.len(), RTT_WINDOW);
+        assert_eq!(peer.rtt_ms.first().copied(), Some(3.0));
+        assert_eq!(peer.rtt_ms.last().copied(), Some((RTT_WINDOW + 2) as f64));
+
+        let unknown = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        assert!(
+            Peer::record_rtt(unknown, Duration::from_millis(1))
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
 }
@@ -0,0 +1,143 @@
+use std::collections::BTreeSet;
+
+use anyhow::{Context, Result, anyhow};
+use iroh::NodeId;
+use serde::{Deserialize, Serialize};
+
+use super::{KeyHandoverRecord, SenderStatus, db};
+use crate::actors::gossip::signing::{SignedPolicy, authorize_admin};
+
+/// The policy currently in force, kept as the signed document so it can be passed on verbatim
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PolicyRecord {
+    pub version: u64,
+    #[serde(with = "crate::custom_serde::node_id_serde")]
+    pub issued_by: NodeId,
+    #[serde(with = "crate::custom_serde::bytes_as_sql")]
+    pub signed: Vec<u8>,
+}
+
+impl PolicyRecord {
+    pub async fn current() -> Result<Option<SignedPolicy>> {
+        let record: Option<PolicyRecord> = db()
+            .await?
+            .select(("policy", "current"))
+            .await
+            .context("Failed to get current policy")?;
+
+        record
+            .map(|record| {
+                SignedPolicy::verify(record.signed)
+                    .map_err(|err| anyhow!("Stored policy is invalid: {err}"))
+            })
+            .transpose()
+    }
+
+    /// Replace the current policy, callers must check the new one supersedes it first
+    pub async fn store(policy: &SignedPolicy) -> Result<()> {
+        let record = PolicyRecord {
+            version: policy.policy.version,
+            issued_by: policy.issued_by,
+            signed: policy.bytes.clone(),
+        };
+
+        let _: Option<PolicyRecord> = db()
+            .await?
+            .upsert(("policy", "current"))
+            .content(record)
+            .await
+            .context("Failed to store policy")?;
+        SenderStatus::invalidate();
+        Ok(())
+    }
+
+    /// Number of distinct admin signatures a sensitive change needs, see [`required_approvals`]
+    ///
+    /// [`required_approvals`]: crate::actors::gossip::signing::required_approvals
+    pub async fn required_approvals() -> Result<usize> {
+        let policy = Self::current().await?;
+        Ok(crate::actors::gossip::signing::required_approvals(
+            policy.as_ref(),
+        ))
+    }
+
+    /// Whether `node_id` is an admin under the current policy, see [`authorize_admin`]
+    ///
+    /// Admins keep their role across key rotations, so any key `node_id` replaced counts too.
+    ///
+    /// [`authorize_admin`]: crate::actors::gossip::signing::authorize_admin
+    pub async fn is_admin(node_id: NodeId) -> Result<bool> {
+        let policy = Self::current().await?;
+        Ok(KeyHandoverRecord::lineage(node_id)
+            .await?
+            .into_iter()
+            .any(|key| authorize_admin(policy.as_ref(), key).is_ok()))
+    }
+
+    /// The admins among `signers`, each counted once by the first key it was known by
+    ///
+    /// An admin that rotated its key part way through signing something only counts once.
+    pub async fn distinct_admins(
+        signers: impl IntoIterator<Item = NodeId>,
+    ) -> Result<BTreeSet<NodeId>> {
+        let mut admins = BTreeSet::new();
+        for signer in signers {
+            let lineage = KeyHandoverRecord::lineage(signer).await?;
+            if Self::is_admin(signer).await?
+                && let Some(original) = lineage.last()
+            {
+                admins.insert(*original);
+            }
+        }
+        Ok(admins)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use crate::actors::gossip::signing::Policy;
+    use crate::network::handover::{KeyHandover, SignedHandover};
+
+    #[tokio::test]
+    async fn test_store_and_load_policy() {
+        let admin = iroh::SecretKey::generate(rand::rngs::OsRng);
+        let other = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        let policy = Policy {
+            version: 1,
+            admins: vec![admin.public()],
+            threshold: 1,
+            issued_at: chrono::Utc::now(),
+        };
+        let signed = SignedPolicy::sign(&admin, policy.clone()).unwrap();
+
+        PolicyRecord::store(&signed).await.unwrap();
+
+        let current = PolicyRecord::current().await.unwrap().unwrap();
+        assert_eq!(current.policy, policy);
+        assert_eq!(current.issued_by, admin.public());
+        assert!(PolicyRecord::is_admin(admin.public()).await.unwrap());
+        assert!(!PolicyRecord::is_admin(other).await.unwrap());
+
+        // The admin rotates its key, both keys together still count as one admin
+        let rotated = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        let handover = SignedHandover::sign(
+            &admin,
+            KeyHandover {
+                old_node_id: admin.public(),
+                new_node_id: rotated,
+                age_public_key: age::x25519::Identity::generate().to_public().to_string(),
+                rotated_at: chrono::Utc::now(),
+            },
+        )
+        .unwrap();
+        KeyHandoverRecord::store(&handover).await.unwrap();
+
+        assert!(PolicyRecord::is_admin(rotated).await.unwrap());
+        let admins = PolicyRecord::distinct_admins([admin.public(), rotated, other])
+            .await
+            .unwrap();
+        assert_eq!(admins, BTreeSet::from([admin.public()]));
+    }
+}
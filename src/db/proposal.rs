@@ -0,0 +1,203 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use iroh::NodeId;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::db;
+use crate::network::proposal::{SignedApproval, SignedProposal};
+
+/// Where a proposal is in its lifecycle
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProposalStatus {
+    #[default]
+    Pending,
+    Applied,
+    Expired,
+    Failed,
+}
+
+impl std::fmt::Display for ProposalStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProposalStatus::Pending => f.write_str("pending"),
+            ProposalStatus::Applied => f.write_str("applied"),
+            ProposalStatus::Expired => f.write_str("expired"),
+            ProposalStatus::Failed => f.write_str("failed"),
+        }
+    }
+}
+
+/// A proposed change, kept as the signed document so it can be passed on verbatim
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProposalRecord {
+    pub proposal_id: String,
+    #[serde(with = "crate::custom_serde::node_id_serde")]
+    pub proposed_by: NodeId,
+    pub summary: String,
+    #[serde(with = "crate::custom_serde::bytes_as_sql")]
+    pub signed: Vec<u8>,
+    #[serde(default)]
+    pub status: ProposalStatus,
+    #[serde(with = "crate::custom_serde::chrono_datetime_as_sql")]
+    pub expires_at: DateTime<Utc>,
+}
+
+/// An admin's sign off on a proposal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApprovalRecord {
+    pub proposal_id: String,
+    #[serde(with = "crate::custom_serde::node_id_serde")]
+    pub approver: NodeId,
+    #[serde(with = "crate::custom_serde::bytes_as_sql")]
+    pub signed: Vec<u8>,
+}
+
+impl ProposalRecord {
+    /// Store a proposal we have not seen before, returns whether it was new
+    pub async fn store(proposal: &SignedProposal) -> Result<bool> {
+        if Self::get(proposal.proposal.id).await?.is_some() {
+            return Ok(false);
+        }
+
+        let record = ProposalRecord {
+            proposal_id: proposal.proposal.id.to_string(),
+            proposed_by: proposal.proposed_by,
+            summary: proposal.proposal.action.to_string(),
+            signed: proposal.bytes.clone(),
+            status: ProposalStatus::Pending,
+            expires_at: proposal.proposal.expires_at,
+        };
+
+        let _: Option<ProposalRecord> = db()
+            .await?
+            .create(("proposal", record.proposal_id.clone()))
+            .content(record)
+            .await
+            .context("Failed to store proposal")?;
+        Ok(true)
+    }
+
+    pub async fn get(proposal_id: Uuid) -> Result<Option<ProposalRecord>> {
+        db().await?
+            .select(("proposal", proposal_id.to_string()))
+            .await
+            .context("Failed to get proposal")
+    }
+
+    pub async fn list() -> Result<Vec<ProposalRecord>> {
+        db().await?
+            .query("SELECT * FROM proposal ORDER BY expires_at ASC")
+            .await?
+            .take(0)
+            .context("Failed to list proposals")
+    }
+
+    pub async fn set_status(proposal_id: Uuid, status: ProposalStatus) -> Result<()> {
+        #[derive(serde::Serialize)]
+        struct UpdateStatus {
+            status: ProposalStatus,
+        }
+
+        let _: Option<ProposalRecord> = db()
+            .await?
+            .update(("proposal", proposal_id.to_string()))
+            .merge(UpdateStatus { status })
+            .await
+            .context("Failed to update proposal status")?;
+        Ok(())
+    }
+
+    pub fn signed_proposal(&self) -> Result<SignedProposal> {
+        SignedProposal::verify(self.signed.clone())
+    }
+}
+
+impl ApprovalRecord {
+    /// Store an approval we have not seen before, returns whether it was new
+    pub async fn store(approval: &SignedApproval) -> Result<bool> {
+        let key = format!("{}_{}", approval.approval.proposal_id, approval.approver);
+        let existing: Option<ApprovalRecord> = db()
+            .await?
+            .select(("approval", key.clone()))
+            .await
+            .context("Failed to get approval")?;
+        if existing.is_some() {
+            return Ok(false);
+        }
+
+        let record = ApprovalRecord {
+            proposal_id: approval.approval.proposal_id.to_string(),
+            approver: approval.approver,
+            signed: approval.bytes.clone(),
+        };
+
+        let _: Option<ApprovalRecord> = db()
+            .await?
+            .create(("approval", key))
+            .content(record)
+            .await
+            .context("Failed to store approval")?;
+        Ok(true)
+    }
+
+    /// Every stored approval of `proposal`, approvals that no longer verify are skipped
+    pub async fn for_proposal(proposal: &SignedProposal) -> Result<Vec<SignedApproval>> {
+        let records: Vec<ApprovalRecord> = db()
+            .await?
+            .query("SELECT * FROM approval WHERE proposal_id = $proposal_id")
+            .bind(("proposal_id", proposal.proposal.id.to_string()))
+            .await?
+            .take(0)
+            .context("Failed to list approvals")?;
+
+        Ok(records
+            .into_iter()
+            .filter_map(|record| SignedApproval::verify(record.signed, proposal).ok())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use crate::network::proposal::{Proposal, ProposalAction};
+
+    #[tokio::test]
+    async fn test_store_proposal_and_approvals() {
+        let admin = iroh::SecretKey::generate(rand::rngs::OsRng);
+        let other = iroh::SecretKey::generate(rand::rngs::OsRng);
+        let node_id = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        let proposal = SignedProposal::sign(
+            &admin,
+            Proposal::new(
+                ProposalAction::Admit { node_id },
+                Utc::now() + chrono::Duration::hours(1),
+            ),
+        )
+        .unwrap();
+
+        assert!(ProposalRecord::store(&proposal).await.unwrap());
+        assert!(!ProposalRecord::store(&proposal).await.unwrap());
+
+        let approval = SignedApproval::sign(&other, &proposal).unwrap();
+        assert!(ApprovalRecord::store(&approval).await.unwrap());
+        assert!(!ApprovalRecord::store(&approval).await.unwrap());
+
+        let approvals = ApprovalRecord::for_proposal(&proposal).await.unwrap();
+        assert_eq!(approvals.len(), 1);
+        assert_eq!(approvals[0].approver, other.public());
+
+        ProposalRecord::set_status(proposal.proposal.id, ProposalStatus::Applied)
+            .await
+            .unwrap();
+        let record = ProposalRecord::get(proposal.proposal.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(record.status, ProposalStatus::Applied);
+        assert_eq!(record.summary, format!("admit {node_id}"));
+    }
+}
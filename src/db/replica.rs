@@ -0,0 +1,228 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use iroh::NodeId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::db;
+
+/// Where a replicated gossip message is kept
+///
+/// Messages that replace the previous one, such as a node's metadata, share a `Latest` slot per
+/// node along with the time they were signed at. Everything else is never replaced and gets a
+/// slot of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplicaSlot {
+    Latest(&'static str, NodeId, DateTime<Utc>),
+    Unique(&'static str),
+}
+
+impl ReplicaSlot {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ReplicaSlot::Latest(kind, ..) | ReplicaSlot::Unique(kind) => kind,
+        }
+    }
+
+    /// The key the message is stored under, `hash` is that of the signed message
+    pub fn key(&self, hash: &str) -> String {
+        match self {
+            ReplicaSlot::Latest(kind, node_id, _) => format!("{kind}/{node_id}"),
+            ReplicaSlot::Unique(kind) => format!("{kind}/{hash}"),
+        }
+    }
+
+    /// When the message in a `Latest` slot was signed
+    pub fn signed_at(&self) -> Option<DateTime<Utc>> {
+        match self {
+            ReplicaSlot::Latest(_, _, signed_at) => Some(*signed_at),
+            ReplicaSlot::Unique(_) => None,
+        }
+    }
+}
+
+/// A signed gossip message kept verbatim, so peers that missed it can fetch it later
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicaRecord {
+    pub slot: String,
+    pub kind: String,
+    /// The node a `Latest` slot belongs to
+    pub node_id: Option<String>,
+    /// Hex encoded sha256 of `envelope`
+    pub hash: String,
+    #[serde(with = "crate::custom_serde::bytes_as_sql")]
+    pub envelope: Vec<u8>,
+    /// When the message reached us
+    #[serde(with = "crate::custom_serde::chrono_datetime_as_sql")]
+    pub stored_at: DateTime<Utc>,
+    /// When the message in a `Latest` slot was signed, see [`ReplicaSlot::signed_at`]
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "crate::custom_serde::optional_chrono_datetime_as_sql"
+    )]
+    pub signed_at: Option<DateTime<Utc>>,
+}
+
+/// A stored replica without its message, which is all a summary needs
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ReplicaEntry {
+    pub slot: String,
+    pub kind: String,
+    pub hash: String,
+    #[serde(default, with = "crate::custom_serde::optional_chrono_datetime_as_sql")]
+    pub signed_at: Option<DateTime<Utc>>,
+}
+
+/// Whether a replica signed at `signed_at` with `hash` replaces one signed at `current_signed_at`
+/// with `current_hash`
+///
+/// Only the signed time and hash are compared, so every node picks the same one whatever order
+/// they arrived in.
+pub fn is_newer(
+    (signed_at, hash): (Option<DateTime<Utc>>, &str),
+    (current_signed_at, current_hash): (Option<DateTime<Utc>>, &str),
+) -> bool {
+    (signed_at, hash) > (current_signed_at, current_hash)
+}
+
+impl ReplicaRecord {
+    pub fn hash(envelope: &[u8]) -> String {
+        hex::encode(Sha256::digest(envelope))
+    }
+
+    /// Keep a copy of a signed message, returns whether it was new
+    ///
+    /// A `Latest` slot only takes a message signed later than the one it holds, see
+    /// [`is_newer`].
+    pub async fn keep(envelope: &[u8], slot: ReplicaSlot) -> Result<bool> {
+        let hash = Self::hash(envelope);
+        let key = slot.key(&hash);
+
+        let existing: Option<ReplicaRecord> = db()
+            .await?
+            .select(("replica", key.clone()))
+            .await
+            .context("Failed to get replica")?;
+        let signed_at = slot.signed_at();
+        if existing.is_some_and(|existing| {
+            !is_newer((signed_at, &hash), (existing.signed_at, &existing.hash))
+        }) {
+            return Ok(false);
+        }
+
+        let record = ReplicaRecord {
+            slot: key.clone(),
+            kind: slot.kind().to_string(),
+            node_id: match slot {
+                ReplicaSlot::Latest(_, node_id, _) => Some(node_id.to_string()),
+                ReplicaSlot::Unique(_) => None,
+            },
+            hash,
+            envelope: envelope.to_vec(),
+            stored_at: Utc::now(),
+            signed_at,
+        };
+
+        let _: Option<ReplicaRecord> = db()
+            .await?
+            .upsert(("replica", key))
+            .content(record)
+            .await
+            .context("Failed to store replica")?;
+        Ok(true)
+    }
+
+    /// Every stored replica of the given kinds, or of every kind if `kinds` is `None`
+    pub async fn entries(kinds: Option<Vec<String>>) -> Result<Vec<ReplicaEntry>> {
+        let query = match kinds {
+            Some(_) => {
+                "SELECT slot, kind, hash, signed_at FROM replica WHERE kind IN $kinds ORDER BY slot"
+            }
+            None => "SELECT slot, kind, hash, signed_at FROM replica ORDER BY slot",
+        };
+
+        db().await?
+            .query(query)
+            .bind(("kinds", kinds.unwrap_or_default()))
+            .await?
+            .take(0)
+            .context("Failed to list replicas")
+    }
+
+    /// The replicas stored in `slots`, slots we do not have are skipped
+    pub async fn get(slots: Vec<String>) -> Result<Vec<ReplicaRecord>> {
+        db().await?
+            .query("SELECT * FROM replica WHERE slot IN $slots")
+            .bind(("slots", slots))
+            .await?
+            .take(0)
+            .context("Failed to get replicas")
+    }
+
+    /// Drop every `Latest` slot of `node_id`, used once it has been revoked
+    pub async fn forget(node_id: NodeId) -> Result<()> {
+        db().await?
+            .query("DELETE replica WHERE node_id = $node_id")
+            .bind(("node_id", node_id.to_string()))
+            .await?
+            .check()
+            .context("Failed to delete replicas")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+
+    #[tokio::test]
+    async fn test_latest_slot_keeps_newest() {
+        let node_id = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        let earlier = ReplicaSlot::Latest("metadata", node_id, Utc::now() - TimeDelta::minutes(1));
+        let later = ReplicaSlot::Latest("metadata", node_id, Utc::now());
+
+        assert!(ReplicaRecord::keep(b"old", earlier).await.unwrap());
+        assert!(ReplicaRecord::keep(b"new", later).await.unwrap());
+        // Arriving after the newer one does not make the older one win
+        assert!(!ReplicaRecord::keep(b"old", earlier).await.unwrap());
+        assert!(!ReplicaRecord::keep(b"new", later).await.unwrap());
+
+        let key = later.key("");
+        let stored = ReplicaRecord::get(vec![key.clone()]).await.unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].envelope, b"new");
+        assert_eq!(stored[0].hash, ReplicaRecord::hash(b"new"));
+
+        ReplicaRecord::forget(node_id).await.unwrap();
+        assert!(ReplicaRecord::get(vec![key]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unique_slots_are_kept_side_by_side() {
+        let kind = "test_unique";
+        assert!(
+            ReplicaRecord::keep(b"first", ReplicaSlot::Unique(kind))
+                .await
+                .unwrap()
+        );
+        assert!(
+            ReplicaRecord::keep(b"second", ReplicaSlot::Unique(kind))
+                .await
+                .unwrap()
+        );
+        assert!(
+            !ReplicaRecord::keep(b"first", ReplicaSlot::Unique(kind))
+                .await
+                .unwrap()
+        );
+
+        let entries = ReplicaRecord::entries(Some(vec![kind.to_string()]))
+            .await
+            .unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().all(|entry| entry.kind == kind));
+    }
+}
@@ -0,0 +1,161 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use iroh::NodeId;
+use serde::{Deserialize, Serialize};
+
+use super::{SenderStatus, db};
+use crate::network::revocation::SignedRevocation;
+
+/// A node that has been banned from the network, kept as the signed document so it can be passed
+/// on verbatim
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RevocationRecord {
+    #[serde(with = "crate::custom_serde::node_id_serde")]
+    pub node_id: NodeId,
+    #[serde(with = "crate::custom_serde::node_id_serde")]
+    pub revoked_by: NodeId,
+    pub reason: Option<String>,
+    #[serde(with = "crate::custom_serde::chrono_datetime_as_sql")]
+    pub revoked_at: DateTime<Utc>,
+    #[serde(with = "crate::custom_serde::bytes_as_sql")]
+    pub signed: Vec<u8>,
+}
+
+impl RevocationRecord {
+    /// Store a revocation we have not seen before, returns whether it was new
+    pub async fn store(revocation: &SignedRevocation) -> Result<bool> {
+        let node_id = revocation.revocation.node_id;
+        if Self::is_revoked(node_id).await? {
+            return Ok(false);
+        }
+
+        let record = RevocationRecord {
+            node_id,
+            revoked_by: revocation.revoked_by,
+            reason: revocation.revocation.reason.clone(),
+            revoked_at: revocation.revocation.revoked_at,
+            signed: revocation.bytes.clone(),
+        };
+
+        let _: Option<RevocationRecord> = db()
+            .await?
+            .create(("revocation", node_id.to_string()))
+            .content(record)
+            .await
+            .context("Failed to store revocation")?;
+        SenderStatus::invalidate();
+        Ok(true)
+    }
+
+    pub async fn is_revoked(node_id: NodeId) -> Result<bool> {
+        let record: Option<RevocationRecord> = db()
+            .await?
+            .select(("revocation", node_id.to_string()))
+            .await
+            .context("Failed to check revocation")?;
+        Ok(record.is_some())
+    }
+
+    pub async fn list() -> Result<Vec<RevocationRecord>> {
+        db().await?
+            .query("SELECT * FROM revocation ORDER BY revoked_at ASC")
+            .await?
+            .take(0)
+            .context("Failed to list revocations")
+    }
+
+    /// Flag every secret `node_id` held for rotation, returns their names
+    ///
+    /// The node keeps whatever it already decrypted, so the copies other nodes hold of the same
+    /// secret are flagged as well.
+    pub async fn flag_secrets(node_id: NodeId) -> Result<Vec<String>> {
+        let mut names: Vec<String> = db()
+            .await?
+            .query("SELECT VALUE name FROM secret WHERE node_id = $node_id")
+            .bind(("node_id", node_id.to_string()))
+            .await?
+            .take(0)
+            .context("Failed to list secrets of revoked peer")?;
+        names.sort();
+        names.dedup();
+
+        db().await?
+            .query("UPDATE secret SET needs_rotation = true WHERE name IN $names")
+            .bind(("names", names.clone()))
+            .await?
+            .check()
+            .context("Failed to flag secrets for rotation")?;
+
+        Ok(names)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use crate::db::SecretEntry;
+    use crate::network::revocation::Revocation;
+
+    #[tokio::test]
+    async fn test_store_revocation() {
+        let admin = iroh::SecretKey::generate(rand::rngs::OsRng);
+        let node_id = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        let revocation = SignedRevocation::sign(
+            &admin,
+            Revocation {
+                node_id,
+                reason: None,
+                revoked_at: Utc::now(),
+            },
+        )
+        .unwrap();
+
+        assert!(!RevocationRecord::is_revoked(node_id).await.unwrap());
+        assert!(RevocationRecord::store(&revocation).await.unwrap());
+        assert!(!RevocationRecord::store(&revocation).await.unwrap());
+        assert!(RevocationRecord::is_revoked(node_id).await.unwrap());
+
+        let listed = RevocationRecord::list().await.unwrap();
+        assert!(
+            listed
+                .iter()
+                .any(|record| record.node_id == node_id && record.revoked_by == admin.public())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_flag_secrets() {
+        let node_id = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        let other = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        for holder in [node_id, other] {
+            db().await
+                .unwrap()
+                .query("CREATE secret SET name = 'smtp_password', node_id = $node_id, created_at = time::now(), data = <bytes>'secret'")
+                .bind(("node_id", holder.to_string()))
+                .await
+                .unwrap()
+                .check()
+                .unwrap();
+        }
+
+        let flagged = RevocationRecord::flag_secrets(node_id).await.unwrap();
+        assert_eq!(flagged, vec!["smtp_password".to_string()]);
+        assert!(
+            SecretEntry::to_rotate()
+                .await
+                .unwrap()
+                .contains(&"smtp_password".to_string())
+        );
+
+        // Nothing is deleted, the revoked node's copy shows what it saw
+        let held: Vec<String> = SecretEntry::list()
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|entry| entry.name == "smtp_password")
+            .map(|entry| entry.node_id)
+            .collect();
+        assert_eq!(held.len(), 2);
+    }
+}
@@ -0,0 +1,299 @@
+use std::path::PathBuf;
+use std::process::Stdio;
+
+use age::secrecy::SecretString;
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use iroh::NodeId;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::RwLock;
+use tracing::debug;
+
+use super::{Identity, db};
+
+/// Environment variable holding the passphrase when no file is given
+pub const PASSPHRASE_ENV: &str = "ROOM_101_PASSPHRASE";
+
+/// Name of the systemd credential holding the passphrase, see `LoadCredential=`
+pub const PASSPHRASE_CREDENTIAL: &str = "room_101_passphrase";
+
+/// Name the identity is bound to when it is encrypted with `systemd-creds`
+const SYSTEMD_CREDENTIAL_NAME: &str = "room_101_identity";
+
+/// The unsealed identity along with how to seal it again, so it is only unsealed once per process
+static UNSEALED: RwLock<Option<(Identity, Sealer)>> = RwLock::const_new(None);
+
+/// How a sealed identity is protected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SealMethod {
+    /// age scrypt encryption, the same format as `identity export`
+    Passphrase,
+    /// Encrypted by `systemd-creds`, bound to the host key and/or TPM
+    SystemdCreds,
+}
+
+impl std::fmt::Display for SealMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SealMethod::Passphrase => f.write_str("passphrase"),
+            SealMethod::SystemdCreds => f.write_str("systemd-creds"),
+        }
+    }
+}
+
+/// Everything needed to seal or unseal an identity
+#[derive(Clone)]
+pub enum Sealer {
+    Passphrase(SecretString),
+    SystemdCreds {
+        /// Use user-scope credentials instead of system-scope
+        user_scope: bool,
+    },
+}
+
+/// Our identity encrypted at rest, stored instead of the plain `identity:self` record
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SealedIdentity {
+    /// Public so the node can be identified without unsealing it
+    #[serde(with = "crate::custom_serde::node_id_serde")]
+    pub node_id: NodeId,
+    pub method: SealMethod,
+    pub user_scope: bool,
+    #[serde(with = "crate::custom_serde::bytes_as_sql")]
+    data: Vec<u8>,
+}
+
+impl Sealer {
+    pub fn method(&self) -> SealMethod {
+        match self {
+            Sealer::Passphrase(_) => SealMethod::Passphrase,
+            Sealer::SystemdCreds { .. } => SealMethod::SystemdCreds,
+        }
+    }
+
+    async fn seal(&self, identity: &Identity) -> Result<Vec<u8>> {
+        match self {
+            Sealer::Passphrase(passphrase) => identity.export(passphrase.clone()),
+            Sealer::SystemdCreds { user_scope } => {
+                systemd_creds("encrypt", *user_scope, &identity.to_backup_bytes()?).await
+            }
+        }
+    }
+
+    async fn unseal(&self, data: &[u8]) -> Result<Identity> {
+        match self {
+            Sealer::Passphrase(passphrase) => Identity::import(data, passphrase.clone())
+                .context("Failed to unseal identity, is the passphrase correct?"),
+            Sealer::SystemdCreds { user_scope } => {
+                Identity::from_backup_bytes(&systemd_creds("decrypt", *user_scope, data).await?)
+            }
+        }
+    }
+}
+
+impl SealedIdentity {
+    pub async fn get() -> Result<Option<SealedIdentity>> {
+        db().await?
+            .select(("sealed_identity", "self"))
+            .await
+            .context("Failed to get sealed identity")
+    }
+
+    /// The identity if it has already been unsealed by this process
+    pub async fn unsealed() -> Option<Identity> {
+        UNSEALED
+            .read()
+            .await
+            .as_ref()
+            .map(|(identity, _)| identity.clone())
+    }
+
+    /// Unseal the identity and keep it in memory for the rest of the process
+    ///
+    /// Without a sealer the passphrase is looked up with [`passphrase_from_environment`].
+    pub async fn unseal(&self, sealer: Option<Sealer>) -> Result<Identity> {
+        let sealer = match sealer {
+            Some(sealer) => sealer,
+            None => self.default_sealer()?,
+        };
+        ensure!(
+            sealer.method() == self.method,
+            "Identity is sealed with {}, not {}",
+            self.method,
+            sealer.method()
+        );
+
+        let identity = sealer.unseal(&self.data).await?;
+        ensure!(
+            identity.id() == self.node_id,
+            "Sealed identity does not match node {}",
+            self.node_id
+        );
+        debug!(node_id = %self.node_id, method = %self.method, "Unsealed identity");
+
+        *UNSEALED.write().await = Some((identity.clone(), sealer));
+        Ok(identity)
+    }
+
+    /// Seal `identity` and remove the unsealed copy from the database
+    pub async fn seal(identity: &Identity, sealer: Sealer) -> Result<SealedIdentity> {
+        let record = SealedIdentity {
+            node_id: identity.id(),
+            method: sealer.method(),
+            user_scope: matches!(sealer, Sealer::SystemdCreds { user_scope: true }),
+            data: sealer.seal(identity).await?,
+        };
+
+        let record: SealedIdentity = db()
+            .await?
+            .upsert(("sealed_identity", "self"))
+            .content(record)
+            .await
+            .context("Failed to store sealed identity")?
+            .ok_or(anyhow!("Failed to store sealed identity"))?;
+        Identity::delete_plain().await?;
+
+        *UNSEALED.write().await = Some((identity.clone(), sealer));
+        Ok(record)
+    }
+
+    /// Seal a replacement identity the same way the current one is sealed
+    pub async fn reseal(identity: &Identity) -> Result<SealedIdentity> {
+        let cached = UNSEALED
+            .read()
+            .await
+            .as_ref()
+            .map(|(_, sealer)| sealer.clone());
+        let sealer = match cached {
+            Some(sealer) => sealer,
+            None => Self::get()
+                .await?
+                .ok_or(anyhow!("Identity is not sealed"))?
+                .default_sealer()?,
+        };
+
+        Self::seal(identity, sealer).await
+    }
+
+    /// Store the identity unsealed again and remove the sealed copy
+    pub async fn remove(identity: &Identity) -> Result<()> {
+        Identity::store_plain(identity).await?;
+        let _: Option<SealedIdentity> = db()
+            .await?
+            .delete(("sealed_identity", "self"))
+            .await
+            .context("Failed to delete sealed identity")?;
+
+        *UNSEALED.write().await = None;
+        Ok(())
+    }
+
+    fn default_sealer(&self) -> Result<Sealer> {
+        Ok(match self.method {
+            SealMethod::Passphrase => {
+                Sealer::Passphrase(passphrase_from_environment()?.ok_or_else(|| {
+                    anyhow!(
+                        "Identity is sealed with a passphrase, provide it with --passphrase-file, \
+                         {PASSPHRASE_ENV} or the {PASSPHRASE_CREDENTIAL} systemd credential"
+                    )
+                })?)
+            }
+            SealMethod::SystemdCreds => Sealer::SystemdCreds {
+                user_scope: self.user_scope,
+            },
+        })
+    }
+}
+
+/// Find the passphrase in the systemd credential directory or in [`PASSPHRASE_ENV`]
+pub fn passphrase_from_environment() -> Result<Option<SecretString>> {
+    if let Some(directory) = std::env::var_os("CREDENTIALS_DIRECTORY") {
+        let path = PathBuf::from(directory).join(PASSPHRASE_CREDENTIAL);
+        if path.exists() {
+            let passphrase = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read passphrase from {}", path.display()))?;
+            return Ok(Some(parse_passphrase(&passphrase)?));
+        }
+    }
+
+    match std::env::var(PASSPHRASE_ENV) {
+        Ok(passphrase) => Ok(Some(parse_passphrase(&passphrase)?)),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(err) => Err(err).context(format!("Failed to read {PASSPHRASE_ENV}")),
+    }
+}
+
+/// Strip the trailing newline most editors add and refuse empty passphrases
+pub fn parse_passphrase(passphrase: &str) -> Result<SecretString> {
+    let passphrase = passphrase.trim_end_matches(['\r', '\n']);
+    ensure!(!passphrase.is_empty(), "The passphrase can not be empty");
+    Ok(SecretString::from(passphrase.to_string()))
+}
+
+/// Run `systemd-creds encrypt|decrypt` over stdin and stdout
+async fn systemd_creds(action: &str, user_scope: bool, input: &[u8]) -> Result<Vec<u8>> {
+    let mut cmd = Command::new("systemd-creds");
+    cmd.arg(action)
+        .arg(format!("--name={SYSTEMD_CREDENTIAL_NAME}"))
+        .arg("-")
+        .arg("-");
+
+    if user_scope {
+        cmd.arg("--user");
+    }
+
+    let mut process = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to run systemd-creds")?;
+
+    let mut stdin = process
+        .stdin
+        .take()
+        .ok_or(anyhow!("Could not get systemd-creds stdin pipe"))?;
+    stdin.write_all(input).await?;
+    stdin.shutdown().await?;
+    drop(stdin);
+
+    let output = process.wait_with_output().await?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if stderr.contains("io.systemd.InteractiveAuthenticationRequired") {
+            bail!(
+                "Insufficient privilege to {action} the identity, try --user-scope or run as root"
+            );
+        }
+        bail!("systemd-creds {action} failed: {}", stderr.trim());
+    }
+
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_passphrase_sealer_round_trip() {
+        let identity = Identity::generate();
+        let sealer = Sealer::Passphrase(SecretString::from("correct horse".to_owned()));
+        let sealed = sealer.seal(&identity).await.unwrap();
+
+        let unsealed = sealer.unseal(&sealed).await.unwrap();
+        assert_eq!(unsealed.id(), identity.id());
+
+        let wrong = Sealer::Passphrase(SecretString::from("battery staple".to_owned()));
+        assert!(wrong.unseal(&sealed).await.is_err());
+    }
+
+    #[test]
+    fn test_parse_passphrase() {
+        assert!(parse_passphrase("\n").is_err());
+        assert!(parse_passphrase("hunter2\r\n").is_ok());
+    }
+}
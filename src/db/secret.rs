@@ -1,3 +1,78 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use super::db;
+
+/// Which secret a node holds, without its contents
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+pub struct SecretEntry {
+    pub node_id: String,
+    pub name: String,
+    pub created_at: String,
+    /// Set once a node that held the secret was revoked
+    pub needs_rotation: bool,
+}
+
+impl SecretEntry {
+    /// Every stored secret, sorted by node and name
+    pub async fn list() -> Result<Vec<SecretEntry>> {
+        let entries: Vec<SecretEntry> = db()
+            .await?
+            .query(
+                "SELECT node_id, name, <string> created_at AS created_at, needs_rotation FROM secret ORDER BY node_id, name",
+            )
+            .await?
+            .take(0)
+            .context("Failed to list secrets")?;
+
+        Ok(entries)
+    }
+
+    /// Names of the secrets that were flagged for rotation, see [`super::RevocationRecord`]
+    pub async fn to_rotate() -> Result<Vec<String>> {
+        let mut names: Vec<String> = Self::list()
+            .await?
+            .into_iter()
+            .filter(|entry| entry.needs_rotation)
+            .map(|entry| entry.name)
+            .collect();
+        names.sort();
+        names.dedup();
+
+        Ok(names)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_list_secret_entries() {
+        let node_id = iroh::SecretKey::generate(rand::rngs::OsRng)
+            .public()
+            .to_string();
+        db().await
+            .unwrap()
+            .query("CREATE secret SET name = 'api_token', node_id = $node_id, created_at = d'2026-01-01T00:00:00Z', data = <bytes>'secret'")
+            .bind(("node_id", node_id.clone()))
+            .await
+            .unwrap()
+            .check()
+            .unwrap();
+
+        let entries = SecretEntry::list().await.unwrap();
+        let entry = entries
+            .iter()
+            .find(|entry| entry.node_id == node_id)
+            .unwrap();
+        assert_eq!(entry.name, "api_token");
+        assert!(entry.created_at.starts_with("2026-01-01T00:00:00"));
+        assert!(!entry.needs_rotation);
+    }
+}
+
 // use anyhow::{Result, anyhow};
 // use chrono::{DateTime, Utc};
 // use iroh::NodeId;
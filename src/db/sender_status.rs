@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use iroh::NodeId;
+
+use super::{KeyHandoverRecord, Peer, PolicyRecord, RevocationRecord};
+
+/// How long a status is cached at most, see [`SenderStatus`]
+const CACHE_TTL: Duration = Duration::from_secs(5);
+
+/// Statuses looked up since the last membership change, along with when they were
+static CACHE: LazyLock<RwLock<HashMap<NodeId, (SenderStatus, Instant)>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
+/// Bumped on every invalidation, so a lookup that raced with one is not cached
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// What the gossip receiver needs to know about the signer of a message
+///
+/// Looking this up takes several queries, so it is cached until something that could change it
+/// is written. CLI commands such as `peers revoke` write membership records from their own
+/// process while the server runs, so entries also expire after a few seconds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SenderStatus {
+    pub revoked: bool,
+    /// The node rotated to a new key, so messages signed with this one are stale
+    pub retired: bool,
+    pub admitted: bool,
+    pub admin: bool,
+}
+
+impl SenderStatus {
+    pub async fn of(node_id: NodeId) -> Result<SenderStatus> {
+        if let Some((status, _)) = CACHE
+            .read()
+            .ok()
+            .and_then(|cache| cache.get(&node_id).copied())
+            .filter(|(_, looked_up_at)| looked_up_at.elapsed() < CACHE_TTL)
+        {
+            return Ok(status);
+        }
+
+        let generation = GENERATION.load(Ordering::Acquire);
+        let status = Self::lookup(node_id).await?;
+        if let Ok(mut cache) = CACHE.write()
+            && GENERATION.load(Ordering::Acquire) == generation
+        {
+            cache.insert(node_id, (status, Instant::now()));
+        }
+
+        Ok(status)
+    }
+
+    /// Forget every cached status, called whenever admission, revocations, handovers or the
+    /// policy change
+    pub fn invalidate() {
+        GENERATION.fetch_add(1, Ordering::AcqRel);
+        if let Ok(mut cache) = CACHE.write() {
+            cache.clear();
+        }
+    }
+
+    async fn lookup(node_id: NodeId) -> Result<SenderStatus> {
+        Ok(SenderStatus {
+            revoked: RevocationRecord::is_revoked(node_id).await?,
+            retired: KeyHandoverRecord::successor(node_id).await?.is_some(),
+            admitted: Peer::is_admitted(node_id).await?,
+            admin: PolicyRecord::is_admin(node_id).await?,
+        })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::db::{PeerAdmission, db};
+
+    #[tokio::test]
+    async fn test_admission_change_invalidates() {
+        let node_id = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        Peer::insert_from_node_id(node_id).await.unwrap();
+        assert!(!SenderStatus::of(node_id).await.unwrap().admitted);
+
+        Peer::set_admission(node_id, PeerAdmission::Approved)
+            .await
+            .unwrap();
+        assert!(SenderStatus::of(node_id).await.unwrap().admitted);
+    }
+
+    #[tokio::test]
+    async fn test_writes_from_another_process_are_seen() {
+        let node_id = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        Peer::insert_from_node_id(node_id).await.unwrap();
+        assert!(!SenderStatus::of(node_id).await.unwrap().admitted);
+
+        // As `peers approve` would from the CLI, without invalidating our cache
+        db().await
+            .unwrap()
+            .query("UPDATE type::thing('peer', $node_id) SET admission = 'approved'")
+            .bind(("node_id", node_id.to_string()))
+            .await
+            .unwrap()
+            .check()
+            .unwrap();
+
+        tokio::time::sleep(CACHE_TTL).await;
+        assert!(SenderStatus::of(node_id).await.unwrap().admitted);
+    }
+
+    /// Run with `cargo test bench_ -- --ignored --nocapture`
+    #[tokio::test]
+    #[ignore = "benchmark"]
+    #[allow(clippy::print_stdout)]
+    async fn bench_sender_status_lookups() {
+        const PEERS: usize = 100;
+        const ROUNDS: usize = 10;
+
+        let peers: Vec<NodeId> = (0..PEERS)
+            .map(|_| iroh::SecretKey::generate(rand::rngs::OsRng).public())
+            .collect();
+        for node_id in &peers {
+            Peer::insert_from_node_id(*node_id).await.unwrap();
+        }
+
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            for node_id in &peers {
+                SenderStatus::lookup(*node_id).await.unwrap();
+            }
+        }
+        let uncached = start.elapsed();
+
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            for node_id in &peers {
+                SenderStatus::of(*node_id).await.unwrap();
+            }
+        }
+        let cached = start.elapsed();
+
+        println!(
+            "{PEERS} peers x {ROUNDS} messages: uncached {uncached:?}, cached {cached:?} ({:.1}x)",
+            uncached.as_secs_f64() / cached.as_secs_f64()
+        );
+        assert!(cached < uncached);
+    }
+}
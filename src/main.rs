@@ -1,8 +1,10 @@
 use anyhow::Result;
+use clap::Parser;
 
 mod actors;
 mod args;
 mod commands;
+mod config;
 mod custom_serde;
 mod db;
 mod network;
@@ -11,23 +13,34 @@ mod utils;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize tracing first
-    tracing::setup_tracing()?;
+    // Parse command line arguments and merge them over the config file
+    let args = args::Args::parse();
+    let config = config::Config::load(&args).await?;
 
-    // Parse command line arguments
-    let args = args::args().await;
+    // Initialize tracing before anything else logs
+    tracing::setup_tracing(config.log_filter.as_deref())?;
 
     // Validate database path
-    if args.db_path == ":memory:" {
+    if config.db_path == ":memory:" {
         anyhow::bail!("In-memory database not allowed in production. Use a file path instead.");
     }
+    db::set_path(config.db_path.clone())?;
 
     // Route to appropriate command handler
+    let network = &config.network;
     match &args.command {
-        args::Commands::Server(server_args) => commands::server::run(server_args).await,
-        args::Commands::Peers(peers_args) => commands::peers::run(peers_args).await,
+        args::Commands::Server(server_args) => {
+            commands::server::run(&args, server_args, &config).await
+        }
+        args::Commands::Peers(peers_args) => commands::peers::run(peers_args, &config).await,
         args::Commands::Status => commands::status::run().await,
-        args::Commands::Init(_) => commands::init::run().await,
+        args::Commands::Init(init_args) => commands::init::run(init_args, network).await,
         args::Commands::Audit(audit_args) => commands::audit::run(audit_args).await,
+        args::Commands::Invite(invite_args) => commands::invite::run(invite_args, network).await,
+        args::Commands::Policy(policy_args) => commands::policy::run(policy_args).await,
+        args::Commands::Approvals(approvals_args) => commands::approvals::run(approvals_args).await,
+        args::Commands::Identity(identity_args) => {
+            commands::identity::run(identity_args, &config).await
+        }
     }
 }
@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+/// Exponential backoff with jitter, for retrying something until it works
+///
+/// Each delay is double the last, up to `max`, and is shortened by a random part of up to
+/// `jitter` of itself so nodes that lost their neighbors at the same moment do not retry in step.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    jitter: f64,
+    attempts: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration, jitter: f64) -> Self {
+        Self {
+            initial,
+            max,
+            jitter: jitter.clamp(0.0, 1.0),
+            attempts: 0,
+        }
+    }
+
+    /// The delay before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self
+            .initial
+            .saturating_mul(2u32.saturating_pow(self.attempts))
+            .min(self.max);
+        self.attempts = self.attempts.saturating_add(1);
+
+        delay.mul_f64(1.0 - rand::thread_rng().gen_range(0.0..=self.jitter))
+    }
+
+    /// Start over from the initial delay, once the thing being retried worked
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delays_double_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10), 0.0);
+
+        let delays: Vec<u64> = (0..6).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 10, 10]);
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_jitter_only_shortens() {
+        let mut backoff = Backoff::new(Duration::from_secs(8), Duration::from_secs(8), 0.5);
+
+        for _ in 0..100 {
+            let delay = backoff.next_delay();
+            assert!(delay <= Duration::from_secs(8));
+            assert!(delay >= Duration::from_secs(4));
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use iroh::NodeId;
+
+/// A peer whose clock is this far from ours is flagged
+pub const CLOCK_SKEW_THRESHOLD: TimeDelta = TimeDelta::seconds(10);
+
+/// A flagged peer is only cleared once its clock is back within this, so an offset hovering
+/// around the threshold does not flag it over and over
+const CLOCK_SKEW_CLEARED: TimeDelta = TimeDelta::seconds(5);
+
+/// How many samples the offset of each peer is estimated from
+const SAMPLES: usize = 9;
+
+/// How many samples we need before a peer is flagged, so one delayed heartbeat is not enough
+const MIN_SAMPLES: usize = 3;
+
+/// A peer's clock crossing the skew threshold in either direction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SkewChange {
+    pub node_id: NodeId,
+    pub offset: TimeDelta,
+    pub skewed: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Tracked {
+    samples: VecDeque<TimeDelta>,
+    skewed: bool,
+}
+
+/// Estimates how far each peer's clock is from ours from the timestamps in their heartbeats
+///
+/// A heartbeat left the peer about half a round trip before it reached us, so the offset is the
+/// difference between its timestamp and that moment. The median of the recent samples is used,
+/// so a single delayed heartbeat does not move the estimate.
+#[derive(Debug, Clone, Default)]
+pub struct ClockSkewEstimator {
+    peers: HashMap<NodeId, Tracked>,
+}
+
+impl ClockSkewEstimator {
+    /// Add a sample from a heartbeat `sent_at` by the peer that we received at `received_at`
+    ///
+    /// `rtt` is the last round trip time measured to the peer, if any. Returns the change if the
+    /// peer just crossed the skew threshold.
+    pub fn heartbeat(
+        &mut self,
+        node_id: NodeId,
+        sent_at: DateTime<Utc>,
+        received_at: DateTime<Utc>,
+        rtt: Option<Duration>,
+    ) -> Option<SkewChange> {
+        let one_way = rtt
+            .and_then(|rtt| TimeDelta::from_std(rtt / 2).ok())
+            .unwrap_or_default();
+        let sample = sent_at - (received_at - one_way);
+
+        let tracked = self.peers.entry(node_id).or_default();
+        if tracked.samples.len() == SAMPLES {
+            tracked.samples.pop_front();
+        }
+        tracked.samples.push_back(sample);
+
+        if tracked.samples.len() < MIN_SAMPLES {
+            return None;
+        }
+        let offset = median(&tracked.samples);
+        let skewed = if tracked.skewed {
+            offset.abs() > CLOCK_SKEW_CLEARED
+        } else {
+            offset.abs() > CLOCK_SKEW_THRESHOLD
+        };
+
+        if skewed == tracked.skewed {
+            return None;
+        }
+        tracked.skewed = skewed;
+        Some(SkewChange {
+            node_id,
+            offset,
+            skewed,
+        })
+    }
+
+    /// Current estimate of how far the peer's clock is ahead of ours, negative if it is behind
+    pub fn offset(&self, node_id: NodeId) -> Option<TimeDelta> {
+        self.peers
+            .get(&node_id)
+            .filter(|tracked| !tracked.samples.is_empty())
+            .map(|tracked| median(&tracked.samples))
+    }
+
+    /// Peers we have an estimate for
+    pub fn node_ids(&self) -> Vec<NodeId> {
+        self.peers.keys().copied().collect()
+    }
+
+    /// Stop tracking a peer, such as one that was revoked
+    pub fn forget(&mut self, node_id: NodeId) {
+        self.peers.remove(&node_id);
+    }
+}
+
+fn median(samples: &VecDeque<TimeDelta>) -> TimeDelta {
+    let mut sorted: Vec<TimeDelta> = samples.iter().copied().collect();
+    sorted.sort();
+    sorted.get(sorted.len() / 2).copied().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node_id() -> NodeId {
+        iroh::SecretKey::generate(rand::rngs::OsRng).public()
+    }
+
+    #[test]
+    fn test_offset_accounts_for_rtt() {
+        let mut estimator = ClockSkewEstimator::default();
+        let node_id = node_id();
+        let now = Utc::now();
+
+        // Sent 50ms before we got it, which is exactly half the round trip
+        estimator.heartbeat(
+            node_id,
+            now - TimeDelta::milliseconds(50),
+            now,
+            Some(Duration::from_millis(100)),
+        );
+        assert_eq!(estimator.offset(node_id), Some(TimeDelta::zero()));
+    }
+
+    #[test]
+    fn test_skew_is_flagged_once_and_cleared() {
+        let mut estimator = ClockSkewEstimator::default();
+        let node_id = node_id();
+        let now = Utc::now();
+        let ahead = now + TimeDelta::minutes(5);
+
+        for _ in 1..MIN_SAMPLES {
+            assert_eq!(estimator.heartbeat(node_id, ahead, now, None), None);
+        }
+        let change = estimator.heartbeat(node_id, ahead, now, None);
+        assert_eq!(
+            change,
+            Some(SkewChange {
+                node_id,
+                offset: TimeDelta::minutes(5),
+                skewed: true
+            })
+        );
+        assert_eq!(estimator.heartbeat(node_id, ahead, now, None), None);
+
+        // The median only follows once most samples agree
+        let mut changes = Vec::new();
+        for _ in 0..SAMPLES {
+            changes.extend(estimator.heartbeat(node_id, now, now, None));
+        }
+        assert_eq!(changes.len(), 1);
+        assert!(!changes[0].skewed);
+    }
+
+    #[test]
+    fn test_single_late_heartbeat_is_ignored() {
+        let mut estimator = ClockSkewEstimator::default();
+        let node_id = node_id();
+        let now = Utc::now();
+
+        for _ in 0..4 {
+            estimator.heartbeat(node_id, now, now, None);
+        }
+        let late = estimator.heartbeat(node_id, now - TimeDelta::minutes(1), now, None);
+        assert_eq!(late, None);
+        assert_eq!(estimator.offset(node_id), Some(TimeDelta::zero()));
+    }
+}
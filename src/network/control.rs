@@ -0,0 +1,233 @@
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use iroh::NodeId;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task::JoinHandle;
+use tracing::{debug, trace, warn};
+
+use crate::actors::gossip::{GossipMessage, gossip_sender, iroh::ping_peer};
+use crate::actors::membership::revoke;
+use crate::db::{Identity, OutboxMessage, Peer};
+use crate::network::revocation::SignedRevocation;
+
+/// Largest request or reply we read
+const MAX_MESSAGE_SIZE: u64 = 64 * 1024;
+
+/// What the CLI asks of the running server
+///
+/// Some commands only have an effect once the server acts on them, such as forgetting a revoked
+/// peer, or need the network the server holds, such as pinging a peer. While the server runs they
+/// are handed to it instead of being applied to the database from the side.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ControlRequest {
+    /// Apply a revocation signed with our key and publish it right away
+    Revoke { revocation: Vec<u8> },
+    /// Ping a peer from the server's endpoint, see [`crate::network::ping`]
+    Ping { node_id: NodeId, timeout: Duration },
+}
+
+/// The server's answer to a [`ControlRequest`]
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ControlReply {
+    Done,
+    /// See [`crate::network::ping::Pinged`]
+    Pinged {
+        rtt: Duration,
+        connection: Option<String>,
+    },
+}
+
+/// The socket the server listens on, next to the database at `db_path`
+pub fn socket_path(db_path: &str) -> PathBuf {
+    PathBuf::from(format!("{db_path}.sock"))
+}
+
+/// Answer requests on the control socket until the returned task is aborted
+///
+/// Only call this while holding the [`crate::utils::ServerLock`], a socket left behind by a
+/// server that did not shut down cleanly is replaced.
+pub fn listen(db_path: &str) -> Result<JoinHandle<()>> {
+    let path = socket_path(db_path);
+    match std::fs::remove_file(&path) {
+        Ok(()) => debug!(?path, "Removed stale control socket"),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err).with_context(|| format!("Failed to remove {path:?}")),
+    }
+
+    let listener =
+        UnixListener::bind(&path).with_context(|| format!("Failed to listen on {path:?}"))?;
+    // Only our own user may ask the server to do anything
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("Failed to restrict access to {path:?}"))?;
+
+    Ok(tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(async move {
+                        if let Err(err) = serve(stream).await {
+                            debug!(?err, "Control request failed");
+                        }
+                    });
+                }
+                Err(err) => {
+                    warn!(?err, "Failed to accept control connection, stopping");
+                    return;
+                }
+            }
+        }
+    }))
+}
+
+async fn serve(mut stream: UnixStream) -> Result<()> {
+    let mut buffer = Vec::new();
+    (&mut stream)
+        .take(MAX_MESSAGE_SIZE)
+        .read_to_end(&mut buffer)
+        .await?;
+    let request: ControlRequest =
+        postcard::from_bytes(&buffer).context("Invalid control request")?;
+    trace!(?request, "Answering control request");
+
+    let reply: Result<ControlReply, String> =
+        handle(request).await.map_err(|err| format!("{err:#}"));
+    stream.write_all(&postcard::to_stdvec(&reply)?).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+async fn handle(request: ControlRequest) -> Result<ControlReply> {
+    match request {
+        ControlRequest::Revoke { revocation } => {
+            let revocation = SignedRevocation::verify(revocation)?;
+            let our_id = Identity::get().await?.id();
+            ensure!(
+                revocation.revoked_by == our_id,
+                "Only revocations signed by this node are accepted"
+            );
+
+            revoke(&revocation, our_id).await?;
+            let message = GossipMessage::Revocation {
+                revocation: revocation.bytes,
+            };
+            // Otherwise it goes out with the rest of the outbox once the network is back
+            if let Err(err) = gossip_sender::send_or_queue(message.clone()).await {
+                debug!(?err, "Network is not running, queueing revocation");
+                OutboxMessage::queue(&message).await?;
+            }
+            Ok(ControlReply::Done)
+        }
+        ControlRequest::Ping { node_id, timeout } => {
+            ensure!(
+                node_id != Identity::get().await?.id(),
+                "Cannot ping yourself"
+            );
+            if Peer::get(node_id).await?.is_none() {
+                bail!("No peer with node ID {node_id}");
+            }
+
+            let pinged = ping_peer(node_id, timeout).await?;
+            Ok(ControlReply::Pinged {
+                rtt: pinged.rtt,
+                connection: pinged.connection,
+            })
+        }
+    }
+}
+
+/// Hand `request` to the server running with the database at `db_path`
+pub async fn request(db_path: &str, request: ControlRequest) -> Result<ControlReply> {
+    let path = socket_path(db_path);
+    let mut stream = UnixStream::connect(&path)
+        .await
+        .with_context(|| format!("Failed to reach the running server at {path:?}"))?;
+    stream.write_all(&postcard::to_stdvec(&request)?).await?;
+    stream.shutdown().await?;
+
+    let mut buffer = Vec::new();
+    stream
+        .take(MAX_MESSAGE_SIZE)
+        .read_to_end(&mut buffer)
+        .await?;
+    let reply: Result<ControlReply, String> =
+        postcard::from_bytes(&buffer).context("Invalid reply from the server")?;
+    reply.map_err(|err| anyhow!("The server refused: {err}"))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use crate::network::revocation::Revocation;
+
+    #[tokio::test]
+    async fn test_revocation_must_be_ours() {
+        let dir = std::env::temp_dir().join(format!("room_101-control-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db_path = dir.join("db").to_string_lossy().to_string();
+        // Another test may have created our identity first
+        let _ = Identity::get_or_generate().await;
+
+        let server = listen(&db_path).unwrap();
+        let someone = iroh::SecretKey::generate(rand::rngs::OsRng);
+        let revocation = SignedRevocation::sign(
+            &someone,
+            Revocation {
+                node_id: iroh::SecretKey::generate(rand::rngs::OsRng).public(),
+                reason: None,
+                revoked_at: chrono::Utc::now(),
+            },
+        )
+        .unwrap();
+
+        let err = request(
+            &db_path,
+            ControlRequest::Revoke {
+                revocation: revocation.bytes,
+            },
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("signed by this node"), "{err}");
+
+        server.abort();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ping_needs_a_known_peer() {
+        let dir = std::env::temp_dir().join(format!("room_101-control-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db_path = dir.join("db").to_string_lossy().to_string();
+        // Another test may have created our identity first
+        let _ = Identity::get_or_generate().await;
+
+        let server = listen(&db_path).unwrap();
+        let ping = |node_id| {
+            request(
+                &db_path,
+                ControlRequest::Ping {
+                    node_id,
+                    timeout: Duration::from_secs(1),
+                },
+            )
+        };
+
+        let our_id = Identity::get().await.unwrap().id();
+        let err = ping(our_id).await.unwrap_err();
+        assert!(err.to_string().contains("yourself"), "{err}");
+
+        let stranger = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        let err = ping(stranger).await.unwrap_err();
+        assert!(err.to_string().contains("No peer"), "{err}");
+
+        server.abort();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,101 @@
+use std::cmp::Ordering;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::db::{Peer, PeerAdmission, PolicyRecord};
+
+/// A small summary of the state a node replicates, carried in its heartbeats
+///
+/// Two nodes with the same state have the same digest, so comparing them is a cheap way to
+/// notice that one of them missed something. Secrets are left out, they are not signed so a peer
+/// that holds different ones could not be brought in line anyway.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateDigest {
+    /// Version of the policy in force, 0 if there is none
+    pub policy_version: u64,
+    /// Number of approved peers the node knows of, not counting itself
+    pub peer_count: u32,
+}
+
+/// The parts of a peer's state that differ from ours
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Divergence {
+    /// How our policy version compares to theirs, `Greater` if ours is newer
+    pub policy: Ordering,
+    pub peers: bool,
+}
+
+impl StateDigest {
+    /// Summarize our own state
+    pub async fn collect() -> Result<Self> {
+        let peer_count = Peer::list()
+            .await?
+            .iter()
+            .filter(|peer| peer.admission == PeerAdmission::Approved)
+            .count();
+        let policy_version = PolicyRecord::current()
+            .await?
+            .map(|policy| policy.policy.version)
+            .unwrap_or(0);
+
+        Ok(Self {
+            policy_version,
+            peer_count: u32::try_from(peer_count).unwrap_or(u32::MAX),
+        })
+    }
+
+    /// Compare our digest with one a peer sent, returns `None` if they match
+    pub fn diverges_from(&self, theirs: &StateDigest) -> Option<Divergence> {
+        if self == theirs {
+            return None;
+        }
+
+        Some(Divergence {
+            policy: self.policy_version.cmp(&theirs.policy_version),
+            peers: self.peer_count != theirs.peer_count,
+        })
+    }
+}
+
+impl Divergence {
+    /// Names of the parts that differ, for logs and audit events
+    pub fn parts(&self) -> Vec<&'static str> {
+        [
+            ("policy", self.policy != Ordering::Equal),
+            ("peers", self.peers),
+        ]
+        .into_iter()
+        .filter(|(_, differs)| *differs)
+        .map(|(name, _)| name)
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_divergence() {
+        let ours = StateDigest {
+            policy_version: 3,
+            peer_count: 4,
+        };
+        assert_eq!(ours.diverges_from(&ours), None);
+
+        let theirs = StateDigest {
+            policy_version: 2,
+            peer_count: 5,
+        };
+        let divergence = ours.diverges_from(&theirs);
+        assert_eq!(
+            divergence,
+            Some(Divergence {
+                policy: Ordering::Greater,
+                peers: true,
+            })
+        );
+        assert_eq!(divergence.map(|d| d.parts()), Some(vec!["policy", "peers"]));
+    }
+}
@@ -0,0 +1,93 @@
+use anyhow::{Result, anyhow, ensure};
+use chrono::{DateTime, Utc};
+use iroh::{NodeId, SecretKey};
+use serde::{Deserialize, Serialize};
+
+use crate::actors::gossip::signing::{Signable, SignedMessage};
+
+/// Statement that a node has moved to new keys, signed with the key it is moving away from
+///
+/// Peers use it to carry the node's membership over to its new node ID. When only the age key is
+/// rotated `old_node_id` and `new_node_id` are the same.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyHandover {
+    pub old_node_id: NodeId,
+    pub new_node_id: NodeId,
+    pub age_public_key: String,
+    pub rotated_at: DateTime<Utc>,
+}
+
+/// A handover along with the signed bytes it came from
+#[derive(Debug, Clone)]
+pub struct SignedHandover {
+    pub handover: KeyHandover,
+    pub bytes: Vec<u8>,
+}
+
+impl Signable for KeyHandover {
+    const SIGNING_CONTEXT: &'static [u8] = b"room_101/key-handover/v1";
+}
+
+impl KeyHandover {
+    pub fn rotates_node_key(&self) -> bool {
+        self.old_node_id != self.new_node_id
+    }
+}
+
+impl SignedHandover {
+    pub fn sign(old_secret_key: &SecretKey, handover: KeyHandover) -> Result<Self> {
+        ensure!(
+            handover.old_node_id == old_secret_key.public(),
+            "A handover must be signed with the old key"
+        );
+        let bytes = SignedMessage::sign_and_encode(old_secret_key, &handover)?;
+        Ok(Self { handover, bytes })
+    }
+
+    pub fn verify(bytes: Vec<u8>) -> Result<Self> {
+        let (signer, handover) = SignedMessage::<KeyHandover>::verify_and_decode(&bytes)
+            .map_err(|err| anyhow!("Invalid key handover: {err}"))?;
+        ensure!(
+            signer == handover.old_node_id,
+            "Key handover was not signed by the old key"
+        );
+        Ok(Self { handover, bytes })
+    }
+
+    /// Check the handover was passed on by the node it hands over to
+    pub fn handover_from(self, sender: NodeId) -> Result<Self> {
+        ensure!(
+            sender == self.handover.new_node_id,
+            "Key handover was sent by {sender} instead of the new key"
+        );
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handover_must_be_signed_by_old_key() {
+        let old = SecretKey::generate(rand::rngs::OsRng);
+        let new = SecretKey::generate(rand::rngs::OsRng);
+        let handover = KeyHandover {
+            old_node_id: old.public(),
+            new_node_id: new.public(),
+            age_public_key: age::x25519::Identity::generate().to_public().to_string(),
+            rotated_at: Utc::now(),
+        };
+
+        let signed = SignedHandover::sign(&old, handover.clone()).unwrap();
+        let verified = SignedHandover::verify(signed.bytes).unwrap();
+        assert_eq!(verified.handover, handover);
+        assert!(verified.handover.rotates_node_key());
+
+        // Someone else can not hand over the old node ID
+        assert!(SignedHandover::sign(&new, handover.clone()).is_err());
+        let forged = SignedMessage::sign_and_encode(&new, &handover).unwrap();
+        assert!(SignedHandover::verify(forged).is_err());
+    }
+}
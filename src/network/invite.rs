@@ -0,0 +1,156 @@
+use anyhow::{Context, Result, anyhow, ensure};
+use chrono::{DateTime, Utc};
+use iroh::{NodeId, SecretKey};
+use iroh_base::ticket::NodeTicket;
+use iroh_gossip::proto::TopicId;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::actors::gossip::signing::{Signable, SignedMessage};
+
+/// Everything a new node needs to join the network, signed by the node that issued it
+///
+/// The token is single use, the issuer admits the first node to redeem it with the bundled labels
+/// and tells every other node, which then refuse it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InviteToken {
+    pub id: Uuid,
+    pub network: TopicId,
+    pub bootstrap: Vec<NodeTicket>,
+    pub labels: Vec<String>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// An invite token along with who signed it and the signed bytes it came from
+#[derive(Debug, Clone)]
+pub struct SignedInvite {
+    pub issuer: NodeId,
+    pub token: InviteToken,
+    pub bytes: Vec<u8>,
+}
+
+impl Signable for InviteToken {
+    const SIGNING_CONTEXT: &'static [u8] = b"room_101/invite/v1";
+}
+
+impl InviteToken {
+    const PREFIX: &'static str = "room101invite";
+
+    pub fn new(
+        network: TopicId,
+        bootstrap: Vec<NodeTicket>,
+        labels: Vec<String>,
+        expires_at: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            id: uuid::Builder::from_random_bytes(rand::random()).into_uuid(),
+            network,
+            bootstrap,
+            labels,
+            expires_at,
+        }
+    }
+
+    /// Sign the token and encode it as a string that can be passed to `server --invite`
+    pub fn sign(&self, secret_key: &SecretKey) -> Result<String> {
+        let bytes = SignedMessage::sign_and_encode(secret_key, self)?;
+        Ok(format!("{}{}", Self::PREFIX, hex::encode(bytes)))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= Utc::now()
+    }
+}
+
+impl SignedInvite {
+    /// Parse and verify a token string produced by [`InviteToken::sign`] for the `network` topic
+    pub fn parse(token: &str, network: TopicId) -> Result<Self> {
+        let encoded = token
+            .trim()
+            .strip_prefix(InviteToken::PREFIX)
+            .context("Not an invite token")?;
+        let bytes = hex::decode(encoded).context("Invite token is not valid hex")?;
+        Self::verify(bytes, network)
+    }
+
+    /// Verify the signed bytes of a token, as carried in gossip messages
+    pub fn verify(bytes: Vec<u8>, network: TopicId) -> Result<Self> {
+        let (issuer, token) = SignedMessage::<InviteToken>::verify_and_decode(&bytes)
+            .map_err(|err| anyhow!("Invalid invite token: {err}"))?;
+        ensure!(
+            token.network == network,
+            "Invite token is for a different network"
+        );
+
+        Ok(Self {
+            issuer,
+            token,
+            bytes,
+        })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use iroh::NodeAddr;
+
+    const NETWORK: TopicId = TopicId::from_bytes([1; 32]);
+
+    fn test_token(expires_at: DateTime<Utc>) -> InviteToken {
+        let bootstrap_key = SecretKey::generate(rand::rngs::OsRng);
+        InviteToken::new(
+            NETWORK,
+            vec![NodeTicket::new(NodeAddr::new(bootstrap_key.public()))],
+            vec!["env=prod".to_string(), "web".to_string()],
+            expires_at,
+        )
+    }
+
+    #[test]
+    fn test_sign_and_parse_round_trip() {
+        let secret_key = SecretKey::generate(rand::rngs::OsRng);
+        let token = test_token(Utc::now() + chrono::Duration::hours(1));
+
+        let encoded = token.sign(&secret_key).unwrap();
+        assert!(encoded.starts_with(InviteToken::PREFIX));
+
+        let signed = SignedInvite::parse(&encoded, NETWORK).unwrap();
+        assert_eq!(signed.issuer, secret_key.public());
+        assert_eq!(signed.token.id, token.id);
+        assert_eq!(signed.token.labels, token.labels);
+        assert_eq!(signed.token.bootstrap.len(), 1);
+        assert!(!signed.token.is_expired());
+    }
+
+    #[test]
+    fn test_parse_rejects_tampered_token() {
+        let secret_key = SecretKey::generate(rand::rngs::OsRng);
+        let encoded = test_token(Utc::now()).sign(&secret_key).unwrap();
+
+        let mut tampered = encoded.into_bytes();
+        let last = tampered.len() - 1;
+        tampered[last] = if tampered[last] == b'0' { b'1' } else { b'0' };
+        let tampered = String::from_utf8(tampered).unwrap();
+
+        assert!(SignedInvite::parse(&tampered, NETWORK).is_err());
+        assert!(SignedInvite::parse("not a token", NETWORK).is_err());
+    }
+
+    #[test]
+    fn test_rejects_other_network() {
+        let secret_key = SecretKey::generate(rand::rngs::OsRng);
+        let mut token = test_token(Utc::now());
+        token.network = TopicId::from_bytes([7; 32]);
+
+        let encoded = token.sign(&secret_key).unwrap();
+        assert!(SignedInvite::parse(&encoded, NETWORK).is_err());
+    }
+
+    #[test]
+    fn test_is_expired() {
+        assert!(test_token(Utc::now() - chrono::Duration::seconds(1)).is_expired());
+        assert!(!test_token(Utc::now() + chrono::Duration::minutes(5)).is_expired());
+    }
+}
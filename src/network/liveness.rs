@@ -0,0 +1,345 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::time::{Duration, Instant};
+
+use iroh::NodeId;
+use serde::{Deserialize, Serialize};
+
+/// A peer is suspect once no heartbeat arrived for this long, unless heartbeats are configured
+/// to be slower, see [`FailureDetector::set_interval`]
+pub const SUSPECT_AFTER: Duration = Duration::from_secs(5);
+
+/// A peer is offline once no heartbeat arrived for this long, unless heartbeats are configured
+/// to be slower
+pub const OFFLINE_AFTER: Duration = Duration::from_secs(30);
+
+/// How many average heartbeat intervals may pass before a peer is suspect, so peers that
+/// heartbeat slowly are not flagged early
+const SUSPECT_INTERVALS: u32 = 3;
+
+/// How many average heartbeat intervals may pass before a peer is offline
+const OFFLINE_INTERVALS: u32 = 10;
+
+/// Whether we think a peer is up, judged from its heartbeats
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Liveness {
+    Online,
+    Suspect,
+    Offline,
+    /// The peer announced it was shutting down, so its silence is expected
+    Left,
+}
+
+impl Display for Liveness {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Liveness::Online => f.write_str("online"),
+            Liveness::Suspect => f.write_str("suspect"),
+            Liveness::Offline => f.write_str("offline"),
+            Liveness::Left => f.write_str("left"),
+        }
+    }
+}
+
+/// A peer moving from one liveness state to another
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transition {
+    pub node_id: NodeId,
+    pub from: Liveness,
+    pub to: Liveness,
+}
+
+#[derive(Debug, Clone)]
+struct Tracked {
+    liveness: Liveness,
+    last_heartbeat: Instant,
+    /// Moving average of the time between heartbeats, `None` until we have seen two
+    mean_interval: Option<Duration>,
+}
+
+/// Timeout based failure detector
+///
+/// The timeouts stretch with the average interval each peer heartbeats at, so a slow but steady
+/// peer is not flagged.
+#[derive(Debug, Clone)]
+pub struct FailureDetector {
+    suspect_after: Duration,
+    offline_after: Duration,
+    peers: HashMap<NodeId, Tracked>,
+}
+
+impl Default for FailureDetector {
+    fn default() -> Self {
+        Self::new(SUSPECT_AFTER, OFFLINE_AFTER)
+    }
+}
+
+impl FailureDetector {
+    pub fn new(suspect_after: Duration, offline_after: Duration) -> Self {
+        Self {
+            suspect_after,
+            offline_after,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// A detector for peers that heartbeat up to `interval` apart
+    pub fn with_interval(interval: Duration) -> Self {
+        let mut detector = Self::default();
+        detector.set_interval(interval);
+        detector
+    }
+
+    /// Stretch the timeouts so peers that heartbeat up to `interval` apart are never suspect
+    ///
+    /// Every node of a network is expected to share the heartbeat settings, so the longest
+    /// interval ours allow is the one we wait for. The interval learned for each peer only ever
+    /// stretches the timeouts further.
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.suspect_after = SUSPECT_AFTER.max(interval * SUSPECT_INTERVALS);
+        self.offline_after = OFFLINE_AFTER.max(interval * OFFLINE_INTERVALS);
+    }
+
+    /// Start watching a peer we last saw in `liveness`, as if it just sent a heartbeat
+    ///
+    /// Used for peers that were online before a restart, they go offline if they stay quiet.
+    pub fn track(&mut self, node_id: NodeId, liveness: Liveness, now: Instant) {
+        self.peers.entry(node_id).or_insert(Tracked {
+            liveness,
+            last_heartbeat: now,
+            mean_interval: None,
+        });
+    }
+
+    /// Record that a peer announced it is leaving, returns the transition if it was not gone yet
+    ///
+    /// The peer stays tracked so its next heartbeat brings it back online, but it is not moved
+    /// to suspect or offline while it is away.
+    pub fn leave(&mut self, node_id: NodeId) -> Option<Transition> {
+        let tracked = self.peers.get_mut(&node_id)?;
+        let from = std::mem::replace(&mut tracked.liveness, Liveness::Left);
+        (from != Liveness::Left).then_some(Transition {
+            node_id,
+            from,
+            to: Liveness::Left,
+        })
+    }
+
+    /// Stop watching a peer, such as one that was revoked
+    pub fn forget(&mut self, node_id: NodeId) {
+        self.peers.remove(&node_id);
+    }
+
+    /// Record a heartbeat, returns the transition if the peer was not online
+    pub fn heartbeat(&mut self, node_id: NodeId, now: Instant) -> Option<Transition> {
+        let Some(tracked) = self.peers.get_mut(&node_id) else {
+            self.peers.insert(
+                node_id,
+                Tracked {
+                    liveness: Liveness::Online,
+                    last_heartbeat: now,
+                    mean_interval: None,
+                },
+            );
+            return Some(Transition {
+                node_id,
+                from: Liveness::Offline,
+                to: Liveness::Online,
+            });
+        };
+
+        // Learn from every heartbeat, a peer that beats slower than the suspect timeout is only
+        // ever seen coming back from suspect. A gap spent offline or away is cut down to the
+        // offline timeout so it does not stretch the timeouts for long.
+        let interval = now
+            .saturating_duration_since(tracked.last_heartbeat)
+            .min(self.offline_after);
+        tracked.mean_interval = Some(match tracked.mean_interval {
+            Some(mean) => (mean * 7 + interval) / 8,
+            None => interval,
+        });
+        tracked.last_heartbeat = now;
+
+        let from = std::mem::replace(&mut tracked.liveness, Liveness::Online);
+        (from != Liveness::Online).then_some(Transition {
+            node_id,
+            from,
+            to: Liveness::Online,
+        })
+    }
+
+    /// Move peers that have gone quiet to suspect or offline
+    pub fn check(&mut self, now: Instant) -> Vec<Transition> {
+        let mut transitions = Vec::new();
+        for (node_id, tracked) in &mut self.peers {
+            if tracked.liveness == Liveness::Left {
+                continue;
+            }
+            let silent = now.saturating_duration_since(tracked.last_heartbeat);
+            let mean = tracked.mean_interval.unwrap_or_default();
+            let liveness = if silent >= self.offline_after.max(mean * OFFLINE_INTERVALS) {
+                Liveness::Offline
+            } else if silent >= self.suspect_after.max(mean * SUSPECT_INTERVALS) {
+                Liveness::Suspect
+            } else {
+                Liveness::Online
+            };
+
+            // Only heartbeats bring a peer back up
+            if liveness != tracked.liveness && liveness != Liveness::Online {
+                transitions.push(Transition {
+                    node_id: *node_id,
+                    from: tracked.liveness,
+                    to: liveness,
+                });
+                tracked.liveness = liveness;
+            }
+        }
+        transitions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node_id() -> NodeId {
+        iroh::SecretKey::generate(rand::rngs::OsRng).public()
+    }
+
+    #[test]
+    fn test_transitions() {
+        let mut detector = FailureDetector::default();
+        let node_id = node_id();
+        let start = Instant::now();
+
+        let transition = detector.heartbeat(node_id, start);
+        assert_eq!(transition.map(|t| t.to), Some(Liveness::Online));
+        assert_eq!(
+            detector.heartbeat(node_id, start + Duration::from_secs(1)),
+            None
+        );
+
+        assert!(detector.check(start + Duration::from_secs(3)).is_empty());
+
+        let transitions = detector.check(start + Duration::from_secs(7));
+        assert_eq!(
+            transitions,
+            vec![Transition {
+                node_id,
+                from: Liveness::Online,
+                to: Liveness::Suspect
+            }]
+        );
+        // No repeated transition while nothing changes
+        assert!(detector.check(start + Duration::from_secs(8)).is_empty());
+
+        let transitions = detector.check(start + Duration::from_secs(40));
+        assert_eq!(transitions[0].to, Liveness::Offline);
+
+        let transition = detector.heartbeat(node_id, start + Duration::from_secs(41));
+        assert_eq!(
+            transition,
+            Some(Transition {
+                node_id,
+                from: Liveness::Offline,
+                to: Liveness::Online
+            })
+        );
+    }
+
+    #[test]
+    fn test_timeouts_stretch_with_slow_heartbeats() {
+        let mut detector = FailureDetector::default();
+        let node_id = node_id();
+        let start = Instant::now();
+
+        // Heartbeats every 30 seconds, checked every second like the actor does
+        detector.heartbeat(node_id, start);
+        let mut transitions = Vec::new();
+        for second in 1..=300 {
+            let now = start + Duration::from_secs(second);
+            if second % 30 == 0 {
+                transitions.extend(detector.heartbeat(node_id, now));
+            }
+            transitions.extend(detector.check(now));
+        }
+
+        // Only the first interval is judged by the default timeouts, before it has been learned
+        assert_eq!(
+            transitions.iter().map(|t| t.to).collect::<Vec<_>>(),
+            vec![Liveness::Suspect, Liveness::Online]
+        );
+
+        // 3 intervals of 30 seconds have not passed yet
+        let last = start + Duration::from_secs(300);
+        assert!(detector.check(last + Duration::from_secs(80)).is_empty());
+        assert_eq!(
+            detector.check(last + Duration::from_secs(95))[0].to,
+            Liveness::Suspect
+        );
+    }
+
+    #[test]
+    fn test_timeouts_allow_for_the_configured_interval() {
+        let interval = Duration::from_secs(30);
+        let mut detector = FailureDetector::with_interval(interval);
+        let node_id = node_id();
+        let start = Instant::now();
+
+        // Not even the first interval is mistaken for a failure
+        detector.heartbeat(node_id, start);
+        for second in 1..=300 {
+            let now = start + Duration::from_secs(second);
+            if second % 30 == 0 {
+                assert_eq!(detector.heartbeat(node_id, now), None);
+            }
+            assert!(detector.check(now).is_empty(), "at {second}s");
+        }
+
+        // The defaults still apply to intervals shorter than them
+        detector.set_interval(Duration::from_secs(1));
+        assert_eq!(detector.suspect_after, SUSPECT_AFTER);
+        assert_eq!(detector.offline_after, OFFLINE_AFTER);
+    }
+
+    #[test]
+    fn test_tracked_peer_goes_offline_when_quiet() {
+        let mut detector = FailureDetector::default();
+        let node_id = node_id();
+        let start = Instant::now();
+
+        detector.track(node_id, Liveness::Online, start);
+        assert_eq!(
+            detector.check(start + OFFLINE_AFTER)[0],
+            Transition {
+                node_id,
+                from: Liveness::Online,
+                to: Liveness::Offline
+            }
+        );
+    }
+
+    #[test]
+    fn test_left_peer_is_not_marked_offline() {
+        let mut detector = FailureDetector::default();
+        let node_id = node_id();
+        let start = Instant::now();
+
+        detector.heartbeat(node_id, start);
+        assert_eq!(
+            detector.leave(node_id),
+            Some(Transition {
+                node_id,
+                from: Liveness::Online,
+                to: Liveness::Left
+            })
+        );
+        assert_eq!(detector.leave(node_id), None);
+        assert!(detector.check(start + OFFLINE_AFTER * 2).is_empty());
+
+        let transition = detector.heartbeat(node_id, start + OFFLINE_AFTER * 3);
+        assert_eq!(transition.map(|t| t.from), Some(Liveness::Left));
+    }
+}
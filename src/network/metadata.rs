@@ -0,0 +1,143 @@
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+/// Longest display name we store, anything longer is cut off
+const MAX_DISPLAY_NAME_LENGTH: usize = 64;
+
+/// Most self-declared labels we store for a single node
+const MAX_LABELS: usize = 32;
+
+/// Longest self-declared label we store
+const MAX_LABEL_LENGTH: usize = 64;
+
+/// Descriptive information a node publishes about itself
+///
+/// None of this is trusted, it is only shown to operators. The labels here are the ones the node
+/// declares for itself, they are separate from the labels admins assign.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeMetadata {
+    pub hostname: Option<String>,
+    pub display_name: Option<String>,
+    pub os: String,
+    pub kernel: Option<String>,
+    pub version: String,
+    pub labels: Vec<String>,
+}
+
+impl NodeMetadata {
+    /// Gather the metadata of the machine we are running on
+    pub fn collect(display_name: Option<String>, labels: Vec<String>) -> Self {
+        Self {
+            hostname: local_hostname(),
+            display_name,
+            os: format!("{} {}", std::env::consts::OS, std::env::consts::ARCH),
+            kernel: kernel_release(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            labels,
+        }
+        .sanitize()
+    }
+
+    /// Gather the metadata again, keeping the configured display name and labels
+    pub fn refresh(&self) -> Self {
+        Self::collect(self.display_name.clone(), self.labels.clone())
+    }
+
+    /// Drop or trim anything that is not safe to store and print
+    pub fn sanitize(self) -> Self {
+        Self {
+            hostname: self.hostname.filter(|hostname| is_valid_hostname(hostname)),
+            display_name: self
+                .display_name
+                .map(|name| clean_text(&name, MAX_DISPLAY_NAME_LENGTH))
+                .filter(|name| !name.is_empty()),
+            os: clean_text(&self.os, MAX_LABEL_LENGTH),
+            kernel: self
+                .kernel
+                .map(|kernel| clean_text(&kernel, MAX_LABEL_LENGTH))
+                .filter(|kernel| !kernel.is_empty()),
+            version: clean_text(&self.version, MAX_LABEL_LENGTH),
+            labels: self
+                .labels
+                .iter()
+                .map(|label| clean_text(label, MAX_LABEL_LENGTH))
+                .filter(|label| !label.is_empty())
+                .take(MAX_LABELS)
+                .collect(),
+        }
+    }
+}
+
+pub fn is_valid_hostname(hostname: &str) -> bool {
+    !hostname.is_empty() && hostname_validator::is_valid(hostname)
+}
+
+/// Our hostname, or `None` if it can not be read or is not a valid hostname
+pub fn local_hostname() -> Option<String> {
+    let hostname = match hostname::get() {
+        Ok(hostname) => hostname.to_string_lossy().into_owned(),
+        Err(err) => {
+            debug!(?err, "Failed to read hostname");
+            return None;
+        }
+    };
+
+    if is_valid_hostname(&hostname) {
+        Some(hostname)
+    } else {
+        debug!(%hostname, "Ignoring invalid hostname");
+        None
+    }
+}
+
+fn kernel_release() -> Option<String> {
+    std::fs::read_to_string("/proc/sys/kernel/osrelease")
+        .ok()
+        .map(|release| release.trim().to_string())
+        .filter(|release| !release.is_empty())
+}
+
+/// Remove control characters and cut `text` to at most `max` characters
+fn clean_text(text: &str, max: usize) -> String {
+    text.chars()
+        .filter(|c| !c.is_control())
+        .take(max)
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize() {
+        let metadata = NodeMetadata {
+            hostname: Some("not a hostname!".to_string()),
+            display_name: Some(format!("evil\x1b[2J{}", "x".repeat(100))),
+            os: "linux x86_64".to_string(),
+            kernel: Some("  ".to_string()),
+            version: "0.1.0".to_string(),
+            labels: vec!["web".to_string(), "\n".to_string()],
+        }
+        .sanitize();
+
+        assert_eq!(metadata.hostname, None);
+        let display_name = metadata.display_name.unwrap_or_default();
+        assert!(!display_name.contains('\x1b'));
+        assert_eq!(display_name.chars().count(), MAX_DISPLAY_NAME_LENGTH);
+        assert_eq!(metadata.kernel, None);
+        assert_eq!(metadata.labels, vec!["web".to_string()]);
+    }
+
+    #[test]
+    fn test_valid_hostname_is_kept() {
+        let metadata = NodeMetadata {
+            hostname: Some("node-1.example.com".to_string()),
+            ..Default::default()
+        }
+        .sanitize();
+        assert_eq!(metadata.hostname.as_deref(), Some("node-1.example.com"));
+    }
+}
@@ -1 +1,13 @@
+pub mod backoff;
+pub mod clock;
+pub mod control;
+pub mod digest;
+pub mod handover;
+pub mod invite;
+pub mod liveness;
+pub mod metadata;
+pub mod ping;
+pub mod proposal;
 pub mod protocol;
+pub mod revocation;
+pub mod sync;
@@ -0,0 +1,132 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, anyhow, ensure};
+use iroh::{
+    Endpoint, NodeId,
+    discovery::static_provider::StaticProvider,
+    endpoint::Connection,
+    protocol::{AcceptError, ProtocolHandler},
+};
+use tracing::{debug, trace};
+
+use crate::actors::gossip::iroh::learned_node_addr;
+use crate::config::NetworkConfig;
+use crate::db::{Identity, Peer, SenderStatus};
+
+/// ALPN of the ping protocol
+///
+/// Pings go straight to the peer over their own connection, so the round trip time measured is
+/// the peer's and not that of the gossip hops in between.
+pub const ALPN: &[u8] = b"room101/ping/0";
+
+/// How long the prober waits for a peer to answer
+pub const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A random tag the peer echoes back, so an answer can not be mixed up with another
+pub fn nonce() -> u64 {
+    rand::random()
+}
+
+/// The answer to a single ping
+#[derive(Debug, Clone)]
+pub struct Pinged {
+    pub rtt: Duration,
+    /// How the endpoint reached the peer, such as `direct(192.0.2.1:4433)` or `relay(...)`
+    pub connection: Option<String>,
+}
+
+/// Echoes pings from admitted peers
+#[derive(Debug, Clone)]
+pub struct PingProtocol;
+
+impl ProtocolHandler for PingProtocol {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let node_id = connection.remote_node_id()?;
+        let status = SenderStatus::of(node_id).await.unwrap_or_default();
+        if !status.admitted || status.revoked || status.retired {
+            debug!(%node_id, "Refusing ping from a peer that is not a member");
+            return Err(AcceptError::NotAllowed {});
+        }
+
+        while let Ok((mut send, mut recv)) = connection.accept_bi().await {
+            let Ok(nonce) = recv.read_to_end(size_of::<u64>()).await else {
+                break;
+            };
+            trace!(%node_id, "Answering ping");
+            if send.write_all(&nonce).await.is_err() || send.finish().is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Ping `node_id` once over the ping protocol
+///
+/// The time taken to connect is left out, only the echo itself is measured.
+pub async fn ping(endpoint: &Endpoint, node_id: NodeId, timeout: Duration) -> Result<Pinged> {
+    let rtt = tokio::time::timeout(timeout, async {
+        let connection = endpoint
+            .connect(node_id, ALPN)
+            .await
+            .context("Failed to connect to peer")?;
+        let result = echo(&connection).await;
+        connection.close(0u32.into(), b"done");
+        result
+    })
+    .await
+    .map_err(|_| anyhow!("No answer within {timeout:?}"))??;
+
+    let connection = endpoint
+        .remote_info(node_id)
+        .map(|info| info.conn_type.to_string());
+    Ok(Pinged { rtt, connection })
+}
+
+async fn echo(connection: &Connection) -> Result<Duration> {
+    let nonce = nonce().to_le_bytes();
+    let (mut send, mut recv) = connection.open_bi().await?;
+
+    let sent_at = Instant::now();
+    send.write_all(&nonce).await?;
+    send.finish()?;
+    let answer = recv.read_to_end(nonce.len()).await?;
+    let rtt = sent_at.elapsed();
+
+    ensure!(answer == nonce, "Peer answered with the wrong nonce");
+    Ok(rtt)
+}
+
+/// Ping `peer` once from an endpoint of our own, used by `peers ping` while the server is stopped
+///
+/// The endpoint uses our identity, so the server must not be running at the same time. The round
+/// trip time and the addresses learned along the way are stored on the peer.
+pub async fn ping_once(
+    identity: &Identity,
+    peer: &Peer,
+    network: &NetworkConfig,
+    timeout: Duration,
+) -> Result<Pinged> {
+    let static_discovery = StaticProvider::new();
+    static_discovery.add_node_info(peer.node_addr().clone());
+
+    let endpoint = network
+        .endpoint_builder(identity.secret_key.clone())
+        .add_discovery(static_discovery)
+        .bind()
+        .await?;
+
+    let pinged = ping(&endpoint, peer.node_id, timeout).await;
+    let info = endpoint.remote_info(peer.node_id);
+    endpoint.close().await;
+    let pinged = pinged?;
+
+    Peer::record_rtt(peer.node_id, pinged.rtt).await?;
+    if let Some(info) = &info
+        && let Some(node_addr) = learned_node_addr(info)
+    {
+        Peer::update_addresses(node_addr, info.conn_type.to_string()).await?;
+    }
+
+    Ok(pinged)
+}
@@ -0,0 +1,250 @@
+use std::collections::BTreeSet;
+use std::fmt::Display;
+
+use anyhow::{Result, anyhow, ensure};
+use chrono::{DateTime, Utc};
+use iroh::{NodeId, SecretKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::actors::gossip::signing::{Signable, SignedMessage, SignedPolicy};
+use crate::network::revocation::SignedRevocation;
+
+/// How long a proposal waits for approvals before it expires
+pub const PROPOSAL_LIFETIME: chrono::Duration = chrono::Duration::days(7);
+
+/// A sensitive change that only applies once enough admins have signed off on it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ProposalAction {
+    /// Admit `node_id` to the network
+    Admit { node_id: NodeId },
+    /// Replace the labels assigned to `node_id`
+    SetLabels {
+        node_id: NodeId,
+        labels: Vec<String>,
+    },
+    /// Replace the policy, carries the signed policy document
+    Policy { policy: Vec<u8> },
+    /// Ban a node from the network, carries the signed revocation
+    Revoke { revocation: Vec<u8> },
+}
+
+impl Display for ProposalAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProposalAction::Admit { node_id } => write!(f, "admit {node_id}"),
+            ProposalAction::SetLabels { node_id, labels } => {
+                write!(f, "set labels of {node_id} to [{}]", labels.join(", "))
+            }
+            ProposalAction::Policy { policy } => match SignedPolicy::verify(policy.clone()) {
+                Ok(policy) => write!(
+                    f,
+                    "set policy version {} with {} admin(s) and a threshold of {}",
+                    policy.policy.version,
+                    policy.policy.admins.len(),
+                    policy.policy.threshold
+                ),
+                Err(_) => write!(f, "set an invalid policy"),
+            },
+            ProposalAction::Revoke { revocation } => {
+                match SignedRevocation::verify(revocation.clone()) {
+                    Ok(revocation) => write!(f, "revoke {}", revocation.revocation.node_id),
+                    Err(_) => write!(f, "apply an invalid revocation"),
+                }
+            }
+        }
+    }
+}
+
+/// A pending change, signed by the admin who proposed it
+///
+/// The proposer's signature counts as the first approval.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Proposal {
+    pub id: Uuid,
+    pub action: ProposalAction,
+    pub proposed_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// A proposal along with who signed it and the signed bytes it came from
+#[derive(Debug, Clone)]
+pub struct SignedProposal {
+    pub proposed_by: NodeId,
+    pub proposal: Proposal,
+    pub bytes: Vec<u8>,
+}
+
+/// An admin signing off on a proposal
+///
+/// The approval names the digest of the signed proposal, so it can not be attached to a different
+/// proposal that reuses the same id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Approval {
+    pub proposal_id: Uuid,
+    pub digest: [u8; 32],
+    pub approved_at: DateTime<Utc>,
+}
+
+/// An approval along with who signed it and the signed bytes it came from
+#[derive(Debug, Clone)]
+pub struct SignedApproval {
+    pub approver: NodeId,
+    pub approval: Approval,
+    pub bytes: Vec<u8>,
+}
+
+impl Signable for Proposal {
+    const SIGNING_CONTEXT: &'static [u8] = b"room_101/proposal/v1";
+}
+
+impl Signable for Approval {
+    const SIGNING_CONTEXT: &'static [u8] = b"room_101/approval/v1";
+}
+
+impl Proposal {
+    pub fn new(action: ProposalAction, expires_at: DateTime<Utc>) -> Self {
+        Self {
+            id: uuid::Builder::from_random_bytes(rand::random()).into_uuid(),
+            action,
+            proposed_at: Utc::now(),
+            expires_at,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= Utc::now()
+    }
+}
+
+impl SignedProposal {
+    pub fn sign(secret_key: &SecretKey, proposal: Proposal) -> Result<Self> {
+        let bytes = SignedMessage::sign_and_encode(secret_key, &proposal)?;
+        Ok(Self {
+            proposed_by: secret_key.public(),
+            proposal,
+            bytes,
+        })
+    }
+
+    pub fn verify(bytes: Vec<u8>) -> Result<Self> {
+        let (proposed_by, proposal) = SignedMessage::<Proposal>::verify_and_decode(&bytes)
+            .map_err(|err| anyhow!("Invalid proposal: {err}"))?;
+        Ok(Self {
+            proposed_by,
+            proposal,
+            bytes,
+        })
+    }
+
+    pub fn digest(&self) -> [u8; 32] {
+        Sha256::digest(&self.bytes).into()
+    }
+
+    /// Everyone that has signed off on this proposal, including the proposer
+    ///
+    /// This does not check who is an admin, see [`PolicyRecord::distinct_admins`].
+    ///
+    /// [`PolicyRecord::distinct_admins`]: crate::db::PolicyRecord::distinct_admins
+    pub fn signers(&self, approvals: &[SignedApproval]) -> BTreeSet<NodeId> {
+        std::iter::once(self.proposed_by)
+            .chain(
+                approvals
+                    .iter()
+                    .filter(|approval| approval.approves(self))
+                    .map(|approval| approval.approver),
+            )
+            .collect()
+    }
+}
+
+impl SignedApproval {
+    pub fn sign(secret_key: &SecretKey, proposal: &SignedProposal) -> Result<Self> {
+        let approval = Approval {
+            proposal_id: proposal.proposal.id,
+            digest: proposal.digest(),
+            approved_at: Utc::now(),
+        };
+        let bytes = SignedMessage::sign_and_encode(secret_key, &approval)?;
+        Ok(Self {
+            approver: secret_key.public(),
+            approval,
+            bytes,
+        })
+    }
+
+    /// Verify the signed bytes of an approval and check it is for `proposal`
+    pub fn verify(bytes: Vec<u8>, proposal: &SignedProposal) -> Result<Self> {
+        let (approver, approval) = SignedMessage::<Approval>::verify_and_decode(&bytes)
+            .map_err(|err| anyhow!("Invalid approval: {err}"))?;
+        let approval = Self {
+            approver,
+            approval,
+            bytes,
+        };
+        ensure!(
+            approval.approves(proposal),
+            "Approval is for a different proposal"
+        );
+        Ok(approval)
+    }
+
+    pub fn approves(&self, proposal: &SignedProposal) -> bool {
+        self.approval.proposal_id == proposal.proposal.id
+            && self.approval.digest == proposal.digest()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+
+    fn test_proposal(secret_key: &SecretKey) -> SignedProposal {
+        let node_id = SecretKey::generate(rand::rngs::OsRng).public();
+        let proposal = Proposal::new(
+            ProposalAction::Admit { node_id },
+            Utc::now() + chrono::Duration::hours(1),
+        );
+        SignedProposal::sign(secret_key, proposal).unwrap()
+    }
+
+    #[test]
+    fn test_approval_is_bound_to_proposal() {
+        let admin = SecretKey::generate(rand::rngs::OsRng);
+        let proposal = test_proposal(&admin);
+        let other = test_proposal(&admin);
+
+        let approval = SignedApproval::sign(&admin, &proposal).unwrap();
+        assert!(SignedApproval::verify(approval.bytes.clone(), &proposal).is_ok());
+        assert!(SignedApproval::verify(approval.bytes.clone(), &other).is_err());
+
+        // Same id but different content
+        let mut forged = other.proposal.clone();
+        forged.id = proposal.proposal.id;
+        let forged = SignedProposal::sign(&admin, forged).unwrap();
+        assert!(SignedApproval::verify(approval.bytes, &forged).is_err());
+    }
+
+    #[test]
+    fn test_signers_are_distinct() {
+        let proposer = SecretKey::generate(rand::rngs::OsRng);
+        let other = SecretKey::generate(rand::rngs::OsRng);
+        let proposal = test_proposal(&proposer);
+        let unrelated = test_proposal(&proposer);
+
+        let approvals = vec![
+            // The proposer approving again does not count twice
+            SignedApproval::sign(&proposer, &proposal).unwrap(),
+            SignedApproval::sign(&other, &proposal).unwrap(),
+            SignedApproval::sign(&other, &unrelated).unwrap(),
+        ];
+
+        let signers = proposal.signers(&approvals);
+        assert_eq!(signers.len(), 2);
+        assert!(signers.contains(&proposer.public()));
+        assert!(signers.contains(&other.public()));
+        assert_eq!(unrelated.signers(&approvals).len(), 2);
+    }
+}
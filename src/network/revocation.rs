@@ -0,0 +1,73 @@
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Utc};
+use iroh::{NodeId, SecretKey};
+use serde::{Deserialize, Serialize};
+
+use crate::actors::gossip::signing::{Signable, SignedMessage};
+
+/// A permanent ban of a node from the network, signed by the admin that issued it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Revocation {
+    pub node_id: NodeId,
+    pub reason: Option<String>,
+    pub revoked_at: DateTime<Utc>,
+}
+
+/// A revocation along with who signed it and the signed bytes it came from
+#[derive(Debug, Clone)]
+pub struct SignedRevocation {
+    pub revoked_by: NodeId,
+    pub revocation: Revocation,
+    pub bytes: Vec<u8>,
+}
+
+impl Signable for Revocation {
+    const SIGNING_CONTEXT: &'static [u8] = b"room_101/revocation/v1";
+}
+
+impl SignedRevocation {
+    pub fn sign(secret_key: &SecretKey, revocation: Revocation) -> Result<Self> {
+        let bytes = SignedMessage::sign_and_encode(secret_key, &revocation)?;
+        Ok(Self {
+            revoked_by: secret_key.public(),
+            revocation,
+            bytes,
+        })
+    }
+
+    pub fn verify(bytes: Vec<u8>) -> Result<Self> {
+        let (revoked_by, revocation) = SignedMessage::<Revocation>::verify_and_decode(&bytes)
+            .map_err(|err| anyhow!("Invalid revocation: {err}"))?;
+        Ok(Self {
+            revoked_by,
+            revocation,
+            bytes,
+        })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify_round_trip() {
+        let admin = SecretKey::generate(rand::rngs::OsRng);
+        let revocation = Revocation {
+            node_id: SecretKey::generate(rand::rngs::OsRng).public(),
+            reason: Some("stolen laptop".to_string()),
+            revoked_at: Utc::now(),
+        };
+
+        let signed = SignedRevocation::sign(&admin, revocation.clone()).unwrap();
+        let verified = SignedRevocation::verify(signed.bytes.clone()).unwrap();
+        assert_eq!(verified.revoked_by, admin.public());
+        assert_eq!(verified.revocation, revocation);
+
+        let mut tampered = signed.bytes;
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(SignedRevocation::verify(tampered).is_err());
+    }
+}
@@ -0,0 +1,361 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::{DateTime, Utc};
+use iroh::{
+    Endpoint, NodeId,
+    endpoint::Connection,
+    protocol::{AcceptError, ProtocolHandler},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, trace};
+
+use crate::actors::gossip::gossip_receiver;
+use crate::db::{ReplicaEntry, ReplicaRecord, SenderStatus, replica::is_newer};
+
+/// ALPN of the anti-entropy protocol
+///
+/// Version 1 orders `Latest` slots by the time their message was signed instead of when it was
+/// stored.
+pub const ALPN: &[u8] = b"room101/sync/1";
+
+/// Largest request or response we read
+const MAX_MESSAGE_SIZE: usize = 8 * 1024 * 1024;
+
+/// Most replicas asked for in one request, the rest are fetched by further requests
+const FETCH_BATCH: usize = 128;
+
+/// How long a whole exchange with a peer may take
+const SYNC_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Serialize, Deserialize)]
+enum SyncRequest {
+    /// A hash per kind of replica, see [`Summary`]
+    Summary,
+    /// The entries of the given kinds
+    Entries { kinds: Vec<String> },
+    /// The replicas stored in the given slots
+    Fetch { slots: Vec<String> },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum SyncResponse {
+    Summary(Summary),
+    Entries(Vec<Entry>),
+    Replicas(Vec<Replica>),
+}
+
+/// The top of a two level hash tree over the replicas a node holds
+///
+/// Each kind of replica, such as revocations or metadata, is hashed on its own. Only the kinds
+/// whose hashes differ have their entries compared, so two nodes that agree exchange little more
+/// than this.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Summary {
+    pub kinds: BTreeMap<String, u64>,
+}
+
+/// A replica as listed to a peer, see [`ReplicaEntry`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub slot: String,
+    pub hash: String,
+    pub signed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Replica {
+    envelope: Vec<u8>,
+}
+
+/// What a reconciliation with a peer turned up
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Reconciled {
+    /// Kinds of replicas that differed
+    pub kinds: Vec<String>,
+    /// Replicas fetched and handed to the gossip receiver
+    pub fetched: usize,
+}
+
+impl From<ReplicaEntry> for Entry {
+    fn from(entry: ReplicaEntry) -> Self {
+        Self {
+            slot: entry.slot,
+            hash: entry.hash,
+            signed_at: entry.signed_at,
+        }
+    }
+}
+
+impl Summary {
+    /// Summarize our own replicas
+    pub async fn collect() -> Result<Self> {
+        let entries = ReplicaRecord::entries(None).await?;
+        Ok(Self::of(&entries))
+    }
+
+    pub fn of(entries: &[ReplicaEntry]) -> Self {
+        let mut hashers: BTreeMap<String, Sha256> = BTreeMap::new();
+        let mut sorted: Vec<&ReplicaEntry> = entries.iter().collect();
+        sorted.sort_by(|a, b| a.slot.cmp(&b.slot));
+
+        for entry in sorted {
+            let hasher = hashers.entry(entry.kind.clone()).or_default();
+            for field in [&entry.slot, &entry.hash] {
+                hasher.update((field.len() as u64).to_le_bytes());
+                hasher.update(field.as_bytes());
+            }
+        }
+
+        let kinds = hashers
+            .into_iter()
+            .map(|(kind, hasher)| {
+                let hash = hasher.finalize();
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(&hash[..8]);
+                (kind, u64::from_le_bytes(bytes))
+            })
+            .collect();
+
+        Self { kinds }
+    }
+
+    /// Kinds `theirs` holds replicas of that ours does not match
+    ///
+    /// Kinds only we hold are left out, the peer fetches those from us when it reconciles.
+    pub fn differing_kinds(&self, theirs: &Summary) -> Vec<String> {
+        theirs
+            .kinds
+            .iter()
+            .filter(|(kind, hash)| self.kinds.get(*kind) != Some(hash))
+            .map(|(kind, _)| kind.clone())
+            .collect()
+    }
+}
+
+/// Slots of `theirs` worth fetching: ones we lack, and ones where their replica is newer
+///
+/// Only `Latest` slots can hold different replicas, the newer one wins there as it does in
+/// [`ReplicaRecord::keep`]. What the peer claims is only used to decide what to fetch, the
+/// fetched messages are checked again when they are kept.
+pub fn wanted(ours: &[Entry], theirs: &[Entry]) -> Vec<String> {
+    let ours: HashMap<&str, &Entry> = ours
+        .iter()
+        .map(|entry| (entry.slot.as_str(), entry))
+        .collect();
+
+    theirs
+        .iter()
+        .filter(|entry| {
+            ours.get(entry.slot.as_str()).is_none_or(|our| {
+                is_newer((entry.signed_at, &entry.hash), (our.signed_at, &our.hash))
+            })
+        })
+        .map(|entry| entry.slot.clone())
+        .collect()
+}
+
+/// Answers anti-entropy requests from admitted peers
+#[derive(Debug, Clone)]
+pub struct SyncProtocol;
+
+impl ProtocolHandler for SyncProtocol {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let node_id = connection.remote_node_id()?;
+        let status = SenderStatus::of(node_id).await.unwrap_or_default();
+        if !status.admitted || status.revoked || status.retired {
+            debug!(%node_id, "Refusing sync from a peer that is not a member");
+            return Err(AcceptError::NotAllowed {});
+        }
+
+        if let Err(err) = serve(&connection).await {
+            debug!(?err, %node_id, "Sync with peer failed");
+        }
+        Ok(())
+    }
+}
+
+/// Answer requests on `connection` until the peer closes it
+async fn serve(connection: &Connection) -> Result<()> {
+    while let Ok((mut send, mut recv)) = connection.accept_bi().await {
+        let request: SyncRequest = postcard::from_bytes(&recv.read_to_end(MAX_MESSAGE_SIZE).await?)
+            .context("Invalid sync request")?;
+        trace!(?request, "Answering sync request");
+
+        let response = match request {
+            SyncRequest::Summary => SyncResponse::Summary(Summary::collect().await?),
+            SyncRequest::Entries { kinds } => SyncResponse::Entries(
+                ReplicaRecord::entries(Some(kinds))
+                    .await?
+                    .into_iter()
+                    .map(Entry::from)
+                    .collect(),
+            ),
+            SyncRequest::Fetch { mut slots } => {
+                slots.truncate(FETCH_BATCH);
+                SyncResponse::Replicas(
+                    ReplicaRecord::get(slots)
+                        .await?
+                        .into_iter()
+                        .map(|record| Replica {
+                            envelope: record.envelope,
+                        })
+                        .collect(),
+                )
+            }
+        };
+
+        send.write_all(&postcard::to_stdvec(&response)?).await?;
+        send.finish()?;
+    }
+
+    Ok(())
+}
+
+async fn request(connection: &Connection, request: &SyncRequest) -> Result<SyncResponse> {
+    let (mut send, mut recv) = connection.open_bi().await?;
+    send.write_all(&postcard::to_stdvec(request)?).await?;
+    send.finish()?;
+
+    postcard::from_bytes(&recv.read_to_end(MAX_MESSAGE_SIZE).await?)
+        .context("Invalid sync response")
+}
+
+/// Fetch every replica `node_id` holds that we lack or have an older copy of
+///
+/// The replicas are handed to the gossip receiver, which checks them like any other gossip
+/// message before they are stored or applied.
+pub async fn reconcile(endpoint: &Endpoint, node_id: NodeId) -> Result<Reconciled> {
+    tokio::time::timeout(SYNC_TIMEOUT, async {
+        let connection = endpoint
+            .connect(node_id, ALPN)
+            .await
+            .context("Failed to connect to peer")?;
+        let result = exchange(&connection, node_id).await;
+        connection.close(0u32.into(), b"done");
+        result
+    })
+    .await
+    .map_err(|_| anyhow!("Sync with {node_id} took longer than {SYNC_TIMEOUT:?}"))?
+}
+
+async fn exchange(connection: &Connection, node_id: NodeId) -> Result<Reconciled> {
+    let SyncResponse::Summary(theirs) = request(connection, &SyncRequest::Summary).await? else {
+        bail!("Expected a summary");
+    };
+    let ours = Summary::collect().await?;
+
+    let mut reconciled = Reconciled {
+        kinds: ours.differing_kinds(&theirs),
+        fetched: 0,
+    };
+    if reconciled.kinds.is_empty() {
+        return Ok(reconciled);
+    }
+
+    let kinds = reconciled.kinds.clone();
+    let SyncResponse::Entries(their_entries) = request(
+        connection,
+        &SyncRequest::Entries {
+            kinds: kinds.clone(),
+        },
+    )
+    .await?
+    else {
+        bail!("Expected entries");
+    };
+    let our_entries: Vec<Entry> = ReplicaRecord::entries(Some(kinds))
+        .await?
+        .into_iter()
+        .map(Entry::from)
+        .collect();
+
+    let slots = wanted(&our_entries, &their_entries);
+    debug!(%node_id, kinds = ?reconciled.kinds, wanted = slots.len(), "Fetching replicas from peer");
+
+    for batch in slots.chunks(FETCH_BATCH) {
+        let SyncResponse::Replicas(replicas) = request(
+            connection,
+            &SyncRequest::Fetch {
+                slots: batch.to_vec(),
+            },
+        )
+        .await?
+        else {
+            bail!("Expected replicas");
+        };
+
+        for replica in replicas {
+            gossip_receiver::replay(node_id, replica.envelope)?;
+            reconciled.fetched += 1;
+        }
+    }
+
+    Ok(reconciled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(slot: &str, hash: &str, minutes_ago: Option<i64>) -> Entry {
+        Entry {
+            slot: slot.to_string(),
+            hash: hash.to_string(),
+            signed_at: minutes_ago.map(|minutes_ago| {
+                DateTime::<Utc>::UNIX_EPOCH + chrono::Duration::minutes(1000 - minutes_ago)
+            }),
+        }
+    }
+
+    fn replica_entry(kind: &str, slot: &str, hash: &str) -> ReplicaEntry {
+        ReplicaEntry {
+            slot: slot.to_string(),
+            kind: kind.to_string(),
+            hash: hash.to_string(),
+            signed_at: None,
+        }
+    }
+
+    #[test]
+    fn test_wanted() {
+        let ours = vec![
+            entry("revocation/a", "a", None),
+            entry("metadata/n1", "old", Some(10)),
+            entry("metadata/n2", "ours", Some(1)),
+        ];
+        let theirs = vec![
+            entry("revocation/a", "a", None),
+            entry("revocation/b", "b", None),
+            entry("metadata/n1", "new", Some(2)),
+            entry("metadata/n2", "theirs", Some(5)),
+        ];
+
+        assert_eq!(wanted(&ours, &theirs), vec!["revocation/b", "metadata/n1"]);
+        // Nothing flows back the other way, so the two sides settle
+        assert_eq!(wanted(&theirs, &ours), vec!["metadata/n2"]);
+    }
+
+    #[test]
+    fn test_summary_only_differs_where_replicas_do() {
+        let entries = vec![
+            replica_entry("revocation", "revocation/a", "a"),
+            replica_entry("metadata", "metadata/n1", "m1"),
+        ];
+        let ours = Summary::of(&entries);
+
+        // Order and storage time do not matter
+        let mut reversed = entries.clone();
+        reversed.reverse();
+        assert_eq!(Summary::of(&reversed), ours);
+        assert!(ours.differing_kinds(&Summary::of(&reversed)).is_empty());
+
+        let mut theirs = entries;
+        theirs.push(replica_entry("revocation", "revocation/b", "b"));
+        theirs.push(replica_entry("policy", "policy/p", "p"));
+        let theirs = Summary::of(&theirs);
+        assert_eq!(ours.differing_kinds(&theirs), vec!["policy", "revocation"]);
+    }
+}
@@ -1,15 +1,16 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use nu_ansi_term::Color;
 use std::fmt;
+use std::sync::OnceLock;
 use tracing::{
     Event, Level, Subscriber,
     field::{Field, Visit},
 };
-use tracing_subscriber::EnvFilter;
 use tracing_subscriber::fmt::format::Writer;
 use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{EnvFilter, Registry, reload};
 
 #[cfg(debug_assertions)]
 const FIELD_INDENT: &str = "    ";
@@ -190,17 +191,26 @@ impl Visit for FieldVisitor {
     }
 }
 
+const DEFAULT_LOG_FILTER: &str = "room_101=info,iroh=error,iroh_gossip=error";
+
+/// Swaps the filter of the running subscriber, see [`set_log_filter`]
+static FILTER_HANDLE: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
 /// Initialize tracing-based logging to stdout
-pub fn setup_tracing() -> Result<()> {
-    // Set up environment filter
-    // Default to INFO level, but allow override with RUST_LOG environment variable
-    let env_filter = EnvFilter::try_from_default_env()
-        .unwrap_or_else(|_| EnvFilter::new("room_101=info,iroh=error,iroh_gossip=error"));
+///
+/// The RUST_LOG environment variable takes precedence over `log_filter` from the config file.
+pub fn setup_tracing(log_filter: Option<&str>) -> Result<()> {
+    let env_filter = match EnvFilter::try_from_default_env() {
+        Ok(env_filter) => env_filter,
+        Err(_) => EnvFilter::try_new(log_filter.unwrap_or(DEFAULT_LOG_FILTER))?,
+    };
+    let (filter_layer, filter_handle) = reload::Layer::new(env_filter);
 
     #[cfg(debug_assertions)]
     {
         // Use custom indented formatter with span field capture layer in debug builds
         let registry = tracing_subscriber::registry()
+            .with(filter_layer)
             .with(SpanFieldLayer)
             .with(
                 tracing_subscriber::fmt::layer()
@@ -209,8 +219,7 @@ pub fn setup_tracing() -> Result<()> {
                     .with_file(false)
                     .with_line_number(false)
                     .event_format(IndentedFormatter),
-            )
-            .with(env_filter);
+            );
 
         tracing::subscriber::set_global_default(registry)?;
     }
@@ -218,26 +227,48 @@ pub fn setup_tracing() -> Result<()> {
     #[cfg(not(debug_assertions))]
     {
         // Use compact formatter in release builds
-        tracing_subscriber::fmt()
-            .with_env_filter(env_filter)
-            .with_target(true)
-            .with_thread_ids(false)
-            .with_file(false)
-            .with_line_number(false)
-            .compact()
-            .init();
+        let registry = tracing_subscriber::registry().with(filter_layer).with(
+            tracing_subscriber::fmt::layer()
+                .with_target(true)
+                .with_thread_ids(false)
+                .with_file(false)
+                .with_line_number(false)
+                .compact(),
+        );
+
+        tracing::subscriber::set_global_default(registry)?;
     }
 
+    let _ = FILTER_HANDLE.set(filter_handle);
+
     Ok(())
 }
 
+/// Replace the log filter of the running subscriber, such as after the config was reloaded
+///
+/// Returns false without changing anything if RUST_LOG is set, since it takes precedence.
+pub fn set_log_filter(log_filter: Option<&str>) -> Result<bool> {
+    if EnvFilter::try_from_default_env().is_ok() {
+        return Ok(false);
+    }
+
+    let env_filter = EnvFilter::try_new(log_filter.unwrap_or(DEFAULT_LOG_FILTER))
+        .context("Invalid log filter")?;
+    FILTER_HANDLE
+        .get()
+        .context("Tracing has not been set up")?
+        .reload(env_filter)?;
+
+    Ok(true)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_tracing_setup() {
-        let result = setup_tracing();
+        let result = setup_tracing(None);
         assert!(result.is_ok());
     }
 
@@ -1,31 +1,23 @@
-use anyhow::{Context, Result, bail};
+use anyhow::{Context, Result, bail, ensure};
 use iroh_base::ticket::NodeTicket;
+use iroh_gossip::proto::TopicId;
 use std::path::Path;
 use std::str::FromStr;
 use tokio::fs;
 use tracing::trace;
 
-/// Creates a TopicId from a string literal, padding with zeros to 32 bytes at compile time
-/// Fails at compile time if the string is longer than 32 bytes
-macro_rules! topic_id {
-    ($s:literal) => {{
-        const BYTES: &[u8] = $s.as_bytes();
-        const LEN: usize = BYTES.len();
-        const _: () = assert!(LEN <= 32, "Topic string is too long (max 32 bytes)");
-        const PADDED: [u8; 32] = {
-            let mut arr = [0u8; 32];
-            let mut i = 0;
-            while i < LEN {
-                arr[i] = BYTES[i];
-                i += 1;
-            }
-            arr
-        };
-        TopicId::from_bytes(PADDED)
-    }};
-}
+/// Creates a TopicId from a network name, padding it with zeros to 32 bytes
+pub fn topic_from_name(name: &str) -> Result<TopicId> {
+    let bytes = name.as_bytes();
+    ensure!(
+        !bytes.is_empty() && bytes.len() <= 32,
+        "Topic '{name}' must be between 1 and 32 bytes long"
+    );
 
-pub(crate) use topic_id;
+    let mut padded = [0u8; 32];
+    padded[..bytes.len()].copy_from_slice(bytes);
+    Ok(TopicId::from_bytes(padded))
+}
 
 /// Check if a file contains a valid ticket format
 async fn is_ticket_file(path: &Path) -> Result<bool> {
@@ -77,3 +69,70 @@ pub async fn write_ticket_to_file(ticket: &NodeTicket, path: &Path) -> Result<()
 
     Ok(())
 }
+
+/// Lock held by the running server on a file next to its database
+///
+/// Commands that bind an endpoint with our identity use it to tell whether the server is running,
+/// as two endpoints with the same identity would fight over connections.
+#[derive(Debug)]
+pub struct ServerLock {
+    /// Only kept open, the lock goes with it
+    _file: std::fs::File,
+}
+
+impl ServerLock {
+    /// Take the lock for the database at `db_path`, `None` if a server already holds it
+    ///
+    /// The lock is released when the returned value is dropped or the process exits.
+    pub fn acquire(db_path: &str) -> Result<Option<Self>> {
+        let path = format!("{db_path}.lock");
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open lock file '{path}'"))?;
+
+        match file.try_lock() {
+            Ok(()) => Ok(Some(Self { _file: file })),
+            Err(std::fs::TryLockError::WouldBlock) => Ok(None),
+            Err(std::fs::TryLockError::Error(err)) => {
+                Err(err).with_context(|| format!("Failed to lock '{path}'"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topic_from_name() {
+        let mut expected = [0u8; 32];
+        expected[..8].copy_from_slice(b"ROOM_101");
+        assert_eq!(
+            topic_from_name("ROOM_101").unwrap(),
+            TopicId::from_bytes(expected)
+        );
+
+        assert!(topic_from_name("").is_err());
+        assert!(topic_from_name(&"x".repeat(33)).is_err());
+    }
+
+    #[test]
+    fn test_server_lock_is_exclusive() {
+        let dir = std::env::temp_dir().join(format!("room_101-lock-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db_path = dir.join("db").to_string_lossy().to_string();
+
+        let held = ServerLock::acquire(&db_path).unwrap();
+        assert!(held.is_some());
+        assert!(ServerLock::acquire(&db_path).unwrap().is_none());
+
+        drop(held);
+        assert!(ServerLock::acquire(&db_path).unwrap().is_some());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}